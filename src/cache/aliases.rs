@@ -0,0 +1,428 @@
+use crate::error::Result;
+use log::debug;
+use sqlx::SqlitePool;
+use std::{collections::HashMap, fs, path::Path};
+
+use super::{connectdb, tableexists};
+
+/// An entry from nixpkgs' `pkgs/top-level/aliases.nix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alias {
+    /// The old attribute name, e.g. `gnome-passwordsafe`.
+    pub attribute: String,
+    /// The attribute the alias points to, if it was renamed rather than removed.
+    pub target: Option<String>,
+    /// The message thrown by nixpkgs when the alias has been removed or converted to a throw.
+    pub message: Option<String>,
+}
+
+/// Parses the contents of nixpkgs' `pkgs/top-level/aliases.nix`.
+///
+/// Every `name = value;` binding is read as a whole expression, so values spanning several lines and
+/// messages containing `;` are understood. Two kinds of value are recognized:
+/// - `old = new;` is a rename to `new`.
+/// - `old = throw "...";` (or `throw ''...''`) is a removal. If the message follows the nixpkgs convention of
+///   `'old' has been renamed to/replaced by 'new'`, `new` is used as the target.
+///
+/// Any other binding, such as the helper functions defined in the `let` block, is skipped.
+pub fn parsealiases(contents: &str) -> HashMap<String, Alias> {
+    let tokens = nixtokens(contents);
+    let mut out = HashMap::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let name = match (&tokens[i], tokens.get(i + 1)) {
+            (NixToken::Ident(name) | NixToken::Str(name), Some(NixToken::Punct('='))) => name,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let Some(len) = bindingend(&tokens[i + 2..]) else {
+            i += 1;
+            continue;
+        };
+        if let Some(alias) = aliasvalue(name, &tokens[i + 2..i + 2 + len]) {
+            out.insert(name.clone(), alias);
+        }
+        // Skip the whole value, so bindings nested in it aren't read as aliases
+        i += len + 3;
+    }
+    out
+}
+
+/// Returns the number of tokens in the value of a binding starting at `value`, up to the `;` ending it.
+/// Returns `None` if the enclosing set ends first.
+fn bindingend(value: &[NixToken]) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, token) in value.iter().enumerate() {
+        match token {
+            NixToken::Punct('(' | '[' | '{') => depth += 1,
+            NixToken::Punct(')' | ']' | '}') => depth = depth.checked_sub(1)?,
+            NixToken::Punct(';') if depth == 0 => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Reads the value of the binding `name` as an [Alias], if it is a rename or a `throw`.
+fn aliasvalue(name: &str, value: &[NixToken]) -> Option<Alias> {
+    match value {
+        [NixToken::Ident(target)] if !matches!(target.as_str(), "null" | "true" | "false") => Some(Alias {
+            attribute: name.to_string(),
+            target: Some(target.clone()),
+            message: None,
+        }),
+        [NixToken::Ident(throw), message @ ..] if throw == "throw" => {
+            let message = stringvalue(message)?;
+            let target = message
+                .split_once("renamed to/replaced by '")
+                .and_then(|(_, x)| x.split_once('\''))
+                .map(|(x, _)| x.to_string());
+            Some(Alias {
+                attribute: name.to_string(),
+                target,
+                message: Some(message),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Reads a string, or strings joined with `+`, optionally in parentheses.
+fn stringvalue(tokens: &[NixToken]) -> Option<String> {
+    let tokens = match tokens {
+        [NixToken::Punct('('), inner @ .., NixToken::Punct(')')] => inner,
+        _ => tokens,
+    };
+    let mut out = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            NixToken::Str(s) if i % 2 == 0 => out.push_str(s),
+            NixToken::Punct('+') if i % 2 == 1 => {}
+            _ => return None,
+        }
+    }
+    (!out.is_empty()).then(|| out.trim().to_string())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NixToken {
+    /// An identifier or attribute path, such as `gnome-secrets` or `libsForQt5.kdeconnect-kde`.
+    Ident(String),
+    /// The contents of a string. Interpolations are kept as they are written.
+    Str(String),
+    /// Any other single character.
+    Punct(char),
+    /// Operators that are not a binding's `=`, such as `==`.
+    Other,
+}
+
+/// Splits the Nix source `contents` into the tokens needed to find bindings, dropping comments.
+fn nixtokens(contents: &str) -> Vec<NixToken> {
+    let chars = contents.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' {
+            let (s, end) = doublequoted(&chars, i + 1);
+            tokens.push(NixToken::Str(s));
+            i = end;
+        } else if c == '\'' && next == Some('\'') {
+            let (s, end) = indented(&chars, i + 2);
+            tokens.push(NixToken::Str(s));
+            i = end;
+        } else if c.is_alphanumeric() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || "_-'.".contains(chars[i])) {
+                i += 1;
+            }
+            tokens.push(NixToken::Ident(chars[start..i].iter().collect()));
+        } else if "=!<>".contains(c) && next == Some('=') {
+            tokens.push(NixToken::Other);
+            i += 2;
+        } else {
+            tokens.push(NixToken::Punct(c));
+            i += 1;
+        }
+    }
+    tokens
+}
+
+/// Copies an interpolation `${...}` starting at `chars[i]` into `out`, returning the index after it.
+fn interpolation(chars: &[char], mut i: usize, out: &mut String) -> usize {
+    let mut depth = 0;
+    while i < chars.len() {
+        out.push(chars[i]);
+        match chars[i] {
+            '{' => depth += 1,
+            '}' if depth == 1 => return i + 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    i
+}
+
+/// Reads a `"..."` string whose contents start at `chars[i]`, returning its value and the index after it.
+fn doublequoted(chars: &[char], mut i: usize) -> (String, usize) {
+    let mut out = String::new();
+    while i < chars.len() {
+        match (chars[i], chars.get(i + 1)) {
+            ('"', _) => return (out, i + 1),
+            ('\\', Some(&c)) => {
+                out.push(match c {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    c => c,
+                });
+                i += 2;
+            }
+            ('$', Some('{')) => i = interpolation(chars, i, &mut out),
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    (out, i)
+}
+
+/// Reads a `''...''` string whose contents start at `chars[i]`, returning its value and the index after it.
+/// As in Nix, the indentation shared by all lines is removed, along with a first line holding only whitespace.
+fn indented(chars: &[char], mut i: usize) -> (String, usize) {
+    let mut raw = String::new();
+    while i < chars.len() {
+        match (chars[i], chars.get(i + 1), chars.get(i + 2)) {
+            // Escapes: ''' is '', ''$ is $ and ''\x is x
+            ('\'', Some('\''), Some('\'')) => {
+                raw.push_str("''");
+                i += 3;
+            }
+            ('\'', Some('\''), Some('$')) => {
+                raw.push('$');
+                i += 3;
+            }
+            ('\'', Some('\''), Some('\\')) => {
+                if let Some(&c) = chars.get(i + 3) {
+                    raw.push(c);
+                }
+                i += 4;
+            }
+            ('\'', Some('\''), _) => {
+                i += 2;
+                break;
+            }
+            ('$', Some('{'), _) => i = interpolation(chars, i, &mut raw),
+            (c, _, _) => {
+                raw.push(c);
+                i += 1;
+            }
+        }
+    }
+    let mut lines = raw.split('\n').collect::<Vec<_>>();
+    if lines.len() > 1 && lines[0].trim().is_empty() {
+        lines.remove(0);
+    }
+    let indent = lines
+        .iter()
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| x.len() - x.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let out = lines
+        .iter()
+        .map(|x| x.get(indent..).unwrap_or_else(|| x.trim_start()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    (out, i)
+}
+
+/// Reads `pkgs/top-level/aliases.nix` from the nixpkgs checkout at `nixpath` and stores it in an `aliases` table in `db`.
+/// Any existing `aliases` table is replaced. Returns the number of aliases stored.
+///
+/// Neither the channel `packages.json` nor the prebuilt nix-data databases contain alias data,
+/// so this has to be run against a nixpkgs source tree (for example the result of `nix eval nixpkgs#path`).
+pub async fn importaliases(db: impl AsRef<Path>, nixpath: &str) -> Result<usize> {
+    let contents = fs::read_to_string(format!("{}/pkgs/top-level/aliases.nix", nixpath))?;
+    let aliases = parsealiases(&contents);
+    debug!("Found {} aliases", aliases.len());
+
+    let pool = connectdb(&db).await?;
+    let mut tx = pool.begin().await?;
+    sqlx::query(r#"DROP TABLE IF EXISTS "aliases""#)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE "aliases" (
+            "attribute"	TEXT NOT NULL UNIQUE,
+            "target"	TEXT,
+            "message"	TEXT,
+            PRIMARY KEY("attribute")
+        )
+        "#,
+    )
+    .execute(&mut tx)
+    .await?;
+    for alias in aliases.values() {
+        sqlx::query(r#"INSERT INTO "aliases" VALUES ($1, $2, $3)"#)
+            .bind(&alias.attribute)
+            .bind(&alias.target)
+            .bind(&alias.message)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(aliases.len())
+}
+
+async fn queryalias(pool: &SqlitePool, attribute: &str) -> Result<Option<Alias>> {
+    if !tableexists(pool, "aliases").await? {
+        return Ok(None);
+    }
+    let row: Option<(String, Option<String>, Option<String>)> =
+        sqlx::query_as(r#"SELECT attribute, target, message FROM aliases WHERE attribute = $1"#)
+            .bind(attribute)
+            .fetch_optional(pool)
+            .await?;
+    Ok(row.map(|(attribute, target, message)| Alias {
+        attribute,
+        target,
+        message,
+    }))
+}
+
+/// Returns the full [Alias] entry for `attribute`, or `None` if it isn't an alias
+/// or `db` has no alias data (see [importaliases()]).
+pub async fn getalias(db: impl AsRef<Path>, attribute: &str) -> Result<Option<Alias>> {
+    let pool = connectdb(&db).await?;
+    queryalias(&pool, attribute).await
+}
+
+/// Resolves a renamed attribute to its current name, e.g. `gnome-passwordsafe` to `gnome-secrets`.
+/// Chains of renames are followed. Returns `None` if `attribute` isn't a known alias,
+/// has been removed without a replacement, or `db` has no alias data (see [importaliases()]).
+pub async fn resolve_alias(db: impl AsRef<Path>, attribute: &str) -> Result<Option<String>> {
+    let pool = connectdb(&db).await?;
+    let mut current = attribute.to_string();
+    let mut seen = vec![current.clone()];
+    while let Some(Alias {
+        target: Some(target),
+        ..
+    }) = queryalias(&pool, &current).await?
+    {
+        if seen.contains(&target) {
+            break;
+        }
+        seen.push(target.clone());
+        current = target;
+    }
+    if current == attribute {
+        Ok(None)
+    } else {
+        Ok(Some(current))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Excerpts of nixpkgs' `pkgs/top-level/aliases.nix`, keeping its layout.
+    const ALIASES: &str = r#"lib: self: super:
+
+with self;
+
+let
+  # Removing recurseForDerivation prevents derivations of aliased attribute set
+  # to appear while listing all the packages available.
+  removeRecurseForDerivations = alias: with lib;
+    if alias.recurseForDerivations or false
+    then removeAttrs alias ["recurseForDerivations"]
+    else alias;
+
+  # Disabling distribution prevents top-level aliases for non-recursed package
+  # sets from building on Hydra.
+  removeDistribute = alias: with lib;
+    if isDerivation alias then
+      dontDistribute alias
+    else alias;
+
+  mapAliases = aliases:
+    lib.mapAttrs (n: alias:
+      removeDistribute (removeRecurseForDerivations alias)
+    ) aliases;
+in
+
+mapAliases ({
+  # Added 2018-07-16 preserve, reason: forceSystem should not be used directly in Nixpkgs.
+  forceSystem = system: _:
+    (import self.path { localSystem = { inherit system; }; });
+
+  ### _ ###
+  _0verkill = throw "'_0verkill' has been removed due to lack of maintenance"; # Added 2022-11-09
+
+  alsaLib = alsa-lib; # Added 2021-06-09
+  ag = silver-searcher; # Added 2018-04-25
+  gnome-passwordsafe = gnome-secrets; # added 2022-01-30
+  kodiGBM = kodi-gbm;
+  mysql = throw "'mysql' has been renamed to/replaced by 'mariadb'"; # Converted to throw 2022-02-22
+  nodejs-14_x = throw "nodejs-14_x has been removed; it reached end of life"; # Added 2023-04-30
+  "fuse2fs" = e2fsprogs.fuse2fs; # Added 2022-10-30
+  inherit (libsForQt5.mauiPackages) buho;
+  ocropus = throw ''
+    'ocropus' has been removed: abandoned by upstream.
+      Use 'ocrad' or 'tesseract' instead.
+  ''; # Added 2022-04-24
+  pinentry_qt = throw ("'pinentry_qt' has been renamed to/replaced by " + "'pinentry-qt'"); # Converted to throw 2023-09-10
+  /* multi-line comments are skipped: old = new; */
+})
+"#;
+
+    #[test]
+    fn parse_aliases_nix() {
+        let aliases = parsealiases(ALIASES);
+        let alias = |name: &str| aliases.get(name).unwrap_or_else(|| panic!("{} missing", name));
+
+        assert_eq!(alias("alsaLib").target.as_deref(), Some("alsa-lib"));
+        assert_eq!(alias("gnome-passwordsafe").target.as_deref(), Some("gnome-secrets"));
+        assert_eq!(alias("fuse2fs").target.as_deref(), Some("e2fsprogs.fuse2fs"));
+        assert_eq!(alias("mysql").target.as_deref(), Some("mariadb"));
+        assert_eq!(alias("pinentry_qt").target.as_deref(), Some("pinentry-qt"));
+
+        // Messages are kept whole, past any `;`
+        let nodejs = alias("nodejs-14_x");
+        assert_eq!(nodejs.target, None);
+        assert_eq!(
+            nodejs.message.as_deref(),
+            Some("nodejs-14_x has been removed; it reached end of life")
+        );
+        assert_eq!(
+            alias("ocropus").message.as_deref(),
+            Some("'ocropus' has been removed: abandoned by upstream.\n  Use 'ocrad' or 'tesseract' instead.")
+        );
+
+        // Functions, inherits and nested bindings aren't aliases
+        for name in ["forceSystem", "removeDistribute", "mapAliases", "localSystem", "buho", "old"] {
+            assert!(!aliases.contains_key(name), "{} parsed as an alias", name);
+        }
+        assert_eq!(aliases.len(), 10, "{:?}", aliases.keys());
+    }
+}
@@ -1,120 +1,254 @@
 use crate::CACHEDIR;
-use anyhow::{anyhow, Context, Result};
-use log::info;
+use crate::error::{tooloutput_async, CommandExt, NixDataError, Result};
+use log::{debug, info};
 use serde::Deserialize;
-use sqlx::SqlitePool;
+use sha2::{Digest, Sha256};
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{BufReader, Read, Write},
-    path::Path,
+    io::{Read, Write},
+    path::{Path, PathBuf},
     process::Command,
 };
 
 use super::{
+    connectdb, httpclient,
     nixos::{self, getnixospkgs, nixospkgs},
-    NixPkgList,
+    query::DbSource,
+    publishedrevision, publishedsha256, readtimeout, requiremeta, setdbmetainfo, streampackages, verifysha256, CacheConfig,
+    Compression, NixPkg,
 };
 
 /// Gets a list of all packages in legacy NixOS systems with their name and version.
 /// Can be used to find what versions of system packages are currently installed.
 /// Will only work on legacy NixOS systems.
-pub async fn legacypkgs() -> Result<String> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
+pub async fn legacypkgs() -> Result<PathBuf> {
+    legacypkgs_with_config(&CacheConfig::default()).await
+}
+
+/// Like [legacypkgs()], but caches the database in the directory given by `config`.
+pub async fn legacypkgs_with_config(config: &CacheConfig) -> Result<PathBuf> {
+    if let Some(cached) = config.offlinefile("legacypkgs.db") {
+        return cached;
+    }
+    let versionout = Command::new("nixos-version").arg("--json").tooloutput()?;
     let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
 
     let nixosversion = version
         .get("nixosVersion")
-        .context("No NixOS version found")?;
-    let relver = if nixosversion[5..8].eq("pre") {
+        .ok_or_else(|| NixDataError::Parse(String::from("No NixOS version found")))?;
+    let release = nixos::parsenixosversion(nixosversion)?;
+    let relver = if nixosversion.get(5..8) == Some("pre") {
         "unstable"
     } else {
-        &nixosversion[0..5]
+        &release
     };
 
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
+    config.createdir()?;
 
     // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/legacypkgs.ver", &*CACHEDIR)) {
-        if prevver.eq(nixosversion) && Path::new(&format!("{}/legacypkgs.db", &*CACHEDIR)).exists()
-        {
+    if let Ok(prevver) = fs::read_to_string(config.file("legacypkgs.ver")) {
+        if prevver.eq(nixosversion) && Path::new(&config.file("legacypkgs.db")).exists() && !config.force {
             info!("No new version of NixOS legacy found");
-            return Ok(format!("{}/legacypkgs.db", &*CACHEDIR));
+            return Ok(config.file("legacypkgs.db").into());
         }
     }
 
-    async fn downloadrelease(relver: &str, nixosversion: &str) -> Result<HashMap<String, String>> {
-        let url = format!(
-            "https://releases.nixos.org/nixos/{}/nixos-{}/packages.json.br",
-            relver, nixosversion
-        );
-        // Download file with reqwest
-        let client = reqwest::Client::builder().brotli(true).build()?;
-        let resp = client.get(url).send().await;
-        let resp = if let Ok(r) = resp {
-            r
-        } else {
-            return Err(anyhow!("Failed to download legacy packages.json"));
-        };
-        if resp.status().is_success() {
-            let pkgjson: NixPkgList =
-                serde_json::from_reader(BufReader::new(resp.text().await?.as_bytes()))?;
-            let pkgout = pkgjson
-                .packages
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.version.to_string()))
-                .collect::<HashMap<String, String>>();
-            Ok(pkgout)
-        } else {
-            Err(anyhow!("Failed to download legacy packages.json"))
-        }
-    }
+    // Get list of packages. Only the release's packages.json gives the pname of each package.
+    // Neither way evaluates nixpkgs locally.
+    let dbfile = config.file("legacypkgs.db");
+    let releaseurl = format!("https://releases.nixos.org/nixos/{}/nixos-{}", relver, nixosversion);
+    let versiondata = match version.get("nixpkgsRevision") {
+        Some(rev) => [relver, "unstable"]
+            .iter()
+            .map(|channel| {
+                format!(
+                    "https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-{}/{}.json.br",
+                    channel, rev
+                )
+            })
+            .collect(),
+        None => vec![],
+    };
+    let source = importlegacy(config, &dbfile, &versiondata, &releaseurl).await?;
+    setdbmetainfo(&dbfile, "source", source.as_str()).await?;
+    recordrevision(&dbfile, version.get("nixpkgsRevision"), &releaseurl).await?;
+
+    // Write version downloaded to file
+    File::create(config.file("legacypkgs.ver"))?.write_all(nixosversion.as_bytes())?;
 
-    // Get list of packages
-    let pkgout = if let Some(rev) = version.get("nixpkgsRevision") {
-        let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-{}/{}.json.br", relver, rev);
-        println!("{}", url);
-        let resp = reqwest::get(&url).await?;
+    Ok(config.file("legacypkgs.db").into())
+}
+
+/// Builds the package database at `dbfile` from the first of the nixpkgs-version-data files at `versiondata`
+/// that is published, or otherwise from the `packages.json` of the NixOS release at `releaseurl`.
+/// Returns which of them was used.
+async fn importlegacy(
+    config: &CacheConfig,
+    dbfile: &str,
+    versiondata: &[String],
+    releaseurl: &str,
+) -> Result<DbSource> {
+    for url in versiondata {
+        debug!("Downloading {}", url);
+        let resp = httpclient(true)?.get(url).send().await?;
         if resp.status().is_success() {
             let r = resp.bytes().await?;
-            println!("Downloaded");
+            debug!("Decompressing {}", url);
             let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
             let mut pkgsout = Vec::new();
             br.read_to_end(&mut pkgsout)?;
             let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-            println!("Decompressed");
-            pkgsjson
-        } else {
-            let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-unstable/{}.json.br", rev);
-            println!("{}", url);
-            let resp = reqwest::get(&url).await?;
-            if resp.status().is_success() {
-                let r = resp.bytes().await?;
-                println!("Downloaded");
-                let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-                let mut pkgsout = Vec::new();
-                br.read_to_end(&mut pkgsout)?;
-                let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-                println!("Decompressed");
-                pkgsjson
-            } else {
-                downloadrelease(relver, nixosversion).await?
+            debug!("Importing {} packages", pkgsjson.len());
+            nixos::createdb(Path::new(dbfile), &pkgsjson).await?;
+            return Ok(DbSource::VersionData);
+        }
+    }
+    let pkgout = downloadrelease(config, releaseurl).await?;
+    nixos::createdbwithpnames(Path::new(dbfile), &pkgout).await?;
+    Ok(DbSource::Release)
+}
+
+/// Downloads and parses the `packages.json` published with the NixOS release at `releaseurl`,
+/// e.g. `https://releases.nixos.org/nixos/23.05/nixos-23.05.1234.abcdef`.
+async fn downloadrelease(config: &CacheConfig, releaseurl: &str) -> Result<HashMap<String, NixPkg>> {
+    // Download file with reqwest. It is decompressed after downloading rather than by reqwest,
+    // so that the compressed payload can be checked against its published hash.
+    // Releases are published with different compression, so take the first available.
+    let client = httpclient(false)?;
+    let mut found = None;
+    for file in ["packages.json.zst", "packages.json.br", "packages.json"] {
+        let url = format!("{}/{}", releaseurl, file);
+        match client.get(&url).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                found = Some((url, resp));
+                break;
+            }
+            Ok(_) => continue,
+            Err(_) => {
+                return Err(NixDataError::Download(String::from("Failed to download legacy packages.json")))
             }
         }
+    }
+    if let Some((url, mut resp)) = found {
+        let compression = Compression::of(&resp, &url);
+        // Write to disk and parse from there, as packages.json is too large to comfortably hold in memory
+        let jsonfile = config.file("legacypackages.json.download");
+        {
+            let mut out = File::create(&jsonfile)?;
+            let mut hasher = Sha256::new();
+            while let Some(chunk) = readtimeout(resp.chunk()).await? {
+                hasher.update(&chunk);
+                out.write_all(&chunk)?;
+            }
+            if let Some(expected) = publishedsha256(&client, &url).await? {
+                if let Err(e) = verifysha256(hasher, &expected, &url) {
+                    fs::remove_file(&jsonfile)?;
+                    return Err(e);
+                }
+            }
+        }
+        tokio::task::spawn_blocking(move || -> Result<HashMap<String, NixPkg>> {
+            let mut pkgout = HashMap::new();
+            let reader = compression.decoder(File::open(&jsonfile)?)?;
+            streampackages(reader, |attribute, pkg| {
+                pkgout.insert(attribute, pkg);
+            })?;
+            fs::remove_file(&jsonfile)?;
+            Ok(pkgout)
+        })
+        .await?
     } else {
-        downloadrelease(relver, nixosversion).await?
+        Err(NixDataError::Download(format!(
+            "No packages.json.zst, packages.json.br or packages.json found at {}",
+            releaseurl
+        )))
+    }
+}
+
+/// Records the nixpkgs revision of the package database at `dbfile`: `revision`, as reported by `nixos-version`
+/// on newer systems, or otherwise the revision published with the NixOS release at `releaseurl`.
+async fn recordrevision(dbfile: &str, revision: Option<&String>, releaseurl: &str) -> Result<()> {
+    let revision = match revision {
+        Some(rev) => Some(rev.to_string()),
+        None => publishedrevision(&httpclient(true)?, releaseurl).await,
     };
-    let dbfile = format!("{}/legacypkgs.db", &*CACHEDIR);
+    if let Some(rev) = revision {
+        setdbmetainfo(dbfile, "revision", &rev).await?;
+    }
+    Ok(())
+}
 
-    nixos::createdb(&dbfile, &pkgout).await?;
+/// Builds a package database from the local nixpkgs checkout at `nixpkgs` (e.g. `~/nixpkgs`) by evaluating it with `nix-env`,
+/// and returns the path to it. This gives the packages of the tree being worked on rather than of the system channel.
+///
+/// Databases are cached for each git revision of the checkout as `localpkgs-<rev>.db`, so evaluating the same revision again
+/// is skipped. Uncommitted changes aren't noticed; set [force](CacheConfig::force) to rebuild anyway.
+/// Checkouts that aren't git repositories are evaluated on every call.
+pub async fn localpkgs(nixpkgs: impl AsRef<Path>) -> Result<PathBuf> {
+    localpkgs_with_config(&CacheConfig::default(), nixpkgs).await
+}
 
-    // Write version downloaded to file
-    File::create(format!("{}/legacypkgs.ver", &*CACHEDIR))?.write_all(nixosversion.as_bytes())?;
+/// Like [localpkgs()], but caches the database in the directory given by `config`.
+pub async fn localpkgs_with_config(config: &CacheConfig, nixpkgs: impl AsRef<Path>) -> Result<PathBuf> {
+    let nixpkgs = nixpkgs.as_ref();
+    // Offline, a checkout without a `.git` directory can only be served from `localpkgs.db`, so skip probing it
+    let revision = if config.offline && !nixpkgs.join(".git").exists() {
+        None
+    } else {
+        tooloutput_async(
+            tokio::process::Command::new("git")
+                .arg("-C")
+                .arg(nixpkgs)
+                .arg("rev-parse")
+                .arg("HEAD"),
+        )
+        .await
+        .ok()
+        .filter(|x| x.status.success())
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .map(|x| x.trim().to_string())
+    };
+    let name = match &revision {
+        Some(rev) => format!("localpkgs-{}.db", rev),
+        None => String::from("localpkgs.db"),
+    };
+    if let Some(cached) = config.offlinefile(&name) {
+        return cached;
+    }
+    config.createdir()?;
+    let dbfile = config.file(&name);
+    if revision.is_some() && Path::new(&dbfile).exists() && !config.force {
+        info!("Using cached package database for {}", nixpkgs.display());
+        return Ok(dbfile.into());
+    }
 
-    Ok(format!("{}/legacypkgs.db", &*CACHEDIR))
+    // An empty config keeps the user's nixpkgs config, such as allowUnfree, from changing the package set
+    let output = tooloutput_async(
+        tokio::process::Command::new("nix-env")
+            .arg("-f")
+            .arg(nixpkgs)
+            .arg("-qaP")
+            .arg("--json")
+            .arg("--arg")
+            .arg("config")
+            .arg("{}"),
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(NixDataError::Other(format!(
+            "Failed to evaluate {}: {}",
+            nixpkgs.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let pkgs: HashMap<String, NixPkg> = serde_json::from_slice(&output.stdout)?;
+    nixos::createdbwithpnames(Path::new(&dbfile), &pkgs).await?;
+    setdbmetainfo(&dbfile, "source", DbSource::Evaluation.as_str()).await?;
+    if let Some(rev) = &revision {
+        setdbmetainfo(&dbfile, "revision", rev).await?;
+    }
+    Ok(dbfile.into())
 }
 
 /// Gets a list of all packages in NixOS systems with their attribute and version.
@@ -133,7 +267,7 @@ struct EnvPkgOut {
 /// Due to limitations of `nix-env`, the HashMap keys are the packages `pname` rather than `attributePath`.
 /// This means that finding more information about the specific derivations is more difficult.
 pub fn getenvpkgs() -> Result<HashMap<String, String>> {
-    let output = Command::new("nix-env").arg("-q").arg("--json").output()?;
+    let output = Command::new("nix-env").arg("-q").arg("--json").tooloutput()?;
     let pkgs: HashMap<String, EnvPkgOut> = serde_json::from_slice(&output.stdout)?;
     let mut out = HashMap::new();
     for (_, v) in pkgs {
@@ -143,8 +277,8 @@ pub fn getenvpkgs() -> Result<HashMap<String, String>> {
 }
 
 pub fn uptodate() -> Result<Option<(String, String)>> {
-    let legacyver = fs::read_to_string(&format!("{}/legacypkgs.ver", &*CACHEDIR))?;
-    let nixosver = fs::read_to_string(&format!("{}/nixospkgs.ver", &*CACHEDIR))?;
+    let legacyver = fs::read_to_string(format!("{}/legacypkgs.ver", &*CACHEDIR))?;
+    let nixosver = fs::read_to_string(format!("{}/nixospkgs.ver", &*CACHEDIR))?;
     if !nixosver.eq(&legacyver) {
         Ok(Some((legacyver, nixosver)))
     } else {
@@ -158,39 +292,27 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
         .arg("-E")
         .arg("with import <nixpkgs> {}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {} {})")
         .arg("--json")
-        .output()?;
+        .tooloutput()?;
     let aliasstr = String::from_utf8(aliases.stdout)?;
     let aliasesout: HashSet<String> = serde_json::from_str(&aliasstr)?;
 
-    let pkgs = {
-        let mut allpkgs: HashSet<String> = HashSet::new();
-        for path in paths {
-            if let Ok(filepkgs) = nix_editor::read::getarrvals(
-                &fs::read_to_string(path)?,
-                "environment.systemPackages",
-            ) {
-                let filepkgset = filepkgs
-                    .into_iter()
-                    .map(|x| x.strip_prefix("pkgs.").unwrap_or(&x).to_string())
-                    .collect::<HashSet<_>>();
-                allpkgs = allpkgs.union(&filepkgset).map(|x| x.to_string()).collect();
-            }
-        }
-        allpkgs
-    };
+    let (pkgs, custom) = nixos::readsystempkgs(paths, nixos::NixosType::Legacy)?;
 
     let mut unavailable = HashMap::new();
+    for pkg in custom {
+        unavailable.insert(pkg, String::from("Unresolvable (custom derivation)"));
+    }
     for pkg in pkgs {
         if aliasesout.contains(&pkg) && Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import <nixpkgs> {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg))
-                .output()?.status.success() {
+                .arg(format!("with import <nixpkgs> {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg))
+                .tooloutput()?.status.success() {
             let out = Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import <nixpkgs> {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg))
-                .output()?;
+                .arg(format!("with import <nixpkgs> {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg))
+                .tooloutput()?;
             let err = String::from_utf8(out.stderr)?;
             let err = err.strip_prefix("error: ").unwrap_or(&err).trim();
             unavailable.insert(pkg, err.to_string());
@@ -199,7 +321,8 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
 
     let legacypkgs = getlegacypkgs(paths).await?;
     let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
+    let pool = connectdb(&nixospkgs).await?;
+    requiremeta(&pool).await?;
 
     for (pkg, _) in legacypkgs {
         let (x, broken, insecure): (String, u8, u8) =
@@ -220,3 +343,158 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
     }
     Ok(unavailable)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{query::db_revision, testconfig, testdir, testserver};
+
+    #[tokio::test]
+    async fn published_revision_is_stored() {
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        let url = testserver(move |_, path| match path {
+            "/nixos/23.05/nixos-23.05.1234.abcdef/git-revision" => (200, vec![], format!("{}\n", rev).into_bytes()),
+            _ => (404, vec![], vec![]),
+        });
+        let dir = testdir("revision");
+        let db = dir.join("legacypkgs.db");
+        let pkgs = HashMap::from([(String::from("hello"), String::from("2.12"))]);
+        nixos::createdb(&db, &pkgs).await.unwrap();
+        let dbfile = db.to_string_lossy();
+
+        let releaseurl = format!("{}/nixos/23.05/nixos-23.05.1234.abcdef", url);
+        recordrevision(&dbfile, None, &releaseurl).await.unwrap();
+        assert_eq!(db_revision(&db).await.unwrap().as_deref(), Some(rev));
+
+        // A revision reported by nixos-version is stored as is
+        let reported = String::from("fedcba9876543210fedcba9876543210fedcba98");
+        recordrevision(&dbfile, Some(&reported), &releaseurl).await.unwrap();
+        assert_eq!(db_revision(&db).await.unwrap(), Some(reported));
+    }
+
+    #[tokio::test]
+    async fn zstd_release_is_imported() {
+        let json = r#"{"version":2,"packages":{"hello":{"pname":"hello","version":"2.12","system":"x86_64-linux"},"python3Packages.requests":{"pname":"python3.11-requests","version":"2.31.0","system":"x86_64-linux"}}}"#;
+        let payload = zstd::encode_all(json.as_bytes(), 0).unwrap();
+        let hash = format!("{:x}", Sha256::digest(&payload));
+        let url = testserver(move |_, path| match path {
+            "/release/packages.json.zst" => (200, vec![], payload.clone()),
+            "/release/packages.json.zst.sha256" => (200, vec![], hash.as_bytes().to_vec()),
+            _ => (404, vec![], vec![]),
+        });
+        let dir = testdir("zstd-release");
+        let config = testconfig(&dir);
+        let pkgout = downloadrelease(&config, &format!("{}/release", url)).await.unwrap();
+        let db = dir.join("legacypkgs.db");
+        nixos::createdbwithpnames(&db, &pkgout).await.unwrap();
+
+        let pool = connectdb(&db).await.unwrap();
+        let pkgs: Vec<(String, String, String)> =
+            sqlx::query_as("SELECT attribute, pname, version FROM pkgs ORDER BY attribute")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            pkgs,
+            vec![
+                ("hello".into(), "hello".into(), "2.12".into()),
+                ("python3Packages.requests".into(), "python3.11-requests".into(), "2.31.0".into()),
+            ]
+        );
+        // The compressed download is removed once parsed
+        assert!(!Path::new(&config.file("legacypackages.json.download")).exists());
+    }
+
+    const FIXTURE: &str = r#"
+{ ... }:
+let
+  mk = pname: version: derivation {
+    name = "${pname}-${version}";
+    inherit pname version;
+    system = builtins.currentSystem;
+    builder = "/bin/sh";
+  };
+in {
+  foo = mk "foo" "1.0";
+  bar = mk "bar" "2.0";
+}
+"#;
+
+    #[tokio::test]
+    #[ignore = "evaluates a fixture nixpkgs with nix-env, which needs Nix installed"]
+    async fn localpkgs_evaluates_fixture() {
+        let dir = testdir("localpkgs");
+        let nixpkgs = dir.join("nixpkgs");
+        fs::create_dir_all(&nixpkgs).unwrap();
+        fs::write(nixpkgs.join("default.nix"), FIXTURE).unwrap();
+        let config = testconfig(&dir.join("cache"));
+        let db = localpkgs_with_config(&config, &nixpkgs).await.unwrap();
+        assert_eq!(db, config.dir.join("localpkgs.db"));
+        let pool = connectdb(&db).await.unwrap();
+        let pkgs: Vec<(String, String)> = sqlx::query_as("SELECT attribute, version FROM pkgs ORDER BY attribute")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(pkgs, vec![("bar".into(), "2.0".into()), ("foo".into(), "1.0".into())]);
+    }
+
+    /// Compresses `data` with brotli, as the release and nixpkgs-version-data files are published.
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+            writer.write_all(data).unwrap();
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn legacy_download_paths() {
+        let release = brotli(br#"{"version":2,"packages":{"hello":{"pname":"hello","version":"2.12"}}}"#);
+        let versiondata = brotli(br#"{"hello":"2.10"}"#);
+        let url = testserver(move |_, path| match path {
+            "/release/packages.json.br" => (200, vec![], release.clone()),
+            "/versiondata/nixos-unstable/rev.json.br" => (200, vec![], versiondata.clone()),
+            _ => (404, vec![], vec![]),
+        });
+        let dir = testdir("legacy-download");
+        let config = testconfig(&dir);
+        let db = dir.join("legacypkgs.db");
+        let dbfile = db.to_string_lossy();
+        let releaseurl = format!("{}/release", url);
+        let missing = format!("{}/versiondata/nixos-23.05/rev.json.br", url);
+
+        // Without published version data, the release's packages.json is downloaded rather than evaluating nixpkgs
+        let source = importlegacy(&config, &dbfile, std::slice::from_ref(&missing), &releaseurl).await.unwrap();
+        assert_eq!(source, DbSource::Release);
+        let pool = connectdb(&db).await.unwrap();
+        let pkgs: Vec<(String, String, String)> = sqlx::query_as("SELECT attribute, pname, version FROM pkgs")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(pkgs, vec![("hello".into(), "hello".into(), "2.12".into())]);
+        pool.close().await;
+
+        // The first version data that is published is preferred
+        let available = format!("{}/versiondata/nixos-unstable/rev.json.br", url);
+        let source = importlegacy(&config, &dbfile, &[missing, available], &releaseurl).await.unwrap();
+        assert_eq!(source, DbSource::VersionData);
+        let pool = connectdb(&db).await.unwrap();
+        let pkgs: Vec<(String, String)> = sqlx::query_as("SELECT attribute, version FROM pkgs")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(pkgs, vec![("hello".into(), "2.10".into())]);
+    }
+
+    #[tokio::test]
+    async fn localpkgs_offline_without_git() {
+        let dir = testdir("localpkgs-offline");
+        let config = CacheConfig {
+            offline: true,
+            ..testconfig(&dir)
+        };
+        let err = localpkgs_with_config(&config, &dir).await.unwrap_err();
+        assert!(matches!(err, NixDataError::NotCached(_)), "{}", err);
+    }
+}
@@ -1,84 +1,83 @@
 use crate::CACHEDIR;
-use anyhow::{Context, Result};
+use crate::error::{tooloutput_async, CommandExt, NixDataError, Result};
 use log::info;
-use sqlx::SqlitePool;
+use serde::Deserialize;
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
     process::Command,
 };
 
 use super::{
-    nixos::{self, getnixospkgs, nixospkgs},
-    NixPkg,
+    connectdb, httpclient,
+    nixos::{self, getnixospkgs, nixospkgs, DbImportStats},
+    query::DbSource,
+    requiremeta, setdbmetainfo, CacheConfig, NixPkg,
 };
 
 /// Gets a list of all packages in the NixOS system with their name and version.
 /// Can be used to find what versions of system packages are currently installed.
 /// Will only work on NixOS systems.
-pub async fn flakespkgs() -> Result<String> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
+pub async fn flakespkgs() -> Result<PathBuf> {
+    flakespkgs_with_config(&CacheConfig::default()).await
+}
+
+/// Like [flakespkgs()], but caches the database in the directory given by `config`.
+pub async fn flakespkgs_with_config(config: &CacheConfig) -> Result<PathBuf> {
+    if let Some(cached) = config.offlinefile("flakespkgs.db") {
+        return cached;
+    }
+    let versionout = Command::new("nixos-version").arg("--json").tooloutput()?;
     let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
 
     let nixosversion = version
         .get("nixosVersion")
-        .context("No NixOS version found")?;
+        .ok_or_else(|| NixDataError::Parse(String::from("No NixOS version found")))?;
 
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
+    config.createdir()?;
 
     // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/flakespkgs.ver", &*CACHEDIR)) {
-        if prevver.eq(nixosversion) && Path::new(&format!("{}/flakespkgs.db", &*CACHEDIR)).exists()
-        {
+    if let Ok(prevver) = fs::read_to_string(config.file("flakespkgs.ver")) {
+        if prevver.eq(nixosversion) && Path::new(&config.file("flakespkgs.db")).exists() && !config.force {
             info!("No new version of NixOS flakes found");
-            return Ok(format!("{}/flakespkgs.db", &*CACHEDIR));
+            return Ok(config.file("flakespkgs.db").into());
         }
     }
 
-    // Get list of packages from flake
-    let pkgsout = if let Some(rev) = version.get("nixpkgsRevision") {
-        let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-{}/{}.json.br", nixosversion.get(0..5).context("Invalid NixOS version")?, rev);
-        let resp = reqwest::get(&url).await?;
+    // Get list of packages from flake. Only `nix search` gives the pname of each package.
+    let dbfile = config.file("flakespkgs.db");
+    let source = if let Some(rev) = version.get("nixpkgsRevision") {
+        let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-{}/{}.json.br", nixos::parsenixosversion(nixosversion)?, rev);
+        let resp = httpclient(true)?.get(&url).send().await?;
         if resp.status().is_success() {
             let r = resp.bytes().await?;
             let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
             let mut pkgsout = Vec::new();
             br.read_to_end(&mut pkgsout)?;
             let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-            pkgsjson
+            nixos::createdb(Path::new(&dbfile), &pkgsjson).await?;
+            DbSource::VersionData
         } else {
             let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-unstable/{}.json.br", rev);
-            let resp = reqwest::get(&url).await?;
+            let resp = httpclient(true)?.get(&url).send().await?;
             if resp.status().is_success() {
                 let r = resp.bytes().await?;
                 let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
                 let mut pkgsout = Vec::new();
                 br.read_to_end(&mut pkgsout)?;
                 let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-                pkgsjson
+                nixos::createdb(Path::new(&dbfile), &pkgsjson).await?;
+                DbSource::VersionData
             } else {
                 let pkgsout = Command::new("nix")
                     .arg("search")
                     .arg("--json")
-                    .arg(&format!("nixpkgs/{}", rev))
-                    .output()?;
-                let pkgsjson: HashMap<String, NixPkg> =
-                    serde_json::from_str(&String::from_utf8(pkgsout.stdout)?)?;
-                let pkgsjson = pkgsjson
-                    .iter()
-                    .map(|(k, v)| {
-                        (
-                            k.split('.').collect::<Vec<_>>()[2..].join("."),
-                            v.version.to_string(),
-                        )
-                    })
-                    .collect::<HashMap<String, String>>();
-                pkgsjson
+                    .arg(format!("nixpkgs/{}", rev))
+                    .tooloutput()?;
+                nixos::createdbwithpnames(Path::new(&dbfile), &parsesearchjson(pkgsout.stdout.as_slice())?).await?;
+                DbSource::NixSearch
             }
         }
     } else {
@@ -88,28 +87,285 @@ pub async fn flakespkgs() -> Result<String> {
             // .arg("--inputs-from")
             // .arg(&flakepath)
             .arg("nixpkgs")
-            .output()?;
-        let pkgsjson: HashMap<String, NixPkg> =
-            serde_json::from_str(&String::from_utf8(pkgsout.stdout)?)?;
-        let pkgsjson = pkgsjson
-            .iter()
-            .map(|(k, v)| {
-                (
-                    k.split('.').collect::<Vec<_>>()[2..].join("."),
-                    v.version.to_string(),
-                )
-            })
-            .collect::<HashMap<String, String>>();
-        pkgsjson
+            .tooloutput()?;
+        nixos::createdbwithpnames(Path::new(&dbfile), &parsesearchjson(pkgsout.stdout.as_slice())?).await?;
+        DbSource::NixSearch
     };
+    setdbmetainfo(&dbfile, "source", source.as_str()).await?;
 
-    let dbfile = format!("{}/flakespkgs.db", &*CACHEDIR);
-    nixos::createdb(&dbfile, &pkgsout).await?;
+    // Without a revision from `nixos-version`, the packages came from the `nixpkgs` flake in the registry
+    let revision = match version.get("nixpkgsRevision") {
+        Some(rev) => Some(rev.to_string()),
+        None => flakerevision("nixpkgs"),
+    };
+    if let Some(rev) = revision {
+        setdbmetainfo(&dbfile, "revision", &rev).await?;
+    }
 
     // Write version downloaded to file
-    File::create(format!("{}/flakespkgs.ver", &*CACHEDIR))?.write_all(nixosversion.as_bytes())?;
+    File::create(config.file("flakespkgs.ver"))?.write_all(nixosversion.as_bytes())?;
+
+    Ok(config.file("flakespkgs.db").into())
+}
+
+#[derive(Debug, Deserialize)]
+struct FlakeMetadata {
+    revision: Option<String>,
+    url: Option<String>,
+}
+
+/// Returns the git revision `flakeref` is locked to by `nix flake metadata`, or `None` if it can't be locked.
+fn flakerevision(flakeref: &str) -> Option<String> {
+    let output = Command::new("nix")
+        .arg("flake")
+        .arg("metadata")
+        .arg("--json")
+        .arg(flakeref)
+        .tooloutput()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    serde_json::from_slice::<FlakeMetadata>(&output.stdout).ok()?.revision
+}
+
+/// Like [flakespkgs()], but builds the package database from the nixpkgs flake `flakeref`,
+/// such as `github:NixOS/nixpkgs/<rev>` or `nixpkgs/nixos-23.05`, rather than from the nixpkgs of the running system.
+/// This gives correct versions for systems built from a pinned or non-default nixpkgs input.
+///
+/// `flakeref` is locked with `nix flake metadata`, unless it already names a full git revision,
+/// and a separate database is cached for each locked revision,
+/// so switching between revisions doesn't evaluate nixpkgs again. Requires a working `nix` with flakes enabled.
+/// [Offline](CacheConfig::offline), `flakeref` is locked without fetching it.
+pub async fn flakespkgs_for(flakeref: &str) -> Result<PathBuf> {
+    flakespkgs_for_with_config(&CacheConfig::default(), flakeref).await
+}
+
+/// Like [flakespkgs_for()], but caches the database in the directory given by `config`.
+pub async fn flakespkgs_for_with_config(config: &CacheConfig, flakeref: &str) -> Result<PathBuf> {
+    if let Some(rev) = flakerefrev(flakeref) {
+        return flakespkgsforrev(config, flakeref, rev).await;
+    }
+
+    let mut cmd = tokio::process::Command::new("nix");
+    cmd.arg("flake").arg("metadata").arg("--json").arg(flakeref);
+    if config.offline {
+        cmd.arg("--offline");
+    }
+    let output = tooloutput_async(&mut cmd).await?;
+    if !output.status.success() {
+        return Err(NixDataError::ChannelResolve(format!(
+            "Failed to lock {}: {}",
+            flakeref,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let metadata: FlakeMetadata = serde_json::from_slice(&output.stdout)?;
+    let rev = metadata
+        .revision
+        .ok_or_else(|| NixDataError::ChannelResolve(format!("{} is not locked to a revision", flakeref)))?;
+    let lockedref = metadata.url.unwrap_or_else(|| flakeref.to_string());
+    flakespkgsforrev(config, &lockedref, &rev).await
+}
+
+/// Returns the full git revision `flakeref` is pinned to, given either as a `rev` parameter
+/// or as the last path segment, such as in `github:NixOS/nixpkgs/<rev>`.
+fn flakerefrev(flakeref: &str) -> Option<&str> {
+    let isrev = |x: &&str| x.len() == 40 && x.bytes().all(|b| b.is_ascii_hexdigit());
+    let (path, query) = flakeref.split_once('?').unwrap_or((flakeref, ""));
+    query
+        .split('&')
+        .find_map(|x| x.strip_prefix("rev="))
+        .filter(isrev)
+        .or_else(|| path.rsplit('/').next().filter(isrev))
+}
+
+/// Returns the package database for the nixpkgs flake `lockedref`, locked to `rev`, building it if it isn't cached.
+async fn flakespkgsforrev(config: &CacheConfig, lockedref: &str, rev: &str) -> Result<PathBuf> {
+    let name = format!("flakespkgs-{}.db", rev);
+    if let Some(cached) = config.offlinefile(&name) {
+        return cached;
+    }
+    config.createdir()?;
+    let dbfile = config.file(&name);
+    if Path::new(&dbfile).exists() && !config.force {
+        info!("Using cached package database for {}", rev);
+        return Ok(dbfile.into());
+    }
+
+    let pkgsout = tooloutput_async(
+        tokio::process::Command::new("nix")
+            .arg("search")
+            .arg("--json")
+            .arg(lockedref)
+            .arg("^"),
+    )
+    .await?;
+    if !pkgsout.status.success() {
+        return Err(NixDataError::Other(format!(
+            "Failed to search {}: {}",
+            lockedref,
+            String::from_utf8_lossy(&pkgsout.stderr).trim()
+        )));
+    }
+    let pkgs = parsesearchjson(pkgsout.stdout.as_slice())?;
+    nixos::createdbwithpnames(Path::new(&dbfile), &pkgs).await?;
+    setdbmetainfo(&dbfile, "source", DbSource::NixSearch.as_str()).await?;
+    setdbmetainfo(&dbfile, "revision", rev).await?;
+    Ok(dbfile.into())
+}
+
+/// Like [getflakepkgs()], but looks up versions in the database built by [flakespkgs_for()] for `flakeref`.
+pub async fn getflakepkgs_for(paths: &[&str], flakeref: &str) -> Result<HashMap<String, String>> {
+    let (pkgs, _) = nixos::readsystempkgs(paths, nixos::NixosType::Flake)?;
+    let pkgsdb = flakespkgs_for(flakeref).await?;
+    let pool = connectdb(&pkgsdb).await?;
+    nixos::queryversions(&pool, pkgs).await
+}
+
+#[derive(Debug, Deserialize)]
+struct FlakeLock {
+    root: String,
+    nodes: HashMap<String, FlakeLockNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlakeLockNode {
+    #[serde(default)]
+    inputs: HashMap<String, FlakeLockInput>,
+    locked: Option<FlakeLockLocked>,
+}
+
+/// An input of a `flake.lock` node: either the name of the node it's locked to,
+/// or, for inputs that `follows` another, the path of inputs to follow from the root node.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FlakeLockInput {
+    Node(String),
+    Follows(Vec<String>),
+}
 
-    Ok(format!("{}/flakespkgs.db", &*CACHEDIR))
+#[derive(Debug, Deserialize)]
+struct FlakeLockLocked {
+    #[serde(rename = "type")]
+    kind: String,
+    rev: Option<String>,
+    owner: Option<String>,
+    repo: Option<String>,
+    url: Option<String>,
+}
+
+/// The `nixpkgs` input of a flake, as pinned by its `flake.lock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockedNixpkgs {
+    /// Git revision nixpkgs is locked to.
+    pub rev: String,
+    /// Flake reference of nixpkgs at that revision, such as `github:NixOS/nixpkgs/<rev>`.
+    pub flakeref: String,
+}
+
+impl FlakeLock {
+    /// Finds the node reached by following the inputs in `path` from the root node, resolving `follows` declarations.
+    /// At most `depth` nested `follows` are resolved, so that a cyclic lockfile doesn't recurse forever.
+    fn resolve(&self, path: &[String], depth: usize) -> Option<&FlakeLockNode> {
+        let mut node = self.nodes.get(&self.root)?;
+        for name in path {
+            node = match node.inputs.get(name)? {
+                FlakeLockInput::Node(x) => self.nodes.get(x)?,
+                FlakeLockInput::Follows(x) => self.resolve(x, depth.checked_sub(1)?)?,
+            };
+        }
+        Some(node)
+    }
+}
+
+/// Reads the `nixpkgs` input pinned by the `flake.lock` at `lockfile`.
+/// Fails with [NixDataError::Parse] if the lockfile has no `nixpkgs` input, or it isn't locked to a git revision.
+pub fn locked_nixpkgs(lockfile: &Path) -> Result<LockedNixpkgs> {
+    let lock: FlakeLock = serde_json::from_reader(BufReader::new(File::open(lockfile)?))?;
+    let locked = lock
+        .resolve(&[String::from("nixpkgs")], lock.nodes.len())
+        .and_then(|x| x.locked.as_ref())
+        .ok_or_else(|| {
+            NixDataError::Parse(format!("{} has no nixpkgs input", lockfile.display()))
+        })?;
+    let rev = locked.rev.clone().ok_or_else(|| {
+        NixDataError::Parse(format!(
+            "nixpkgs is not locked to a revision in {}",
+            lockfile.display()
+        ))
+    })?;
+    let flakeref = match (locked.kind.as_str(), &locked.owner, &locked.repo, &locked.url) {
+        (kind @ ("github" | "gitlab" | "sourcehut"), Some(owner), Some(repo), _) => {
+            format!("{}:{}/{}/{}", kind, owner, repo, rev)
+        }
+        ("git", _, _, Some(url)) => format!("git+{}?rev={}", url, rev),
+        (kind, ..) => {
+            return Err(NixDataError::Parse(format!(
+                "Unsupported nixpkgs input type {} in {}",
+                kind,
+                lockfile.display()
+            )))
+        }
+    };
+    Ok(LockedNixpkgs { rev, flakeref })
+}
+
+/// Like [flakespkgs_for()], but builds the package database for the nixpkgs revision pinned by the `flake.lock` at `lockfile`,
+/// rather than for a flake reference. This gives the versions the flake is actually built with,
+/// however far the live channel has moved on. As with [flakespkgs_for()], a database is cached for each revision.
+pub async fn flakespkgs_from_lock(lockfile: &Path) -> Result<PathBuf> {
+    flakespkgs_from_lock_with_config(&CacheConfig::default(), lockfile).await
+}
+
+/// Like [flakespkgs_from_lock()], but caches the database in the directory given by `config`.
+pub async fn flakespkgs_from_lock_with_config(config: &CacheConfig, lockfile: &Path) -> Result<PathBuf> {
+    let locked = locked_nixpkgs(lockfile)?;
+    flakespkgsforrev(config, &locked.flakeref, &locked.rev).await
+}
+
+/// Like [getflakepkgs()], but looks up versions in the database built by [flakespkgs_from_lock()] for `lockfile`.
+pub async fn getflakepkgs_from_lock(paths: &[&str], lockfile: &Path) -> Result<HashMap<String, String>> {
+    let (pkgs, _) = nixos::readsystempkgs(paths, nixos::NixosType::Flake)?;
+    let pkgsdb = flakespkgs_from_lock(lockfile).await?;
+    let pool = connectdb(&pkgsdb).await?;
+    nixos::queryversions(&pool, pkgs).await
+}
+
+/// Parses the output of `nix search --json` into a map of attribute to package.
+fn parsesearchjson<R: Read>(reader: R) -> Result<HashMap<String, NixPkg>> {
+    let pkgsjson: HashMap<String, NixPkg> = serde_json::from_reader(BufReader::new(reader))?;
+    let pkgsjson = pkgsjson
+        .into_iter()
+        .filter_map(|(k, v)| {
+            let attr = k.split('.').collect::<Vec<_>>().get(2..)?.join(".");
+            Some((attr, v))
+        })
+        .collect::<HashMap<String, NixPkg>>();
+    Ok(pkgsjson)
+}
+
+/// Builds a package database at `db` from the output of `nix search --json`, read from `reader`.
+/// This gives flake users a local alternative to downloading channel data.
+///
+/// `nix search --json nixpkgs` emits a flat object keyed by the full flake output path:
+/// ```json
+/// {
+///   "legacyPackages.x86_64-linux.hello": {
+///     "description": "A program that produces a familiar, friendly greeting",
+///     "pname": "hello",
+///     "version": "2.12.1"
+///   }
+/// }
+/// ```
+/// The `legacyPackages.<system>.` prefix is stripped to give the attribute (`hello`),
+/// and `pname` and `version` are stored with it. `description` is not stored,
+/// matching the databases built by [flakespkgs()].
+///
+/// Returns how many packages were stored, and how many malformed entries (with an empty attribute or version) were skipped.
+pub async fn build_db_from_search_json<R: Read>(reader: R, db: impl AsRef<Path>) -> Result<DbImportStats> {
+    let pkgs = parsesearchjson(reader)?;
+    nixos::createdbwithpnames(db.as_ref(), &pkgs).await
 }
 
 /// Returns a list of all installed system packages with their attribute and version
@@ -119,19 +375,19 @@ pub async fn getflakepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
 }
 
 pub fn uptodate() -> Result<Option<(String, String)>> {
-    let flakesver = fs::read_to_string(&format!("{}/flakespkgs.ver", &*CACHEDIR))?;
-    let nixosver = fs::read_to_string(&format!("{}/nixospkgs.ver", &*CACHEDIR))?;
+    let flakesver = fs::read_to_string(format!("{}/flakespkgs.ver", &*CACHEDIR))?;
+    let nixosver = fs::read_to_string(format!("{}/nixospkgs.ver", &*CACHEDIR))?;
     let flakeslast = flakesver
         .split('.')
         .collect::<Vec<_>>()
         .last()
-        .context("Invalid version")?
+        .ok_or_else(|| NixDataError::Parse(String::from("Invalid version")))?
         .to_string();
     let nixoslast = nixosver
         .split('.')
         .collect::<Vec<_>>()
         .last()
-        .context("Invalid version")?
+        .ok_or_else(|| NixDataError::Parse(String::from("Invalid version")))?
         .to_string();
     if !nixoslast.starts_with(&flakeslast) {
         Ok(Some((flakesver, nixosver)))
@@ -141,19 +397,19 @@ pub fn uptodate() -> Result<Option<(String, String)>> {
 }
 
 pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
+    let versionout = Command::new("nixos-version").arg("--json").tooloutput()?;
     let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
     let nixpath = if let Some(rev) = version.get("nixpkgsRevision") {
         Command::new("nix")
             .arg("eval")
-            .arg(&format!("nixpkgs/{}#path", rev))
-            .output()?
+            .arg(format!("nixpkgs/{}#path", rev))
+            .tooloutput()?
             .stdout
     } else {
         Command::new("nix")
             .arg("eval")
             .arg("nixpkgs#path")
-            .output()?
+            .tooloutput()?
             .stdout
     };
     let nixpath = String::from_utf8(nixpath)?;
@@ -162,41 +418,29 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
     let aliases = Command::new("nix-instantiate")
         .arg("--eval")
         .arg("-E")
-        .arg(&format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
+        .arg(format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
         .arg("--json")
-        .output()?;
+        .tooloutput()?;
     let aliasstr = String::from_utf8(aliases.stdout)?;
     let aliasesout: HashSet<String> = serde_json::from_str(&aliasstr)?;
 
-    let pkgs = {
-        let mut allpkgs: HashSet<String> = HashSet::new();
-        for path in paths {
-            if let Ok(filepkgs) = nix_editor::read::getarrvals(
-                &fs::read_to_string(path)?,
-                "environment.systemPackages",
-            ) {
-                let filepkgset = filepkgs
-                    .into_iter()
-                    .map(|x| x.strip_prefix("pkgs.").unwrap_or(&x).to_string())
-                    .collect::<HashSet<_>>();
-                allpkgs = allpkgs.union(&filepkgset).map(|x| x.to_string()).collect();
-            }
-        }
-        allpkgs
-    };
+    let (pkgs, custom) = nixos::readsystempkgs(paths, nixos::NixosType::Flake)?;
 
     let mut unavailable = HashMap::new();
+    for pkg in custom {
+        unavailable.insert(pkg, String::from("Unresolvable (custom derivation)"));
+    }
     for pkg in pkgs {
         if aliasesout.contains(&pkg) && Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?.status.success() {
+                .arg(format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
+                .tooloutput()?.status.success() {
             let out = Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?;
+                .arg(format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
+                .tooloutput()?;
             let err = String::from_utf8(out.stderr)?;
             let err = err.strip_prefix("error: ").unwrap_or(&err).trim();
             unavailable.insert(pkg, err.to_string());
@@ -205,7 +449,8 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
 
     let profilepkgs = getflakepkgs(paths).await?;
     let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
+    let pool = connectdb(&nixospkgs).await?;
+    requiremeta(&pool).await?;
 
     for (pkg, _) in profilepkgs {
         let (x, broken, insecure): (String, u8, u8) =
@@ -226,3 +471,76 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
     }
     Ok(unavailable)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{testconfig, testdir};
+
+    const REV: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    const LOCK: &str = r#"{
+  "nodes": {
+    "home-manager": {
+      "inputs": { "nixpkgs": ["nixpkgs"] },
+      "locked": { "owner": "nix-community", "repo": "home-manager", "rev": "fedcba9876543210fedcba9876543210fedcba98", "type": "github" }
+    },
+    "nixpkgs": {
+      "locked": { "owner": "NixOS", "repo": "nixpkgs", "rev": "0123456789abcdef0123456789abcdef01234567", "type": "github" }
+    },
+    "root": {
+      "inputs": { "home-manager": "home-manager", "nixpkgs": "nixpkgs" }
+    }
+  },
+  "root": "root",
+  "version": 7
+}"#;
+
+    #[test]
+    fn revision_from_flakeref() {
+        assert_eq!(flakerefrev(&format!("github:NixOS/nixpkgs/{}", REV)), Some(REV));
+        assert_eq!(flakerefrev(&format!("git+https://github.com/NixOS/nixpkgs?ref=master&rev={}", REV)), Some(REV));
+        assert_eq!(flakerefrev("github:NixOS/nixpkgs/nixos-23.05"), None);
+        assert_eq!(flakerefrev("nixpkgs"), None);
+    }
+
+    #[tokio::test]
+    async fn pinned_flakeref_uses_cache_offline() {
+        let dir = testdir("flakespkgs-for");
+        let config = CacheConfig {
+            offline: true,
+            ..testconfig(&dir)
+        };
+        let flakeref = format!("github:NixOS/nixpkgs/{}", REV);
+        let err = flakespkgs_for_with_config(&config, &flakeref).await.unwrap_err();
+        assert!(matches!(err, NixDataError::NotCached(_)), "{}", err);
+
+        let pkgs = HashMap::from([(String::from("hello"), String::from("2.12"))]);
+        let db = dir.join(format!("flakespkgs-{}.db", REV));
+        nixos::createdb(&db, &pkgs).await.unwrap();
+        assert_eq!(flakespkgs_for_with_config(&config, &flakeref).await.unwrap(), db);
+    }
+
+    #[tokio::test]
+    async fn lockfile_revision() {
+        let dir = testdir("flakespkgs-from-lock");
+        let lockfile = dir.join("flake.lock");
+        fs::write(&lockfile, LOCK).unwrap();
+        assert_eq!(
+            locked_nixpkgs(&lockfile).unwrap(),
+            LockedNixpkgs {
+                rev: REV.to_string(),
+                flakeref: format!("github:NixOS/nixpkgs/{}", REV),
+            }
+        );
+
+        let config = CacheConfig {
+            offline: true,
+            ..testconfig(&dir)
+        };
+        let pkgs = HashMap::from([(String::from("hello"), String::from("2.12"))]);
+        let db = dir.join(format!("flakespkgs-{}.db", REV));
+        nixos::createdb(&db, &pkgs).await.unwrap();
+        assert_eq!(flakespkgs_from_lock_with_config(&config, &lockfile).await.unwrap(), db);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+use super::{connectdb, requiremeta};
+
+/// License of a package, as given in its `meta.license` in nixpkgs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum License {
+    /// A license given only by name, e.g. `mit` or `unfree`.
+    Name(String),
+    /// A license from `lib.licenses`, with whatever details nixpkgs records for it.
+    Detailed {
+        /// SPDX identifier, e.g. `MPL-2.0`.
+        #[serde(rename = "spdxId")]
+        spdx_id: Option<String>,
+        /// Full name, e.g. `Mozilla Public License 2.0`.
+        #[serde(rename = "fullName")]
+        full_name: Option<String>,
+        /// Short name used in nixpkgs, e.g. `mpl20`.
+        #[serde(rename = "shortName")]
+        short_name: Option<String>,
+        /// Whether the license is free.
+        free: Option<bool>,
+        /// URL of the license text.
+        url: Option<String>,
+    },
+}
+
+impl License {
+    /// SPDX identifier of the license, if known.
+    pub fn spdx_id(&self) -> Option<&str> {
+        match self {
+            License::Name(_) => None,
+            License::Detailed { spdx_id, .. } => spdx_id.as_deref(),
+        }
+    }
+
+    /// Whether the license is free, if known. Licenses given only by name are only known to be unfree if named `unfree`.
+    pub fn is_free(&self) -> Option<bool> {
+        match self {
+            License::Name(name) if name == "unfree" => Some(false),
+            License::Name(_) => None,
+            License::Detailed { free, .. } => *free,
+        }
+    }
+}
+
+/// Reads the text stored in `column` of the `meta` table for `attribute`.
+/// Returns `None` if the attribute or the value doesn't exist.
+async fn metatext(db: impl AsRef<Path>, attribute: &str, column: &str) -> Result<Option<String>> {
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    let row: Option<(Option<String>,)> =
+        sqlx::query_as(&format!("SELECT {} FROM meta WHERE attribute = $1", column))
+            .bind(attribute)
+            .fetch_optional(&pool)
+            .await?;
+    Ok(row.and_then(|(x,)| x))
+}
+
+/// Reads the JSON stored in `column` of the `meta` table for `attribute`.
+/// Returns `None` if the attribute or the value doesn't exist.
+async fn metajson(db: impl AsRef<Path>, attribute: &str, column: &str) -> Result<Option<Value>> {
+    match metatext(db, attribute, column).await? {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+/// Parses `value`, which is either a single `T` or a list of them, skipping entries that don't parse.
+fn oneormany<T: for<'de> Deserialize<'de>>(value: Value) -> Vec<T> {
+    match value {
+        Value::Array(values) => values
+            .into_iter()
+            .filter_map(|x| serde_json::from_value(x).ok())
+            .collect(),
+        value => serde_json::from_value(value).into_iter().collect(),
+    }
+}
+
+/// Returns the licenses of `attribute` in the package database at `db`.
+/// nixpkgs stores a license as a name, as an object from `lib.licenses`, or as a list of either; all are returned as a list.
+/// Returns an empty list if the package doesn't exist or has no license.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn package_licenses(db: impl AsRef<Path>, attribute: &str) -> Result<Vec<License>> {
+    Ok(metajson(db, attribute, "license")
+        .await?
+        .map(oneormany)
+        .unwrap_or_default())
+}
+
+/// Maintainer of a package, as given in its `meta.maintainers` in nixpkgs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Maintainer {
+    /// Name of the maintainer.
+    pub name: Option<String>,
+    /// Email address of the maintainer.
+    pub email: Option<String>,
+    /// GitHub username of the maintainer.
+    pub github: Option<String>,
+    /// Numeric GitHub user id of the maintainer.
+    #[serde(rename = "githubId")]
+    pub github_id: Option<u64>,
+}
+
+/// A maintainer as stored in `meta.maintainers`, which is occasionally just a name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaintainerJson {
+    Name(String),
+    Detailed(Maintainer),
+}
+
+impl From<MaintainerJson> for Maintainer {
+    fn from(maintainer: MaintainerJson) -> Self {
+        match maintainer {
+            MaintainerJson::Name(name) => Maintainer {
+                name: Some(name),
+                ..Default::default()
+            },
+            MaintainerJson::Detailed(maintainer) => maintainer,
+        }
+    }
+}
+
+/// Returns the maintainers of `attribute` in the package database at `db`. Maintainers given only by name have the other fields unset.
+/// Returns an empty list if the package doesn't exist or has no maintainers.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn package_maintainers(db: impl AsRef<Path>, attribute: &str) -> Result<Vec<Maintainer>> {
+    Ok(metajson(db, attribute, "maintainers")
+        .await?
+        .map(oneormany::<MaintainerJson>)
+        .unwrap_or_default()
+        .into_iter()
+        .map(Maintainer::from)
+        .collect())
+}
+
+/// Returns the platforms `attribute` is available on in the package database at `db`, e.g. `x86_64-linux`.
+/// Only platforms listed by name are returned; entries of `meta.platforms` that are predicates
+/// matching a group of platforms (such as `{ "kernel": { "name": "linux" } }`) are skipped.
+/// Returns an empty list if the package doesn't exist or has no platforms recorded.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn package_platforms(db: impl AsRef<Path>, attribute: &str) -> Result<Vec<String>> {
+    Ok(metajson(db, attribute, "platforms")
+        .await?
+        .map(oneormany)
+        .unwrap_or_default())
+}
+
+/// Returns every homepage of `attribute` in the package database at `db`, in the order nixpkgs lists them.
+/// The `homepage` column holds either a single URL or, for packages with several homepages, a JSON list of them.
+/// Returns an empty list if the package doesn't exist or has no homepage.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn package_homepages(db: impl AsRef<Path>, attribute: &str) -> Result<Vec<String>> {
+    Ok(match metatext(db, attribute, "homepage").await? {
+        Some(homepage) if homepage.trim_start().starts_with('[') => {
+            oneormany(serde_json::from_str(&homepage)?)
+        }
+        Some(homepage) if !homepage.is_empty() => vec![homepage],
+        _ => vec![],
+    })
+}
+
+/// Returns the first homepage of `attribute` in the package database at `db`, which is the only one for most packages.
+/// See [package_homepages()] for all of them.
+pub async fn primary_homepage(db: impl AsRef<Path>, attribute: &str) -> Result<Option<String>> {
+    Ok(package_homepages(db, attribute).await?.into_iter().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{testdir, testpkgsdb};
+
+    /// Builds a package database in a new test directory named `name`, with `column` of `meta` set for each attribute in `values`.
+    async fn metadb(name: &str, column: &str, values: &[(&str, &str)]) -> String {
+        let db = testdir(name).join("pkgs.db");
+        let pkgs = values
+            .iter()
+            .map(|(attribute, _)| (*attribute, *attribute, "1.0", "A package"))
+            .collect::<Vec<_>>();
+        let pool = testpkgsdb(&db, &pkgs).await;
+        for (attribute, value) in values {
+            sqlx::query(&format!("UPDATE meta SET {} = $1 WHERE attribute = $2", column))
+                .bind(value)
+                .bind(attribute)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        pool.close().await;
+        db.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn license_shapes() {
+        let db = metadb(
+            "licenses",
+            "license",
+            &[
+                ("string", r#""unfree""#),
+                ("object", r#"{"spdxId":"MIT","fullName":"MIT License","shortName":"mit","free":true}"#),
+                ("list", r#"[{"spdxId":"GPL-2.0-only","free":true},"bsd3"]"#),
+            ],
+        )
+        .await;
+        let licenses = package_licenses(&db, "string").await.unwrap();
+        assert_eq!(licenses, vec![License::Name(String::from("unfree"))]);
+        assert_eq!(licenses[0].is_free(), Some(false));
+
+        let licenses = package_licenses(&db, "object").await.unwrap();
+        assert_eq!(licenses.len(), 1);
+        assert_eq!(licenses[0].spdx_id(), Some("MIT"));
+        assert_eq!(licenses[0].is_free(), Some(true));
+
+        let licenses = package_licenses(&db, "list").await.unwrap();
+        assert_eq!(licenses.len(), 2);
+        assert_eq!(licenses[0].spdx_id(), Some("GPL-2.0-only"));
+        assert_eq!(licenses[1], License::Name(String::from("bsd3")));
+
+        assert!(package_licenses(&db, "missing").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn two_maintainers() {
+        let db = metadb(
+            "maintainers",
+            "maintainers",
+            &[(
+                "hello",
+                r#"[{"name":"Alice","email":"alice@example.org","github":"alice","githubId":1},{"name":"Bob","github":"bob","githubId":2}]"#,
+            )],
+        )
+        .await;
+        let maintainers = package_maintainers(&db, "hello").await.unwrap();
+        assert_eq!(
+            maintainers,
+            vec![
+                Maintainer {
+                    name: Some(String::from("Alice")),
+                    email: Some(String::from("alice@example.org")),
+                    github: Some(String::from("alice")),
+                    github_id: Some(1),
+                },
+                Maintainer {
+                    name: Some(String::from("Bob")),
+                    email: None,
+                    github: Some(String::from("bob")),
+                    github_id: Some(2),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn two_homepages() {
+        let db = metadb(
+            "homepages",
+            "homepage",
+            &[
+                ("single", "https://example.org"),
+                ("two", r#"["https://example.org","https://example.com/docs"]"#),
+            ],
+        )
+        .await;
+        assert_eq!(
+            package_homepages(&db, "two").await.unwrap(),
+            vec!["https://example.org", "https://example.com/docs"]
+        );
+        assert_eq!(primary_homepage(&db, "two").await.unwrap().as_deref(), Some("https://example.org"));
+        assert_eq!(package_homepages(&db, "single").await.unwrap(), vec!["https://example.org"]);
+        assert_eq!(primary_homepage(&db, "missing").await.unwrap(), None);
+    }
+}
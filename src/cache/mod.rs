@@ -1,26 +1,1340 @@
-use std::collections::HashMap;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use crate::CACHEDIR;
+use crate::error::{CommandExt, NixDataError, Result};
 use ijson::IString;
-use serde::{Deserialize, Serialize};
+use log::{debug, warn};
+use serde::{
+    de::{DeserializeSeed, IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
+    SqlitePool,
+};
+use tokio_util::sync::CancellationToken;
 
+/// Resolve renamed and removed nixpkgs attributes
+pub mod aliases;
 /// Cache and determine packages installed on legacy NixOS and with `nix-env`
 pub mod channel;
 /// Cache and determine packages installed on flakes enabled NixOS
 pub mod flakes;
+/// Structured package metadata, such as licenses, maintainers and platforms
+pub mod meta;
 /// Cache latest NixOS `packages.json` and `options.json`
 pub mod nixos;
+/// Parse and query NixOS options
+pub mod options;
 /// Cache and determine packages installed with `nix profile`
 pub mod profile;
 /// Nixpkgs cache on non-NixOS
 pub mod nonnixos;
-
-#[derive(Debug, Deserialize)]
-struct NixPkgList {
-    packages: HashMap<String, NixPkg>,
-}
+/// Query package databases such as `nixospkgs.db`
+pub mod query;
+/// Rebuild the NixOS package database with progress reporting and cancellation
+pub mod rebuild;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct NixPkg {
     pname: IString,
     version: IString,
+    #[serde(default, deserialize_with = "systemfield")]
+    system: Option<IString>,
+}
+
+/// Parses the `system` of a `packages.json` entry, which should be a single platform such as `x86_64-linux`.
+/// Entries without one get `None`, and anything else is logged and treated as missing, rather than stored mangled.
+fn systemfield<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<IString>, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(system) => Some(system.into()),
+        serde_json::Value::Null => None,
+        x => {
+            warn!("Ignoring unexpected system {} in packages.json", x);
+            None
+        }
+    })
+}
+
+/// Parses the `packages` object of a channel `packages.json` from `reader`, calling `f` with each attribute and package
+/// as soon as it is parsed. Unlike deserializing the whole file, memory use doesn't grow with the size of the channel.
+fn streampackages<R: Read>(reader: R, f: impl FnMut(String, NixPkg)) -> Result<()> {
+    struct Root<F>(F);
+    struct Packages<'a, F>(&'a mut F);
+
+    impl<'de, F: FnMut(String, NixPkg)> DeserializeSeed<'de> for Root<F> {
+        type Value = ();
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+            deserializer.deserialize_map(self)
+        }
+    }
+
+    impl<'de, F: FnMut(String, NixPkg)> Visitor<'de> for Root<F> {
+        type Value = ();
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a packages.json object")
+        }
+        fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+            while let Some(key) = map.next_key::<String>()? {
+                if key == "packages" {
+                    map.next_value_seed(Packages(&mut self.0))?;
+                } else {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<'de, 'a, F: FnMut(String, NixPkg)> DeserializeSeed<'de> for Packages<'a, F> {
+        type Value = ();
+        fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+            deserializer.deserialize_map(self)
+        }
+    }
+
+    impl<'de, 'a, F: FnMut(String, NixPkg)> Visitor<'de> for Packages<'a, F> {
+        type Value = ();
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map of attributes to packages")
+        }
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+            while let Some((attribute, pkg)) = map.next_entry::<String, NixPkg>()? {
+                (self.0)(attribute, pkg);
+            }
+            Ok(())
+        }
+    }
+
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    Root(f).deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(())
+}
+
+/// Opens the existing database at `db`. The path is passed to SQLite as is rather than in a `sqlite://` URL,
+/// so that paths containing characters such as `?` or `#` work.
+pub(super) async fn connectdb(db: impl AsRef<Path>) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::new().filename(db);
+    Ok(SqlitePool::connect_with(options).await?)
+}
+
+/// Checks whether a table named `table` exists in the database.
+pub(super) async fn tableexists(pool: &SqlitePool, table: &str) -> Result<bool> {
+    let (count,): (i64,) =
+        sqlx::query_as(r#"SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = $1"#)
+            .bind(table)
+            .fetch_one(pool)
+            .await?;
+    Ok(count > 0)
+}
+
+/// Checks whether `table` has a column named `column`.
+pub(super) async fn columnexists(pool: &SqlitePool, table: &str, column: &str) -> Result<bool> {
+    let (count,): (i64,) =
+        sqlx::query_as(r#"SELECT COUNT(*) FROM pragma_table_info($1) WHERE name = $2"#)
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool)
+            .await?;
+    Ok(count > 0)
+}
+
+/// Errors if the database doesn't contain the `meta` table, rather than letting
+/// a query fail with sqlite's "no such table" error.
+pub(super) async fn requiremeta(pool: &SqlitePool) -> Result<()> {
+    if tableexists(pool, "meta").await? {
+        Ok(())
+    } else {
+        Err(NixDataError::Other(String::from(
+            "Package database has no meta table; it only contains attributes and versions",
+        )))
+    }
+}
+
+/// Returns the Nix system double of the host, e.g. `x86_64-linux` or `aarch64-darwin`.
+pub(super) fn hostsystem() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    format!("{}-{}", std::env::consts::ARCH, os)
+}
+
+/// Sets `key` to `value` in the `meta_info` table, creating the table if needed.
+/// `meta_info` holds details about the database as a whole, such as the system it was built for.
+pub(super) async fn setmetainfo(pool: &SqlitePool, key: &str, value: &str) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "meta_info" (
+            "key"	TEXT NOT NULL UNIQUE,
+            "value"	TEXT,
+            PRIMARY KEY("key")
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query(r#"INSERT OR REPLACE INTO "meta_info" VALUES ($1, $2)"#)
+        .bind(key)
+        .bind(value)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Records the channel `version` a database was built from, and the time it was built, in its `meta_info` table.
+/// Read back with [db_version()](query::db_version) and [last_synced()].
+pub(super) async fn setbuildinfo(pool: &SqlitePool, version: &str) -> Result<()> {
+    setmetainfo(pool, "version", version).await?;
+    setbuilttime(pool).await
+}
+
+/// Records the current time, in seconds since the Unix epoch, as the time the database was built.
+pub(super) async fn setbuilttime(pool: &SqlitePool) -> Result<()> {
+    let built = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+    setmetainfo(pool, "built", &built.to_string()).await
+}
+
+/// A database cached by this crate, for [last_synced()].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheKind {
+    /// The NixOS package database built by [nixospkgs()](nixos::nixospkgs).
+    NixosPackages,
+    /// The package database built by [flakespkgs()](flakes::flakespkgs).
+    FlakePackages,
+    /// The package database built by [legacypkgs()](channel::legacypkgs).
+    LegacyPackages,
+    /// The NixOS options database built by [optionsdb()](options::optionsdb).
+    NixosOptions,
+}
+
+impl CacheKind {
+    fn filename(&self) -> &'static str {
+        match self {
+            CacheKind::NixosPackages => "nixospkgs.db",
+            CacheKind::FlakePackages => "flakespkgs.db",
+            CacheKind::LegacyPackages => "legacypkgs.db",
+            CacheKind::NixosOptions => "nixosoptions.db",
+        }
+    }
+}
+
+/// Returns when the cached database of `kind` was last built, for example to show how old the package data is.
+/// Returns `None` if it isn't cached, or was built by an older version of this crate that didn't record the time.
+pub async fn last_synced(kind: CacheKind) -> Result<Option<SystemTime>> {
+    last_synced_with_config(&CacheConfig::default(), kind).await
+}
+
+/// Like [last_synced()], for the databases cached in the directory given by `config`.
+pub async fn last_synced_with_config(config: &CacheConfig, kind: CacheKind) -> Result<Option<SystemTime>> {
+    let dbfile = config.file(kind.filename());
+    if !Path::new(&dbfile).exists() {
+        return Ok(None);
+    }
+    let pool = connectdb(&dbfile).await?;
+    let built = getmetainfo(&pool, "built").await?;
+    pool.close().await;
+    Ok(built
+        .and_then(|x| x.parse::<u64>().ok())
+        .map(|x| UNIX_EPOCH + Duration::from_secs(x)))
+}
+
+/// How usable a cached artifact is, as reported by [cache_status()].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArtifactState {
+    /// Nothing is cached.
+    Absent,
+    /// Cached, but for a different NixOS release than the running system, or its release couldn't be told.
+    Stale,
+    /// Cached for the NixOS release of the running system. A newer build of the release may still be available.
+    Current,
+}
+
+/// Status of a single cached artifact, as reported by [cache_status()].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactStatus {
+    pub state: ArtifactState,
+    /// Version stored alongside the artifact, e.g. `23.05.1234.abcdef`. `None` if it is absent or has no version file.
+    pub version: Option<String>,
+}
+
+impl ArtifactStatus {
+    /// Whether the artifact is cached at all, even if it is [stale](ArtifactState::Stale).
+    pub fn exists(&self) -> bool {
+        self.state != ArtifactState::Absent
+    }
+
+    /// Whether the artifact is cached for the NixOS release of the running system.
+    pub fn is_current(&self) -> bool {
+        self.state == ArtifactState::Current
+    }
+}
+
+/// Status of the NixOS caches, as returned by [cache_status()].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheStatus {
+    /// The package database built by [nixospkgs()](nixos::nixospkgs).
+    pub packages: ArtifactStatus,
+    /// The `options.json` downloaded by [nixosoptions()](nixos::nixosoptions).
+    pub options: ArtifactStatus,
+}
+
+/// Reports which NixOS caches already exist and whether they look current, without any network access,
+/// so that cached data can be shown straight away while it is refreshed in the background.
+/// Never fails: anything that can't be read is reported as [absent](ArtifactState::Absent) or [stale](ArtifactState::Stale).
+pub fn cache_status() -> CacheStatus {
+    cache_status_with_config(&CacheConfig::default())
+}
+
+/// Like [cache_status()], but looks in the directory given by `config`, for its [system](CacheConfig::system)
+/// and [NixOS version](CacheConfig::nixos_version).
+pub fn cache_status_with_config(config: &CacheConfig) -> CacheStatus {
+    let release = localrelease(config);
+    CacheStatus {
+        packages: artifactstatus(
+            config,
+            &config.pkgsname("db"),
+            &config.pkgsname("ver"),
+            release.as_deref(),
+        ),
+        options: artifactstatus(
+            config,
+            "nixosoptions.json",
+            "nixosoptions.ver",
+            release.as_deref(),
+        ),
+    }
+}
+
+/// The `YY.MM` release (or `unstable`) that caches are expected to be for, or `None` if it can't be told.
+fn localrelease(config: &CacheConfig) -> Option<String> {
+    match config.nixos_version.as_deref() {
+        Some("unstable") => Some(String::from("unstable")),
+        Some(version) => nixos::parsenixosversion(version).ok(),
+        None => {
+            let versionout = std::process::Command::new("nixos-version").tooloutput().ok()?;
+            nixos::parsenixosversion(&String::from_utf8(versionout.stdout).ok()?).ok()
+        }
+    }
+}
+
+fn artifactstatus(config: &CacheConfig, file: &str, verfile: &str, release: Option<&str>) -> ArtifactStatus {
+    if !Path::new(&config.file(file)).exists() {
+        return ArtifactStatus {
+            state: ArtifactState::Absent,
+            version: None,
+        };
+    }
+    let version = std::fs::read_to_string(config.file(verfile))
+        .ok()
+        .map(|x| nixos::stripchannelprefix(x.trim()))
+        .filter(|x| !x.is_empty());
+    let current = match (release, version.as_deref()) {
+        // Unstable versions look like `23.11pre530470.abcdef`
+        (Some("unstable"), Some(version)) => version.contains("pre"),
+        (Some(release), Some(version)) => {
+            nixos::parsenixosversion(version).ok().as_deref() == Some(release)
+        }
+        _ => false,
+    };
+    ArtifactStatus {
+        state: if current {
+            ArtifactState::Current
+        } else {
+            ArtifactState::Stale
+        },
+        version,
+    }
+}
+
+/// Reads `key` from the `meta_info` table. Returns `None` if the key or the table doesn't exist.
+pub(super) async fn getmetainfo(pool: &SqlitePool, key: &str) -> Result<Option<String>> {
+    if !tableexists(pool, "meta_info").await? {
+        return Ok(None);
+    }
+    let row: Option<(Option<String>,)> =
+        sqlx::query_as(r#"SELECT value FROM meta_info WHERE key = $1"#)
+            .bind(key)
+            .fetch_optional(pool)
+            .await?;
+    Ok(row.and_then(|(value,)| value))
+}
+
+/// Where package and option caches are stored.
+/// The [Default] is `~/.cache/nix-data`, which is used by all functions that don't take a [CacheConfig].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Directory holding the cached databases and their version files. It is created if it doesn't exist.
+    pub dir: PathBuf,
+    /// Never access the network, and use whatever is already cached instead, even if it is out of date.
+    /// Functions fail with [NixDataError::NotCached] if there is nothing cached to use.
+    pub offline: bool,
+    /// System to download the NixOS package database for, e.g. `aarch64-linux`. `None` for the host system.
+    /// Databases for systems other than the host are cached separately, as `nixospkgs-<system>.db`.
+    pub system: Option<String>,
+    /// Where NixOS channel data, such as `options.json`, is downloaded from.
+    pub channel_source: ChannelSource,
+    /// NixOS version to download data for, e.g. `23.05` or `unstable`, instead of the version of the running system.
+    /// This lets the NixOS download functions run on systems other than NixOS, which otherwise fail with [NixDataError::NotNixos].
+    pub nixos_version: Option<String>,
+    /// Download and rebuild databases even if the cached ones are up to date, e.g. to replace a corrupted database.
+    /// Has no effect in [offline](CacheConfig::offline) mode.
+    pub force: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            dir: PathBuf::from(&*CACHEDIR),
+            offline: false,
+            system: None,
+            channel_source: ChannelSource::default(),
+            nixos_version: None,
+            force: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Path of the file `name` in the cache directory.
+    pub(super) fn file(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+
+    /// In [offline](CacheConfig::offline) mode, returns the path of the cached file `name`,
+    /// or a [NixDataError::NotCached] error if there is none. Returns `None` otherwise.
+    pub(super) fn offlinefile(&self, name: &str) -> Option<Result<PathBuf>> {
+        if !self.offline {
+            return None;
+        }
+        let path = self.file(name);
+        if Path::new(&path).exists() {
+            debug!("Offline, using cached {}", path);
+            Some(Ok(path.into()))
+        } else {
+            Some(Err(NixDataError::NotCached(path)))
+        }
+    }
+
+    /// The [system](CacheConfig::system) to download package data for, if it isn't the host system.
+    pub(super) fn othersystem(&self) -> Option<&str> {
+        self.system
+            .as_deref()
+            .filter(|system| *system != hostsystem())
+    }
+
+    /// Name of the NixOS package database file with extension `ext` (e.g. `db` or `ver`) for the configured system,
+    /// namespaced by system if it isn't the host system.
+    pub(super) fn pkgsname(&self, ext: &str) -> String {
+        match self.othersystem() {
+            Some(system) => format!("nixospkgs-{}.{}", system, ext),
+            None => format!("nixospkgs.{}", ext),
+        }
+    }
+
+    /// Creates the cache directory if it doesn't exist.
+    pub(super) fn createdir(&self) -> Result<()> {
+        if !self.dir.exists() {
+            std::fs::create_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Server and channel that NixOS channel data is downloaded from.
+/// The [Default] is `https://channels.nixos.org`, with the channel matching the running system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelSource {
+    /// Base URL of the server, e.g. `https://channels.nixos.org` or the URL of an internal mirror.
+    pub base_url: String,
+    /// Name of the channel on the server, e.g. `nixos-unstable-small`.
+    /// `None` to use the channel matching the running system, e.g. `nixos-23.05`.
+    pub channel: Option<String>,
+}
+
+impl Default for ChannelSource {
+    fn default() -> Self {
+        ChannelSource {
+            base_url: String::from("https://channels.nixos.org"),
+            channel: None,
+        }
+    }
+}
+
+impl ChannelSource {
+    /// URL of the channel, using `systemchannel` (e.g. `23.05` or `unstable`) if no [channel](ChannelSource::channel) is set.
+    pub(super) fn url(&self, systemchannel: &str) -> String {
+        let channel = self
+            .channel
+            .clone()
+            .unwrap_or_else(|| format!("nixos-{}", systemchannel));
+        format!("{}/{}", self.base_url.trim_end_matches('/'), channel)
+    }
+}
+
+/// Checks that `url` can be downloaded with a `HEAD` request, so that a misconfigured source fails early
+/// with a clear error rather than partway into a download.
+pub(super) async fn checkreachable(client: &reqwest::Client, url: &str) -> Result<()> {
+    let resp = sendretrying(|| client.head(url)).await?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(NixDataError::Download(format!(
+            "{} is not available: {}",
+            url,
+            resp.status()
+        )))
+    }
+}
+
+/// How downloads of the NixOS package and option data are retried after transient failures,
+/// such as connection errors, timeouts, `5xx` and `429 Too Many Requests` responses.
+/// Requests failing with any other `4xx` response or an error in the request itself are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// Maximum number of attempts, including the first. `1` disables retrying.
+    pub max_attempts: u32,
+    /// Delay before the first retry. It is doubled for every following retry.
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref RETRYCONFIG: RwLock<RetryConfig> = RwLock::new(RetryConfig::default());
+}
+
+/// Sets the [RetryConfig] used for all following downloads.
+pub fn set_retry_config(config: RetryConfig) {
+    *RETRYCONFIG.write().unwrap_or_else(|e| e.into_inner()) = config;
+}
+
+/// Returns the [RetryConfig] currently in use.
+pub fn retry_config() -> RetryConfig {
+    *RETRYCONFIG.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// SQLite settings for the databases built by this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbConfig {
+    /// Whether newly built databases use a write-ahead log (`PRAGMA journal_mode = WAL`) with `PRAGMA synchronous = NORMAL`,
+    /// rather than SQLite's default rollback journal. This lets queries run while another process writes to the database,
+    /// such as a background refresh building its full-text index. Defaults to `true`.
+    ///
+    /// A write-ahead log needs the `-wal` and `-shm` files next to the database to be writable,
+    /// so databases in read-only locations should turn this off.
+    pub wal: bool,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig { wal: true }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DBCONFIG: RwLock<DbConfig> = RwLock::new(DbConfig::default());
+}
+
+/// Sets the [DbConfig] used for all following database builds. Databases that were already built are not changed.
+pub fn set_db_config(config: DbConfig) {
+    *DBCONFIG.write().unwrap_or_else(|e| e.into_inner()) = config;
+}
+
+/// Returns the [DbConfig] currently in use.
+pub fn db_config() -> DbConfig {
+    *DBCONFIG.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// HTTP settings used for all downloads, for example to go through a proxy or to send extra headers.
+/// Unless [proxy](HttpConfig::proxy) is set, proxies are taken from the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY`
+/// environment variables.
+///
+/// Requests that exceed one of the timeouts fail with [NixDataError::Timeout].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    /// URL of a proxy to send all requests through, e.g. `http://proxy.example.com:8080`.
+    pub proxy: Option<String>,
+    /// Timeout for connecting to the server. Defaults to 30 seconds.
+    pub connect_timeout: Option<Duration>,
+    /// Timeout for each read while downloading, so that a stalled download fails rather than hanging.
+    /// Defaults to 30 seconds.
+    pub read_timeout: Option<Duration>,
+    /// Timeout for each request, from connecting until the response has been read. Defaults to `None`,
+    /// as the package data downloads are large and may take a long time on slow connections.
+    pub timeout: Option<Duration>,
+    /// Headers added to every request, as name and value.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            proxy: None,
+            connect_timeout: Some(Duration::from_secs(30)),
+            read_timeout: Some(Duration::from_secs(30)),
+            timeout: None,
+            headers: vec![],
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref HTTPCONFIG: RwLock<HttpConfig> = RwLock::new(HttpConfig::default());
+}
+
+/// Sets the [HttpConfig] used for all following downloads.
+pub fn set_http_config(config: HttpConfig) {
+    *HTTPCONFIG.write().unwrap_or_else(|e| e.into_inner()) = config;
+}
+
+/// Returns the [HttpConfig] currently in use.
+pub fn http_config() -> HttpConfig {
+    HTTPCONFIG.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Builds an HTTP client as set by [set_http_config()], decompressing brotli responses if `brotli` is set.
+pub(super) fn httpclient(brotli: bool) -> Result<reqwest::Client> {
+    buildclient(&http_config(), brotli)
+}
+
+/// Builds an HTTP client with the settings in `config`, decompressing brotli responses if `brotli` is set.
+fn buildclient(config: &HttpConfig, brotli: bool) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().brotli(brotli);
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    if let Some(timeout) = config.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = config.timeout {
+        builder = builder.timeout(timeout);
+    }
+    let mut headers = reqwest::header::HeaderMap::new();
+    for (name, value) in &config.headers {
+        let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| NixDataError::Other(format!("Invalid header name {}: {}", name, e)))?;
+        let value = reqwest::header::HeaderValue::from_str(value)
+            .map_err(|e| NixDataError::Other(format!("Invalid value for header {}: {}", name, e)))?;
+        headers.insert(name, value);
+    }
+    Ok(builder.default_headers(headers).build()?)
+}
+
+/// Awaits `read`, a read from a response such as [chunk()](reqwest::Response::chunk),
+/// failing with [NixDataError::Timeout] if it takes longer than the [read timeout](HttpConfig::read_timeout).
+pub(super) async fn readtimeout<T>(
+    read: impl std::future::Future<Output = reqwest::Result<T>>,
+) -> Result<T> {
+    match http_config().read_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, read).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(NixDataError::Timeout(format!(
+                "No data received for {:?}",
+                timeout
+            ))),
+        },
+        None => Ok(read.await?),
+    }
+}
+
+/// Whether a request failing with `err` is worth retrying.
+fn retryableerror(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout()
+}
+
+/// Whether a request answered with `status` is worth retrying.
+fn retryablestatus(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Sends the request built by `req`, retrying on transient failures as set by [set_retry_config()].
+/// If every attempt gets a retryable response, the last one is returned for the caller to handle.
+pub(super) async fn sendretrying(
+    req: impl Fn() -> reqwest::RequestBuilder,
+) -> reqwest::Result<reqwest::Response> {
+    let config = retry_config();
+    let mut backoff = config.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        let result = req().send().await;
+        let retry = match &result {
+            Ok(resp) => retryablestatus(resp.status()),
+            Err(e) => retryableerror(e),
+        };
+        if !retry || attempt >= config.max_attempts {
+            return result;
+        }
+        debug!("Request failed, retrying in {:?}", backoff);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Error returned when a download doesn't match the SHA-256 hash published alongside it,
+/// usually because it was truncated or corrupted in transit. Retrying the download may succeed.
+///
+/// Functions in this crate return it as [NixDataError::ChecksumMismatch].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    /// URL of the download.
+    pub url: String,
+    /// Published SHA-256 hash, in lowercase hex.
+    pub expected: String,
+    /// SHA-256 hash of the downloaded data, in lowercase hex.
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SHA-256 mismatch for {}: expected {}, got {}",
+            self.url, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Fetches the SHA-256 hash published at `{url}.sha256`, as done for files in NixOS channels.
+/// Returns `None` if no hash is published for `url` (404), in which case the download can't be verified.
+/// Any other failure to fetch the hash is an error, so a flaky server can't turn verification off.
+pub(super) async fn publishedsha256(client: &reqwest::Client, url: &str) -> Result<Option<String>> {
+    let resp = client.get(format!("{}.sha256", url)).send().await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(resp
+        .error_for_status()?
+        .text()
+        .await?
+        .split_whitespace()
+        .next()
+        .map(|x| x.to_lowercase()))
+}
+
+/// Fetches the nixpkgs git revision published as `git-revision` in the NixOS channel or release at `url`.
+/// Returns `None` if none is published or it doesn't look like a commit hash.
+pub(super) async fn publishedrevision(client: &reqwest::Client, url: &str) -> Option<String> {
+    let resp = client.get(format!("{}/git-revision", url)).send().await.ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    let rev = resp.text().await.ok()?.trim().to_lowercase();
+    (rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit())).then_some(rev)
+}
+
+/// Like [setmetainfo()], but on the package database at `db`, e.g. to record the nixpkgs `revision`
+/// read back with [db_revision()](query::db_revision) once the database is built.
+pub(super) async fn setdbmetainfo(db: impl AsRef<Path>, key: &str, value: &str) -> Result<()> {
+    let pool = connectdb(db).await?;
+    setmetainfo(&pool, key, value).await?;
+    pool.close().await;
+    Ok(())
+}
+
+/// Compares the SHA-256 `hasher` has computed over the download from `url` against `expected`,
+/// returning a [ChecksumMismatch] error if they differ.
+pub(super) fn verifysha256(hasher: Sha256, expected: &str, url: &str) -> Result<()> {
+    let actual = format!("{:x}", hasher.finalize());
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(ChecksumMismatch {
+            url: url.to_string(),
+            expected: expected.to_string(),
+            actual,
+        }
+        .into())
+    }
+}
+
+/// Opens the database being built at `tmpfile`, creating it if needed.
+/// It uses a rollback journal rather than a write-ahead log, so that everything written is in `tmpfile` itself
+/// once the pool is closed, and the file can be moved into place with [replacedb()].
+/// The database should be closed with [closetmp()].
+pub(super) async fn connecttmp(tmpfile: impl AsRef<Path>) -> Result<SqlitePool> {
+    let mut options = SqliteConnectOptions::new()
+        .filename(tmpfile)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Delete);
+    if db_config().wal {
+        options = options.synchronous(SqliteSynchronous::Normal);
+    }
+    Ok(SqlitePool::connect_with(options).await?)
+}
+
+/// Closes the database built with [connecttmp()], switching it to the journal mode set by [set_db_config()].
+/// The journal mode is stored in the database file, so it carries over once the file is moved into place.
+pub(super) async fn closetmp(pool: SqlitePool) -> Result<()> {
+    if db_config().wal {
+        sqlx::query("PRAGMA journal_mode = WAL").execute(&pool).await?;
+    }
+    // Nothing is written after the switch, so closing the last connection leaves no `-wal` or `-shm` file behind
+    pool.close().await;
+    Ok(())
+}
+
+/// Exclusive lock on a cached file, released when dropped. Taken with [lockfile()].
+pub(super) struct FileLock {
+    _file: File,
+}
+
+/// Waits until no other process or task is updating the cached file at `path`, then locks it until the returned [FileLock] is dropped.
+/// The lock is an advisory lock on `<path>.lock`, so it is released even if the process holding it dies.
+/// Callers should check whether the file is up to date after taking the lock, as it may just have been updated by another caller.
+pub(super) async fn lockfile(path: impl AsRef<Path>) -> Result<FileLock> {
+    let mut lockpath = path.as_ref().as_os_str().to_owned();
+    lockpath.push(".lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lockpath)?;
+    tokio::task::spawn_blocking(move || {
+        // Locks belong to the open file, so this also waits for other tasks in this process
+        file.lock()?;
+        Ok(FileLock { _file: file })
+    })
+    .await?
+}
+
+/// Moves the newly built database `tmpfile` to `dbfile`, replacing it atomically so readers never see a partial database.
+/// The previous database is kept as `<dbfile>.bak`.
+pub(super) fn replacedb(tmpfile: impl AsRef<Path>, dbfile: impl AsRef<Path>) -> Result<()> {
+    let dbfile = dbfile.as_ref();
+    if dbfile.exists() {
+        let bakfile = withsuffix(dbfile, ".bak");
+        if bakfile.exists() {
+            std::fs::remove_file(&bakfile)?;
+        }
+        // A hard link keeps the backup without copying the database
+        if let Err(e) = std::fs::hard_link(dbfile, &bakfile) {
+            debug!("Could not back up {}: {}", dbfile.display(), e);
+        }
+    }
+    std::fs::rename(tmpfile, dbfile)?;
+    Ok(())
+}
+
+/// Appends `suffix` to the file name of `path`, e.g. `nixospkgs.db` to `nixospkgs.db.tmp`.
+pub(super) fn withsuffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Whether the body of `resp`, downloaded from `url` by a client that doesn't decompress responses itself,
+/// is brotli compressed. This is the case for `.br` files, whether or not the server sends `Content-Encoding: br`,
+/// and for any response the server sends with that encoding.
+pub(super) fn isbrotli(resp: &reqwest::Response, url: &str) -> bool {
+    url.ends_with(".br")
+        || resp
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .is_some_and(|x| x.as_bytes().eq_ignore_ascii_case(b"br"))
+}
+
+/// Compression of a file downloaded by a client that doesn't decompress responses itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Compression {
+    Brotli,
+    Zstd,
+    None,
+}
+
+impl Compression {
+    /// Detects the compression of the body of `resp`, downloaded from `url`, from the file extension or `Content-Encoding` header.
+    pub(super) fn of(resp: &reqwest::Response, url: &str) -> Self {
+        if url.ends_with(".zst")
+            || resp
+                .headers()
+                .get(reqwest::header::CONTENT_ENCODING)
+                .is_some_and(|x| x.as_bytes().eq_ignore_ascii_case(b"zstd"))
+        {
+            Compression::Zstd
+        } else if isbrotli(resp, url) {
+            Compression::Brotli
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Wraps `reader` to decompress what is read from it.
+    pub(super) fn decoder<'a>(self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::Brotli => Box::new(brotli::Decompressor::new(reader, 4096)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+            Compression::None => Box::new(reader),
+        })
+    }
+}
+
+/// Decompresses brotli compressed `bytes` into a new file at `path`, returning the number of bytes written.
+/// This is CPU bound, so async callers should run it with [tokio::task::spawn_blocking].
+pub(super) fn writebrotli(bytes: &[u8], path: &str) -> Result<u64> {
+    let mut written = 0;
+    let mut out = File::create(path)?;
+    let mut reader = brotli::Decompressor::new(
+        bytes,
+        4096, // buffer size
+    );
+    let mut buf = [0u8; 4096];
+    loop {
+        match reader.read(&mut buf[..]) {
+            Err(e) => {
+                if let std::io::ErrorKind::Interrupted = e.kind() {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            Ok(size) => {
+                if size == 0 {
+                    break;
+                }
+                out.write_all(&buf[..size])?;
+                written += size as u64;
+            }
+        }
+    }
+    Ok(written)
+}
+
+/// Fails with [NixDataError::Cancelled] if `cancel` has been cancelled.
+pub(super) fn checkcancelled(cancel: &CancellationToken) -> Result<()> {
+    if cancel.is_cancelled() {
+        Err(NixDataError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Creates an empty directory named after `name` for a test to write to.
+#[cfg(test)]
+pub(crate) fn testdir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("nix-data-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A [CacheConfig] caching into `dir`.
+#[cfg(test)]
+pub(crate) fn testconfig(dir: &std::path::Path) -> CacheConfig {
+    CacheConfig {
+        dir: dir.to_path_buf(),
+        ..Default::default()
+    }
+}
+
+/// Creates a package database at `db` holding `pkgs`, given as attribute, pname, version and description,
+/// with a `meta` table laid out like the one in the prebuilt databases.
+#[cfg(test)]
+pub(crate) async fn testpkgsdb(db: &std::path::Path, pkgs: &[(&str, &str, &str, &str)]) -> SqlitePool {
+    let pool = SqlitePool::connect(&format!("sqlite://{}?mode=rwc", db.display()))
+        .await
+        .unwrap();
+    sqlx::query(r#"CREATE TABLE "pkgs" ("attribute" TEXT NOT NULL UNIQUE, "pname" TEXT, "version" TEXT, PRIMARY KEY("attribute"))"#)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query(
+        r#"
+        CREATE TABLE "meta" (
+            "attribute" TEXT NOT NULL UNIQUE, "description" TEXT, "longdescription" TEXT,
+            "broken" INTEGER, "insecure" INTEGER, "unfree" INTEGER, "unsupported" INTEGER,
+            "homepage" TEXT, "license" TEXT, "maintainers" TEXT, "platforms" TEXT,
+            PRIMARY KEY("attribute")
+        )
+        "#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    for (attribute, pname, version, description) in pkgs {
+        sqlx::query("INSERT INTO pkgs (attribute, pname, version) VALUES ($1, $2, $3)")
+            .bind(attribute)
+            .bind(pname)
+            .bind(version)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO meta (attribute, description) VALUES ($1, $2)")
+            .bind(attribute)
+            .bind(description)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    pool
+}
+
+/// Response of a [testserver()]: status, headers and body.
+#[cfg(test)]
+pub(crate) type TestResponse = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+/// Serves HTTP on a local port, answering each request with `handler(method, path)`, and returns the server's base URL.
+#[cfg(test)]
+pub(crate) fn testserver(handler: impl Fn(&str, &str) -> TestResponse + Send + 'static) -> String {
+    use std::io::{BufRead, BufReader};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            if reader.read_line(&mut request).is_err() {
+                continue;
+            }
+            // Headers are ignored, but have to be read before answering
+            let mut header = String::new();
+            while reader.read_line(&mut header).is_ok_and(|n| n > 0) && header != "\r\n" {
+                header.clear();
+            }
+            let mut parts = request.split_whitespace();
+            let method = parts.next().unwrap_or_default();
+            let path = parts.next().unwrap_or_default();
+            let (status, headers, body) = handler(method, path);
+            let mut head = format!(
+                "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+                status,
+                body.len()
+            );
+            for (name, value) in headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str("\r\n");
+            let _ = stream.write_all(head.as_bytes());
+            if method != "HEAD" {
+                let _ = stream.write_all(&body);
+            }
+        }
+    });
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, collections::HashMap, rc::Rc};
+
+    /// Generates a `packages.json` with `total` packages as it is read, without ever holding all of it in memory.
+    struct PackagesJson {
+        next: usize,
+        total: usize,
+        buf: Vec<u8>,
+        read: Rc<Cell<usize>>,
+    }
+
+    impl Read for PackagesJson {
+        fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+            if self.buf.is_empty() {
+                self.buf = match self.next {
+                    0 => br#"{"version":2,"packages":{"#.to_vec(),
+                    x if x > self.total + 1 => return Ok(0),
+                    x if x == self.total + 1 => b"}}".to_vec(),
+                    x => format!(
+                        r#"{}"pkg{}":{{"name":"pkg{}-1.0","pname":"pkg{}","version":"1.0","system":"x86_64-linux","meta":{{"description":"Package number {}"}}}}"#,
+                        if x == 1 { "" } else { "," },
+                        x,
+                        x,
+                        x,
+                        x
+                    )
+                    .into_bytes(),
+                };
+                self.next += 1;
+            }
+            let n = out.len().min(self.buf.len());
+            out[..n].copy_from_slice(&self.buf[..n]);
+            self.buf.drain(..n);
+            self.read.set(self.read.get() + n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn stream_large_packages_json() {
+        let total = 100_000;
+        let read = Rc::new(Cell::new(0));
+        let reader = PackagesJson {
+            next: 0,
+            total,
+            buf: Vec::new(),
+            read: read.clone(),
+        };
+        let mut count = 0;
+        let mut readatfirst = None;
+        streampackages(reader, |attribute, pkg| {
+            count += 1;
+            readatfirst.get_or_insert(read.get());
+            assert_eq!(attribute, pkg.pname.as_str());
+        })
+        .unwrap();
+        assert_eq!(count, total);
+        // Packages are handed over as they are parsed, long before the whole file has been read
+        assert!(readatfirst.unwrap() < read.get() / 100, "{:?} of {}", readatfirst, read.get());
+    }
+
+    #[tokio::test]
+    async fn missing_system_imports_null() {
+        let json = r#"{"version":2,"packages":{
+            "hello":{"pname":"hello","version":"2.12","system":"x86_64-linux"},
+            "nosystem":{"pname":"nosystem","version":"1.0"},
+            "listsystem":{"pname":"listsystem","version":"1.0","system":["x86_64-linux","aarch64-linux"]}
+        }}"#;
+        let mut pkgs = HashMap::new();
+        streampackages(json.as_bytes(), |attribute, pkg| {
+            pkgs.insert(attribute, pkg);
+        })
+        .unwrap();
+        let db = testdir("missing-system").join("pkgs.db");
+        nixos::createdbwithpnames(&db, &pkgs).await.unwrap();
+
+        let pool = connectdb(&db).await.unwrap();
+        let systems: Vec<(String, Option<String>)> =
+            sqlx::query_as("SELECT attribute, system FROM pkgs ORDER BY attribute")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            systems,
+            vec![
+                ("hello".into(), Some("x86_64-linux".into())),
+                ("listsystem".into(), None),
+                ("nosystem".into(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupted_download() {
+        let payload = b"{\"version\":2,\"packages\":{}}".to_vec();
+        let hash = format!("{:x}", Sha256::digest(&payload));
+        let mut corrupted = payload.clone();
+        corrupted[3] ^= 1;
+        let url = testserver(move |_, path| match path {
+            "/packages.json.br" => (200, vec![], payload.clone()),
+            "/corrupted.json.br" => (200, vec![], corrupted.clone()),
+            _ => (200, vec![], hash.as_bytes().to_vec()),
+        });
+        let client = reqwest::Client::new();
+        for (file, valid) in [("packages.json.br", true), ("corrupted.json.br", false)] {
+            let url = format!("{}/{}", url, file);
+            let bytes = client.get(&url).send().await.unwrap().bytes().await.unwrap();
+            let expected = publishedsha256(&client, &url).await.unwrap().unwrap();
+            match verifysha256(Sha256::new_with_prefix(&bytes), &expected, &url) {
+                Ok(()) => assert!(valid),
+                Err(NixDataError::ChecksumMismatch(e)) => {
+                    assert!(!valid);
+                    assert_eq!(e.expected, expected);
+                    assert_eq!(e.url, url);
+                }
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_proxy() {
+        let proxy = testserver(|_, path| match path {
+            // A proxy is sent the full URL of the request
+            "http://nix-data.invalid/nixpkgs.ver" => (200, vec![], b"23.05.1234.abcdef".to_vec()),
+            _ => (404, vec![], vec![]),
+        });
+        let config = HttpConfig {
+            proxy: Some(proxy),
+            ..Default::default()
+        };
+        let resp = buildclient(&config, true)
+            .unwrap()
+            .get("http://nix-data.invalid/nixpkgs.ver")
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(resp.text().await.unwrap(), "23.05.1234.abcdef");
+    }
+
+    #[tokio::test]
+    async fn slow_server_times_out() {
+        let url = testserver(|_, _| {
+            std::thread::sleep(Duration::from_secs(2));
+            (200, vec![], b"late".to_vec())
+        });
+        let config = HttpConfig {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let err = buildclient(&config, true)
+            .unwrap()
+            .get(&url)
+            .send()
+            .await
+            .map_err(NixDataError::from)
+            .unwrap_err();
+        assert!(matches!(err, NixDataError::Timeout(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn published_sha256_missing() {
+        let url = testserver(|_, path| match path {
+            "/missing.br.sha256" => (404, vec![], vec![]),
+            _ => (500, vec![], vec![]),
+        });
+        let client = reqwest::Client::new();
+        // Only a hash that isn't published skips verification, a failing server doesn't
+        assert_eq!(publishedsha256(&client, &format!("{}/missing.br", url)).await.unwrap(), None);
+        assert!(publishedsha256(&client, &format!("{}/failing.br", url)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let url = testserver(move |_, path| {
+            let attempt = counter.fetch_add(1, Ordering::SeqCst);
+            match path {
+                "/missing" => (404, vec![], vec![]),
+                "/limited" if attempt == 0 => (429, vec![], vec![]),
+                "/file" if attempt < 2 => (503, vec![], vec![]),
+                _ => (200, vec![], b"ok".to_vec()),
+            }
+        });
+        let client = reqwest::Client::new();
+        let resp = sendretrying(|| client.get(format!("{}/file", url))).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.text().await.unwrap(), "ok");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Rate limiting is retried
+        attempts.store(0, Ordering::SeqCst);
+        let resp = sendretrying(|| client.get(format!("{}/limited", url))).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // Other client errors are never retried
+        attempts.store(0, Ordering::SeqCst);
+        let resp = sendretrying(|| client.get(format!("{}/missing", url))).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn offline_makes_no_request() {
+        let dir = testdir("offline");
+        let config = CacheConfig {
+            offline: true,
+            ..testconfig(&dir)
+        };
+        // Nothing cached: fails before resolving or downloading anything
+        let pkgs = config.file("nixospkgs.db");
+        assert!(matches!(
+            nixos::nixospkgs_with_config(&config).await,
+            Err(NixDataError::NotCached(path)) if path == pkgs
+        ));
+        assert!(matches!(
+            options::optionsdb_with_config(&config).await,
+            Err(NixDataError::NotCached(_))
+        ));
+        assert!(matches!(
+            flakes::flakespkgs_for_with_config(&config, &format!("github:NixOS/nixpkgs/{}", "0".repeat(40)))
+                .await,
+            Err(NixDataError::NotCached(_))
+        ));
+
+        // Cached databases are used as they are, even without the options.json they were built from
+        std::fs::write(&pkgs, "").unwrap();
+        std::fs::write(config.file("nixosoptions.db"), "").unwrap();
+        assert_eq!(nixos::nixospkgs_with_config(&config).await.unwrap(), pkgs);
+        assert_eq!(
+            options::optionsdb_with_config(&config).await.unwrap(),
+            config.file("nixosoptions.db")
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_during_write_with_wal() {
+        let dir = testdir("wal");
+        let db = dir.join("pkgs.db");
+        let dbfile = db.to_str().unwrap();
+        let pool = connecttmp(dbfile).await.unwrap();
+        sqlx::query("CREATE TABLE pkgs (attribute TEXT)").execute(&pool).await.unwrap();
+        sqlx::query("INSERT INTO pkgs VALUES ('hello')").execute(&pool).await.unwrap();
+        closetmp(pool).await.unwrap();
+        assert!(!dir.join("pkgs.db-wal").exists());
+
+        let url = format!("sqlite://{}", dbfile);
+        let writer = SqlitePool::connect(&url).await.unwrap();
+        let reader = SqlitePool::connect(&url).await.unwrap();
+        let mut tx = writer.begin().await.unwrap();
+        sqlx::query("INSERT INTO pkgs VALUES ('firefox')").execute(&mut tx).await.unwrap();
+
+        // The reader sees the last commit while the write transaction is still open
+        let (mode,): (String,) = sqlx::query_as("PRAGMA journal_mode").fetch_one(&reader).await.unwrap();
+        assert_eq!(mode, "wal");
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM pkgs").fetch_one(&reader).await.unwrap();
+        assert_eq!(count, 1);
+        tx.commit().await.unwrap();
+    }
+
+    #[test]
+    fn partial_cache_status() {
+        let dir = testdir("cache-status");
+        let config = CacheConfig {
+            nixos_version: Some(String::from("23.05.1234.abcdef")),
+            ..testconfig(&dir)
+        };
+        let status = cache_status_with_config(&config);
+        assert!(!status.packages.exists() && !status.options.exists());
+
+        // Only the package database, for the running release
+        std::fs::write(config.file(&config.pkgsname("db")), "").unwrap();
+        std::fs::write(config.file(&config.pkgsname("ver")), "nixos-23.05.5678.fedcba\n").unwrap();
+        let status = cache_status_with_config(&config);
+        assert!(status.packages.is_current());
+        assert_eq!(status.packages.version.as_deref(), Some("23.05.5678.fedcba"));
+        assert_eq!(status.options.state, ArtifactState::Absent);
+
+        // Options for an older release, and a database that lost its version file
+        std::fs::write(config.file("nixosoptions.json"), "{}").unwrap();
+        std::fs::write(config.file("nixosoptions.ver"), "22.11.4321.abcdef").unwrap();
+        std::fs::remove_file(config.file(&config.pkgsname("ver"))).unwrap();
+        let status = cache_status_with_config(&config);
+        assert_eq!(
+            status.packages,
+            ArtifactStatus {
+                state: ArtifactState::Stale,
+                version: None,
+            }
+        );
+        assert_eq!(status.options.state, ArtifactState::Stale);
+        assert_eq!(status.options.version.as_deref(), Some("22.11.4321.abcdef"));
+    }
 }
@@ -1,273 +1,2636 @@
-use crate::CACHEDIR;
-use anyhow::{anyhow, Context, Result};
-use log::{debug, info};
-use sqlx::{migrate::MigrateDatabase, Row, Sqlite, SqlitePool};
+use crate::error::{tooloutput_async, NixDataError, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{QueryBuilder, Row, Sqlite, SqlitePool};
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{Read, Write},
-    path::Path,
-    process::{Command, Stdio},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Instant,
 };
+use tokio_util::sync::CancellationToken;
 
-use super::{channel, flakes};
-
-/// Downloads the latest `packages.json` for the system from the NixOS cache and returns the path to an SQLite database `nixospkgs.db` which contains package data.
-/// Will only work on NixOS systems.
-pub async fn nixospkgs() -> Result<String> {
-    let versionout = Command::new("nixos-version").output()?;
-    let mut version = &String::from_utf8(versionout.stdout)?[0..5];
+use super::{
+    channel, checkcancelled, checkreachable, closetmp, columnexists, connectdb, connecttmp, flakes, getmetainfo, hostsystem,
+    httpclient, isbrotli, lockfile, publishedsha256,
+    query::{createfts, querypackages, NixPackage, PackageDb},
+    rebuild::{RebuildOutcome, RebuildPhase},
+    readtimeout, replacedb, requiremeta, sendretrying, setbuildinfo, setbuilttime, setmetainfo,
+    tableexists, verifysha256, withsuffix, writebrotli, CacheConfig, ChannelSource, NixPkg,
+};
 
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
+/// Extracts the `YY.MM` release from the output of `nixos-version`, such as `23.05.1234.abcdef (Stoat)` or `23.11pre530470.abcdef (Tapir)`.
+pub(super) fn parsenixosversion(output: &str) -> Result<String> {
+    let invalid = || {
+        NixDataError::Parse(format!(
+            "Unexpected output from nixos-version: {:?}",
+            output.trim()
+        ))
+    };
+    let version = output.split_whitespace().next().ok_or_else(invalid)?;
+    let mut parts = version.split('.');
+    let major = parts.next().ok_or_else(invalid)?;
+    let minor = parts.next().ok_or_else(invalid)?;
+    let minor = minor.get(0..2).ok_or_else(invalid)?;
+    if major.len() != 2
+        || !major.chars().all(|c| c.is_ascii_digit())
+        || !minor.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
     }
+    Ok(format!("{}.{}", major, minor))
+}
 
-    let verurl = format!(
-        "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-{}/nixpkgs.ver",
-        version
-    );
-    debug!("Checking NixOS version");
-    let resp = reqwest::get(&verurl);
-    let resp = if let Ok(r) = resp.await {
-        r
+/// Returns `unstable` if the `YY.MM` release `version` is the one currently being developed on `nixos-unstable`,
+/// as given by `unstableurl`, the URL `https://channels.nixos.org/nixos-unstable` redirects to.
+/// Otherwise `version` is a stable release and is returned unchanged.
+fn channelfor(version: &str, unstableurl: &reqwest::Url) -> String {
+    let unstable = unstableurl
+        .path_segments()
+        .and_then(|mut x| x.next_back())
+        .map(|x| x.strip_prefix("nixos-").unwrap_or(x))
+        .and_then(|x| parsenixosversion(x).ok());
+    if unstable.as_deref() == Some(version) {
+        String::from("unstable")
     } else {
-        // Internet connection failed
-        // Check if we can use the old database
-        let dbpath = format!("{}/nixospkgs.db", &*CACHEDIR);
-        if Path::new(&dbpath).exists() {
-            info!("Using old database");
-            return Ok(dbpath);
-        } else {
-            return Err(anyhow!("Could not find latest NixOS version"));
-        }
+        version.to_string()
+    }
+}
+
+/// Resolves the channel to use for the NixOS `YY.MM` release `version`:
+/// `unstable` if `version` is the release currently being developed on `nixos-unstable`, otherwise `version` itself.
+/// This is decided by following the `https://channels.nixos.org/nixos-unstable` redirect,
+/// so if it can't be reached, `version` is assumed to be a stable release.
+pub async fn resolve_channel(version: &str) -> String {
+    resolvechannel(&httpclient(true).unwrap_or_default(), &channelurl("unstable"), version).await
+}
+
+/// Like [resolve_channel()], following the redirect of the unstable channel at `unstableurl` with `client`.
+async fn resolvechannel(client: &reqwest::Client, unstableurl: &str, version: &str) -> String {
+    match sendretrying(|| client.get(unstableurl)).await {
+        Ok(resp) if resp.status().is_success() => channelfor(version, resp.url()),
+        _ => version.to_string(),
+    }
+}
+
+/// Resolves the channel matching the running NixOS system, e.g. `22.11` or `unstable`,
+/// or matching the [NixOS version](CacheConfig::nixos_version) set in `config`.
+pub(super) async fn systemchannel(config: &CacheConfig, client: &reqwest::Client) -> Result<String> {
+    let version = match config.nixos_version.as_deref() {
+        Some("unstable") => return Ok(String::from("unstable")),
+        Some(version) => parsenixosversion(version)?,
+        None => runningrelease(&mut tokio::process::Command::new("nixos-version")).await?,
     };
+    Ok(resolvechannel(client, &channelurl("unstable"), &version).await)
+}
+
+/// Reads the `YY.MM` release of the running NixOS system from the output of `nixosversion`, a `nixos-version` command.
+/// Fails with [NixDataError::NotNixos] if it can't be found.
+async fn runningrelease(nixosversion: &mut tokio::process::Command) -> Result<String> {
+    let versionout = tooloutput_async(nixosversion).await?;
+    parsenixosversion(&String::from_utf8(versionout.stdout)?)
+}
+
+/// URL of the NixOS channel `channel`, e.g. `22.11` or `unstable`, which redirects to its latest release.
+fn channelurl(channel: &str) -> String {
+    format!("https://channels.nixos.org/nixos-{}", channel)
+}
+
+/// URL of `file` in the nix-data database repository for `channel`.
+/// Files for systems other than the host (`othersystem`) are in a subdirectory named after the system.
+fn dburl(channel: &str, othersystem: Option<&str>, file: &str) -> String {
+    match othersystem {
+        Some(system) => format!(
+            "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-{}/{}/{}",
+            channel, system, file
+        ),
+        None => format!(
+            "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-{}/{}",
+            channel, file
+        ),
+    }
+}
+
+/// Fetches the latest version of the nix-data database from its version file at `verurl`.
+async fn latestdbversion(client: &reqwest::Client, verurl: &str) -> Result<String> {
+    debug!("Checking NixOS version");
+    let resp = sendretrying(|| client.get(verurl)).await?;
     let latestnixosver = if resp.status().is_success() {
         resp.text().await?
     } else {
-        let resp = reqwest::get("https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-unstable/nixpkgs.ver").await?;
-        if resp.status().is_success() {
-            version = "unstable";
-            resp.text().await?
-        } else {
-            return Err(anyhow!("Could not find latest NixOS version"));
-        }
+        return Err(NixDataError::ChannelResolve(String::from("Could not find latest NixOS version")));
     };
     debug!("Latest NixOS version: {}", latestnixosver);
 
-    let latestnixosver = latestnixosver
-        .strip_prefix("nixos-")
-        .unwrap_or(&latestnixosver);
+    Ok(stripchannelprefix(latestnixosver.trim()))
+}
+
+/// Strips the `nixos-` prefix from a channel release name such as `nixos-23.05.1234.abcdef`,
+/// giving the bare version stored in `.ver` files, e.g. `23.05.1234.abcdef`.
+pub(super) fn stripchannelprefix(release: &str) -> String {
+    release.strip_prefix("nixos-").unwrap_or(release).to_string()
+}
+
+/// Resolves the latest release of the NixOS channel `channel` (e.g. `23.05` or `unstable`) on `https://channels.nixos.org`,
+/// returning its bare version, e.g. `23.05.1234.abcdef`.
+/// Only the channel's redirect is followed, so this is cheap enough to show the latest version without downloading anything.
+pub async fn latest_version(channel: &str) -> Result<String> {
+    latest_version_with_config(&CacheConfig::default(), channel).await
+}
+
+/// Like [latest_version()], but resolves `channel` on the [base URL](ChannelSource::base_url) of the channel source
+/// given by `config`. The [channel](ChannelSource::channel) set there is ignored in favor of `channel`.
+pub async fn latest_version_with_config(config: &CacheConfig, channel: &str) -> Result<String> {
+    let source = ChannelSource {
+        base_url: config.channel_source.base_url.clone(),
+        channel: None,
+    };
+    let (_, release) = channelrelease(&httpclient(true)?, &source.url(channel)).await?;
+    Ok(stripchannelprefix(&release))
+}
+
+/// Resolves the latest release of the channel at `channelurl` by following its redirect with `client`,
+/// returning the URL of the release and its name, e.g. `nixos-23.05.1234.abcdef`.
+async fn channelrelease(client: &reqwest::Client, channelurl: &str) -> Result<(String, String)> {
+    debug!("Checking NixOS version");
+    // Only the URL redirected to is needed, not the release page itself
+    let resp = sendretrying(|| client.head(channelurl)).await?;
+    if !resp.status().is_success() {
+        return Err(NixDataError::ChannelResolve(String::from("Could not find latest NixOS version")));
+    }
+    let release = resp
+        .url()
+        .path_segments()
+        .and_then(|mut x| x.next_back())
+        .ok_or_else(|| NixDataError::ChannelResolve(String::from("No path segments found")))?
+        .to_string();
+    debug!("Latest NixOS version: {}", release);
+    Ok((resp.url().to_string(), release))
+}
+
+/// HTTP cache validators of a previous download, sent back to the server to check whether it has changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Validators {
+    etag: Option<String>,
+    lastmodified: Option<String>,
+}
+
+impl Validators {
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x: &reqwest::header::HeaderValue| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+        Validators {
+            etag: header(reqwest::header::ETAG),
+            lastmodified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+}
+
+/// Reads validators written by [writevalidators()]. Returns `None` if there are none,
+/// in which case freshness has to be decided from the `.ver` file instead.
+fn readvalidators(path: &str) -> Option<Validators> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut validators = Validators::default();
+    for line in contents.lines() {
+        match line.split_once(": ") {
+            Some(("ETag", v)) => validators.etag = Some(v.to_string()),
+            Some(("Last-Modified", v)) => validators.lastmodified = Some(v.to_string()),
+            _ => {}
+        }
+    }
+    if validators == Validators::default() {
+        None
+    } else {
+        Some(validators)
+    }
+}
+
+/// Stores `validators` at `path` as header lines. If the server sent none, any previously stored validators are removed.
+fn writevalidators(path: &str, validators: &Validators) -> Result<()> {
+    let mut contents = String::new();
+    if let Some(etag) = &validators.etag {
+        contents.push_str(&format!("ETag: {}\n", etag));
+    }
+    if let Some(lastmodified) = &validators.lastmodified {
+        contents.push_str(&format!("Last-Modified: {}\n", lastmodified));
+    }
+    if contents.is_empty() {
+        if Path::new(path).exists() {
+            fs::remove_file(path)?;
+        }
+    } else {
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+/// Downloads the latest `packages.json` for the system from the NixOS cache and returns the path to an SQLite database `nixospkgs.db` which contains package data.
+/// Will only work on NixOS systems, unless a [NixOS version](CacheConfig::nixos_version) is given to the `_with_config` variant.
+/// Elsewhere, fails with [NixDataError::NotNixos].
+/// Transient network failures are retried as set by [set_retry_config()](super::set_retry_config).
+///
+/// The database contains a `pkgs` table with the `attribute`, `system`, `pname` and `version` of each package,
+/// and a `meta` table with the `broken`, `insecure`, `unsupported` and `unfree` flags, `description`, `longdescription`,
+/// `homepage` (a JSON list for packages with several), `position`, and the `maintainers`, `license` and `platforms` (as JSON)
+/// of each attribute.
+///
+/// The `ETag` and `Last-Modified` headers of the download are stored alongside the database, and sent back on the next call
+/// so that an unchanged database isn't downloaded again, while a database rebuilt for the same channel version is picked up.
+/// If the server doesn't send them, the cached database is reused as long as the channel version hasn't changed.
+/// Set [force](CacheConfig::force) in the `_with_config` variant to download the database regardless.
+///
+/// If a SHA-256 hash is published alongside the download, it is verified before the database is written,
+/// and a [ChecksumMismatch](super::ChecksumMismatch) error is returned if it doesn't match.
+///
+/// A new database only replaces the cached one once it has been fully written, so a failed download leaves the previous
+/// database in place. The previous database is kept as `nixospkgs.db.bak`.
+///
+/// The returned path can be passed straight to the query functions:
+/// ```no_run
+/// # async fn example() -> nix_data::error::Result<()> {
+/// use nix_data::cache::{nixos::nixospkgs, query::package_info};
+///
+/// let db = nixospkgs().await?;
+/// let hello = package_info(&db, "hello").await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn nixospkgs() -> Result<PathBuf> {
+    nixospkgs_with_progress(|_, _| {}).await
+}
+
+/// Like [nixospkgs()], but calls `cb` with the number of bytes downloaded so far and the total size of the download
+/// as each chunk arrives. The total is `None` if the server doesn't report it, which is common for compressed responses.
+pub async fn nixospkgs_with_progress(cb: impl Fn(u64, Option<u64>)) -> Result<PathBuf> {
+    downloadnixospkgs(&CacheConfig::default(), cb, &CancellationToken::new()).await
+}
+
+/// Like [nixospkgs_with_progress()], but fails with [NixDataError::Cancelled] once `cancel` is cancelled.
+/// Cancellation is checked between downloaded chunks and before the database is written,
+/// and a cancelled download leaves the previous database untouched.
+pub async fn nixospkgs_with_cancel(
+    cb: impl Fn(u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    downloadnixospkgs(&CacheConfig::default(), cb, cancel).await
+}
+
+/// Like [nixospkgs()], but caches the database in the directory given by `config`,
+/// for the [system](CacheConfig::system) it sets.
+pub async fn nixospkgs_with_config(config: &CacheConfig) -> Result<PathBuf> {
+    downloadnixospkgs(config, |_, _| {}, &CancellationToken::new()).await
+}
+
+async fn downloadnixospkgs(
+    config: &CacheConfig,
+    cb: impl Fn(u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    if let Some(cached) = config.offlinefile(&config.pkgsname("db")) {
+        return cached;
+    }
+    let client = httpclient(true)?;
+    let channel = systemchannel(config, &client).await?;
+    let progress = |phase, done, total| {
+        if phase == RebuildPhase::Download {
+            cb(done, total)
+        }
+    };
+    Ok(fetchnixospkgs(config, &client, &channel, progress, cancel).await?.path)
+}
+
+/// Downloads the latest nix-data database for `channel` with `client`, unless the cached one is up to date,
+/// reporting each [RebuildPhase] to `progress`. With [force](CacheConfig::force), it is downloaded even if it is up to date.
+/// If the latest version can't be checked because the connection failed, the cached database is used if there is one.
+pub(super) async fn fetchnixospkgs(
+    config: &CacheConfig,
+    client: &reqwest::Client,
+    channel: &str,
+    progress: impl Fn(RebuildPhase, u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<RebuildOutcome> {
+    config.createdir()?;
+
+    let dbfile = config.file(&config.pkgsname("db"));
+    let verurl = dburl(channel, config.othersystem(), "nixpkgs.ver");
+    let latestnixosver = match latestdbversion(client, &verurl).await {
+        Ok(latest) => latest,
+        Err(NixDataError::Network(e)) if Path::new(&dbfile).exists() => {
+            warn!("Could not check for a new NixOS database, using the old one: {}", e);
+            return Ok(RebuildOutcome {
+                path: dbfile.into(),
+                downloaded: false,
+                version: fs::read_to_string(config.file(&config.pkgsname("ver"))).unwrap_or_default(),
+            });
+        }
+        Err(e) => return Err(e),
+    };
     info!("latestnixosver: {}", latestnixosver);
+    checkcancelled(cancel)?;
+    updatedb(
+        config,
+        client,
+        &dburl(channel, config.othersystem(), "nixpkgs.db.br"),
+        &latestnixosver,
+        progress,
+        cancel,
+    )
+    .await
+}
+
+/// Brings `nixospkgs.db` in the directory of `config` up to date with the database at `url`, which is `latestnixosver`,
+/// reporting each [RebuildPhase] to `progress`. The version and validators of the database are stored next to it.
+/// With [force](CacheConfig::force), the database is downloaded again even if it is up to date.
+async fn updatedb(
+    config: &CacheConfig,
+    client: &reqwest::Client,
+    url: &str,
+    latestnixosver: &str,
+    progress: impl Fn(RebuildPhase, u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<RebuildOutcome> {
+    let dbfile = config.file(&config.pkgsname("db"));
+    let verfile = config.file(&config.pkgsname("ver"));
+    let validatorfile = config.file(&config.pkgsname("validators"));
+    // Held until the database is replaced, so concurrent callers wait and then find it up to date
+    let _lock = lockfile(&dbfile).await?;
+    let dbexists = Path::new(&dbfile).exists();
+    let validators = if dbexists && !config.force {
+        readvalidators(&validatorfile)
+    } else {
+        None
+    };
+    // Without validators from a previous download, check if latest version is already downloaded
+    if validators.is_none() && dbexists && !config.force {
+        if let Ok(prevver) = fs::read_to_string(&verfile) {
+            if prevver == latestnixosver {
+                debug!("No new version of NixOS found");
+                return Ok(RebuildOutcome {
+                    path: dbfile.into(),
+                    downloaded: false,
+                    version: latestnixosver.to_string(),
+                });
+            }
+        }
+    }
+
+    let (newvalidators, downloaded) =
+        downloaddb(config, client, url, latestnixosver, validators.as_ref(), &progress, cancel).await?;
+    debug!("Writing nix-data version");
+    // Write version downloaded to file, also when the server reports the database as unchanged,
+    // as the channel version may have moved on without the database being rebuilt
+    File::create(&verfile)?.write_all(latestnixosver.as_bytes())?;
+    writevalidators(&validatorfile, &newvalidators)?;
+    Ok(RebuildOutcome {
+        path: dbfile.into(),
+        downloaded,
+        version: latestnixosver.to_string(),
+    })
+}
+
+/// Downloads the brotli compressed database at `url` with `client` and replaces the NixOS package database of `config` with it,
+/// recording it as `version` for the [system](CacheConfig::system) of `config`.
+/// The new database is built in a temporary file and only moved into place once it is complete and contains packages,
+/// so a cancelled or failed download leaves the previous database untouched.
+/// Each [RebuildPhase] is reported to `progress`, and `cancel` is checked between downloaded chunks and at phase boundaries.
+///
+/// If `validators` are given the request is conditional, and the database is left as it is if the server reports it unchanged.
+/// Returns the validators to send with the next request, and whether the database was downloaded.
+async fn downloaddb(
+    config: &CacheConfig,
+    client: &reqwest::Client,
+    url: &str,
+    version: &str,
+    validators: Option<&Validators>,
+    progress: &impl Fn(RebuildPhase, u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<(Validators, bool)> {
+    debug!("Downloading nix-data database");
+    let downloadstart = Instant::now();
+    let mut resp = sendretrying(|| {
+        let mut req = client.get(url);
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                req = req.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(lastmodified) = &validators.lastmodified {
+                req = req.header(reqwest::header::IF_MODIFIED_SINCE, lastmodified);
+            }
+        }
+        req
+    })
+    .await?;
+    if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+        debug!("nix-data database not modified");
+        // A 304 may carry updated validators, any it leaves out still apply
+        let mut newvalidators = Validators::from_headers(resp.headers());
+        if let Some(validators) = validators {
+            newvalidators.etag = newvalidators.etag.or_else(|| validators.etag.clone());
+            newvalidators.lastmodified = newvalidators
+                .lastmodified
+                .or_else(|| validators.lastmodified.clone());
+        }
+        return Ok((newvalidators, false));
+    }
+    if !resp.status().is_success() {
+        return Err(NixDataError::Download(String::from("Failed to download latest nixospkgs.db.br")));
+    }
+    let newvalidators = Validators::from_headers(resp.headers());
+    let total = resp.content_length();
+    let mut bytes = Vec::new();
+    progress(RebuildPhase::Download, 0, total);
+    while let Some(chunk) = readtimeout(resp.chunk()).await? {
+        checkcancelled(cancel)?;
+        bytes.extend_from_slice(&chunk);
+        progress(RebuildPhase::Download, bytes.len() as u64, total);
+    }
+    checkcancelled(cancel)?;
+    let downloadtime = downloadstart.elapsed();
+    let downloadsize = bytes.len();
+    debug!("Downloaded {} bytes in {:.2?}", downloadsize, downloadtime);
+    if let Some(expected) = publishedsha256(client, url).await? {
+        debug!("Verifying nix-data database");
+        verifysha256(Sha256::new_with_prefix(&bytes), &expected, url)?;
+    }
+    debug!("Writing nix-data database");
+    let importstart = Instant::now();
+    let dbfile = config.file(&config.pkgsname("db"));
+    let tmpfile = format!("{}.tmp", dbfile);
+    let system = config.othersystem().map(String::from).unwrap_or_else(hostsystem);
+    let count = match writenixospkgs(bytes, &tmpfile, version, &system, progress, cancel).await {
+        Ok(count) => count,
+        Err(e) => {
+            let _ = fs::remove_file(&tmpfile);
+            return Err(e);
+        }
+    };
+    replacedb(&tmpfile, &dbfile)?;
+    let importtime = importstart.elapsed();
+    debug!("Imported {} packages in {:.2?}", count, importtime);
+    info!(
+        "Updated {} to {}: downloaded {} bytes in {:.2?}, imported {} packages in {:.2?}",
+        dbfile, version, downloadsize, downloadtime, count, importtime
+    );
+    Ok((newvalidators, true))
+}
+
+/// Decompresses the downloaded database `bytes` to `tmpfile` and finishes it for use as `version` of the packages for `system`,
+/// reporting the [RebuildPhase]s after the download to `progress`.
+/// Decompressing runs on the blocking thread pool, so it never stalls the async runtime. Returns the number of packages.
+async fn writenixospkgs(
+    bytes: Vec<u8>,
+    tmpfile: &str,
+    version: &str,
+    system: &str,
+    progress: &impl Fn(RebuildPhase, u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<usize> {
+    let outfile = tmpfile.to_string();
+    progress(RebuildPhase::Parse, 0, None);
+    let start = Instant::now();
+    let written = tokio::task::spawn_blocking(move || writebrotli(&bytes, &outfile)).await??;
+    debug!("Decompressed nix-data database in {:.2?}", start.elapsed());
+    progress(RebuildPhase::Parse, written, Some(written));
+    checkcancelled(cancel)?;
+
+    debug!("Verifying nix-data database");
+    let pool = connecttmp(tmpfile).await?;
+    let count = finishnixospkgs(&pool, system, version, progress, cancel).await?;
+    closetmp(pool).await?;
+    Ok(count)
+}
+
+/// Records `system` and `version` in the package database in `pool` and indexes it for searching,
+/// failing if it doesn't contain any packages. Reports the [RebuildPhase::Verify] and [RebuildPhase::Insert] phases to `progress`.
+/// Returns the number of packages.
+async fn finishnixospkgs(
+    pool: &SqlitePool,
+    system: &str,
+    version: &str,
+    progress: &impl Fn(RebuildPhase, u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<usize> {
+    progress(RebuildPhase::Verify, 0, Some(1));
+    let (count,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM pkgs"#)
+        .fetch_one(pool)
+        .await?;
+    if count == 0 {
+        return Err(NixDataError::Other(String::from(
+            "Package database is empty",
+        )));
+    }
+    progress(RebuildPhase::Verify, 1, Some(1));
+    checkcancelled(cancel)?;
+
+    progress(RebuildPhase::Insert, 0, Some(2));
+    setmetainfo(pool, "system", system).await?;
+    setbuildinfo(pool, version).await?;
+    progress(RebuildPhase::Insert, 1, Some(2));
+    let start = Instant::now();
+    createfts(pool).await?;
+    debug!("Built full-text index in {:.2?}", start.elapsed());
+    progress(RebuildPhase::Insert, 2, Some(2));
+    Ok(count as usize)
+}
+
+/// Columns a package database needs for the queries in this crate, by table.
+const NIXOSPKGSSCHEMA: &[(&str, &[&str])] = &[
+    ("pkgs", &["attribute", "pname", "version"]),
+    (
+        "meta",
+        &[
+            "attribute",
+            "broken",
+            "insecure",
+            "unsupported",
+            "unfree",
+            "description",
+            "longdescription",
+            "homepage",
+        ],
+    ),
+];
+
+/// Errors if the package database in `pool` is missing a table or column from [NIXOSPKGSSCHEMA].
+async fn checknixospkgsschema(pool: &SqlitePool) -> Result<()> {
+    for (table, columns) in NIXOSPKGSSCHEMA {
+        if !tableexists(pool, table).await? {
+            return Err(NixDataError::Other(format!(
+                "Package database has no {} table",
+                table
+            )));
+        }
+        for column in *columns {
+            if !columnexists(pool, table, column).await? {
+                return Err(NixDataError::Other(format!(
+                    "Package database has no {} column in its {} table",
+                    column, table
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Installs the prebuilt package database at `src` as the cached [nixospkgs()] database for `channel_version`
+/// (e.g. `23.05.1234.abcdef`), for deployments that distribute the database rather than download it.
+/// Returns the path to the installed database.
+///
+/// `src` is copied rather than moved, and the copy is checked to have the `pkgs` and `meta` tables with the columns
+/// [nixospkgs()] databases have, and at least one package. A database failing the check is rejected,
+/// leaving any previously cached database in place.
+/// Until the channel moves on from `channel_version`, [nixospkgs()] then uses the imported database without downloading one.
+pub async fn import_prebuilt_db(src: &Path, channel_version: &str) -> Result<PathBuf> {
+    import_prebuilt_db_with_config(&CacheConfig::default(), src, channel_version).await
+}
+
+/// Like [import_prebuilt_db()], but installs the database in the directory given by `config`,
+/// for the [system](CacheConfig::system) it sets.
+pub async fn import_prebuilt_db_with_config(
+    config: &CacheConfig,
+    src: &Path,
+    channel_version: &str,
+) -> Result<PathBuf> {
+    config.createdir()?;
+    let dbfile = config.file(&config.pkgsname("db"));
+    let tmpfile = format!("{}.tmp", dbfile);
+    let _lock = lockfile(&dbfile).await?;
+    let system = config.othersystem().map(String::from).unwrap_or_else(hostsystem);
+    fs::copy(src, &tmpfile)?;
+    let imported = async {
+        let pool = connecttmp(&tmpfile).await?;
+        let result = match checknixospkgsschema(&pool).await {
+            Ok(()) => {
+                finishnixospkgs(&pool, &system, channel_version, &|_, _, _| {}, &CancellationToken::new())
+                    .await
+                    .map(|_| ())
+            }
+            Err(e) => Err(e),
+        };
+        result.and(closetmp(pool).await)
+    }
+    .await;
+    if let Err(e) = imported {
+        let _ = fs::remove_file(&tmpfile);
+        return Err(e);
+    }
+    replacedb(&tmpfile, &dbfile)?;
+    File::create(config.file(&config.pkgsname("ver")))?.write_all(channel_version.as_bytes())?;
+    // Validators belong to a downloaded database, and would make the next download skip the version check
+    let _ = fs::remove_file(config.file(&config.pkgsname("validators")));
+    Ok(dbfile.into())
+}
+
+/// Downloads the latest 'options.json' for the system from the NixOS cache and returns the path to the file.
+/// The file can be parsed with [parse_options()](super::options::parse_options).
+/// Will only work on NixOS systems, unless a [NixOS version](CacheConfig::nixos_version) is given to the `_with_config` variant.
+/// Elsewhere, fails with [NixDataError::NotNixos].
+/// Transient network failures are retried as set by [set_retry_config()](super::set_retry_config).
+pub async fn nixosoptions() -> Result<PathBuf> {
+    nixosoptions_with_progress(|_, _| {}).await
+}
+
+/// Like [nixosoptions()], but calls `cb` with the number of bytes downloaded so far and the total size of the download
+/// as each chunk arrives. The total is `None` if the server doesn't report it, which is common for compressed responses.
+pub async fn nixosoptions_with_progress(cb: impl Fn(u64, Option<u64>)) -> Result<PathBuf> {
+    downloadnixosoptions(&CacheConfig::default(), cb, &CancellationToken::new()).await
+}
+
+/// Like [nixosoptions_with_progress()], but fails with [NixDataError::Cancelled] once `cancel` is cancelled.
+/// Cancellation is checked between downloaded chunks, and a cancelled download leaves the previous `options.json` untouched.
+pub async fn nixosoptions_with_cancel(
+    cb: impl Fn(u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    downloadnixosoptions(&CacheConfig::default(), cb, cancel).await
+}
+
+/// Like [nixosoptions()], but stores `options.json` in the directory given by `config`,
+/// and downloads it from the [channel source](CacheConfig::channel_source) it sets.
+pub async fn nixosoptions_with_config(config: &CacheConfig) -> Result<PathBuf> {
+    downloadnixosoptions(config, |_, _| {}, &CancellationToken::new()).await
+}
+
+async fn downloadnixosoptions(
+    config: &CacheConfig,
+    cb: impl Fn(u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    if let Some(cached) = config.offlinefile("nixosoptions.json") {
+        return cached;
+    }
+    let client = httpclient(true)?;
+    let channel = systemchannel(config, &client).await?;
+    let (releaseurl, release) = channelrelease(&client, &config.channel_source.url(&channel)).await?;
+    fetchnixosoptions(config, &client, &releaseurl, &release, cb, cancel).await
+}
+
+/// Downloads `options.json` of the NixOS release `release` at `releaseurl` with `client`, unless the cached one is up to date.
+async fn fetchnixosoptions(
+    config: &CacheConfig,
+    client: &reqwest::Client,
+    releaseurl: &str,
+    release: &str,
+    cb: impl Fn(u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    config.createdir()?;
+
+    // Held until the new options are written, so concurrent callers wait and then find them up to date
+    let _lock = lockfile(config.file("nixosoptions.json")).await?;
+
     // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/nixospkgs.ver", &*CACHEDIR)) {
-        if prevver == latestnixosver && Path::new(&format!("{}/nixospkgs.db", &*CACHEDIR)).exists()
+    // Versions written by older versions of this crate kept the `nixos-` prefix
+    let version = stripchannelprefix(release);
+    if let Ok(prevver) = tokio::fs::read_to_string(config.file("nixosoptions.ver")).await {
+        if stripchannelprefix(prevver.trim()) == version
+            && Path::new(&config.file("nixosoptions.json")).exists()
+            && !config.force
         {
-            debug!("No new version of NixOS found");
-            return Ok(format!("{}/nixospkgs.db", &*CACHEDIR));
+            debug!("No new version of NixOS options found");
+            return Ok(config.file("nixosoptions.json").into());
         }
     }
 
-    let url = format!(
-        "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-{}/nixpkgs.db.br",
-        version
-    );
-    debug!("Downloading nix-data database");
-    let client = reqwest::Client::builder().brotli(true).build()?;
-    let resp = client.get(url).send().await?;
+    // Downloaded from the release rather than the channel, so that it matches the version checked above
+    let url = format!("{}/options.json.br", releaseurl);
+    checkreachable(client, &url).await?;
+
+    // Download file with reqwest. It is decompressed here rather than by reqwest,
+    // as some mirrors serve `.br` files without `Content-Encoding: br`.
+    let rawclient = httpclient(false)?;
+    let mut resp = sendretrying(|| rawclient.get(&url)).await?;
     if resp.status().is_success() {
-        debug!("Writing nix-data database");
-        let mut out = File::create(&format!("{}/nixospkgs.db", &*CACHEDIR))?;
-        {
-            let bytes = resp.bytes().await?;
-            let mut reader = brotli::Decompressor::new(
-                bytes.as_ref(),
-                4096, // buffer size
-            );
-            let mut buf = [0u8; 4096];
-            loop {
-                match reader.read(&mut buf[..]) {
-                    Err(e) => {
-                        if let std::io::ErrorKind::Interrupted = e.kind() {
-                            continue;
-                        }
-                        panic!("{}", e);
-                    }
-                    Ok(size) => {
-                        if size == 0 {
-                            break;
-                        }
-                        if let Err(e) = out.write_all(&buf[..size]) {
-                            panic!("{}", e)
-                        }
-                    }
-                }
+        let compressed = isbrotli(&resp, &url);
+        let total = resp.content_length();
+        let mut bytes = Vec::new();
+        cb(0, total);
+        while let Some(chunk) = readtimeout(resp.chunk()).await? {
+            checkcancelled(cancel)?;
+            bytes.extend_from_slice(&chunk);
+            cb(bytes.len() as u64, total);
+        }
+        checkcancelled(cancel)?;
+        let jsonfile = config.file("nixosoptions.json");
+        // Options change less often than the channel version, so keep the file untouched if the download is identical
+        let hashfile = config.file("nixosoptions.sha256");
+        let hash = format!("{:x}", Sha256::digest(&bytes));
+        let unchanged = Path::new(&jsonfile).exists()
+            && !config.force
+            && tokio::fs::read_to_string(&hashfile).await.is_ok_and(|x| x.trim() == hash);
+        if unchanged {
+            debug!("NixOS options for {} are unchanged", version);
+        } else {
+            if compressed {
+                let jsonfile = jsonfile.clone();
+                tokio::task::spawn_blocking(move || writebrotli(&bytes, &jsonfile)).await??;
+            } else {
+                tokio::fs::write(&jsonfile, &bytes).await?;
             }
+            tokio::fs::write(&hashfile, hash.as_bytes()).await?;
         }
-        debug!("Writing nix-data version");
         // Write version downloaded to file
-        File::create(format!("{}/nixospkgs.ver", &*CACHEDIR))?
-            .write_all(latestnixosver.as_bytes())?;
+        tokio::fs::write(config.file("nixosoptions.ver"), version.as_bytes()).await?;
     } else {
-        return Err(anyhow!("Failed to download latest nixospkgs.db.br"));
+        return Err(NixDataError::Download(String::from("Failed to download latest options.json")));
     }
-    Ok(format!("{}/nixospkgs.db", &*CACHEDIR))
+
+    Ok(config.file("nixosoptions.json").into())
 }
 
-/// Downloads the latest 'options.json' for the system from the NixOS cache and returns the path to the file.
-/// Will only work on NixOS systems.
-pub fn nixosoptions() -> Result<String> {
-    let versionout = Command::new("nixos-version").output()?;
-    let mut version = &String::from_utf8(versionout.stdout)?[0..5];
+/// Downloads both the package database (see [nixospkgs()]) and `options.json` (see [nixosoptions()]) concurrently,
+/// resolving the latest release of the channel of the running system only once, so that both are for the same release.
+/// Returns the paths to the database and `options.json`.
+/// Will only work on NixOS systems, unless a [NixOS version](CacheConfig::nixos_version) is given to the `_with_config` variant.
+/// Elsewhere, fails with [NixDataError::NotNixos].
+pub async fn sync_all() -> Result<(PathBuf, PathBuf)> {
+    sync_all_with_config(&CacheConfig::default()).await
+}
 
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
+/// Like [sync_all()], but caches the files in the directory given by `config`.
+pub async fn sync_all_with_config(config: &CacheConfig) -> Result<(PathBuf, PathBuf)> {
+    if let (Some(pkgs), Some(options)) = (
+        config.offlinefile(&config.pkgsname("db")),
+        config.offlinefile("nixosoptions.json"),
+    ) {
+        return Ok((pkgs?, options?));
     }
+    let client = httpclient(true)?;
+    let channel = systemchannel(config, &client).await?;
+    syncrelease(
+        config,
+        &client,
+        &config.channel_source.url(&channel),
+        &dburl(&channel, config.othersystem(), "nixpkgs.db.br"),
+    )
+    .await
+}
 
-    let verurl = format!("https://channels.nixos.org/nixos-{}", version);
-    debug!("Checking NixOS version");
-    let resp = reqwest::blocking::get(&verurl)?;
-    let latestnixosver = if resp.status().is_success() {
-        resp.url()
-            .path_segments()
-            .context("No path segments found")?
-            .last()
-            .context("Last element not found")?
-            .to_string()
-    } else {
-        let resp = reqwest::blocking::get("https://channels.nixos.org/nixos-unstable")?;
-        if resp.status().is_success() {
-            version = "unstable";
-            resp.url()
-                .path_segments()
-                .context("No path segments found")?
-                .last()
-                .context("Last element not found")?
-                .to_string()
-        } else {
-            return Err(anyhow!("Could not find latest NixOS version"));
+/// Resolves the latest release of the channel at `channelurl` once, and brings both the package database at `dburl`
+/// and the `options.json` of that release up to date, recording the release as the version of both.
+async fn syncrelease(
+    config: &CacheConfig,
+    client: &reqwest::Client,
+    channelurl: &str,
+    dburl: &str,
+) -> Result<(PathBuf, PathBuf)> {
+    config.createdir()?;
+    let (releaseurl, release) = channelrelease(client, channelurl).await?;
+    // nixospkgs.ver holds the version without the `nixos-` prefix
+    let version = stripchannelprefix(&release);
+    let cancel = CancellationToken::new();
+    let (pkgs, options) = tokio::join!(
+        updatedb(config, client, dburl, &version, |_, _, _| {}, &cancel),
+        fetchnixosoptions(config, client, &releaseurl, &release, |_, _| {}, &cancel)
+    );
+    Ok((pkgs?.path, options?))
+}
+
+/// Returns whether the package database at `db` contains the `meta` table with package metadata
+/// (description, license, broken/insecure flags, ...).
+/// The prebuilt `nixospkgs.db` includes it, while the lighter databases built for flakes and legacy systems
+/// only contain attributes and versions.
+pub async fn has_meta(db: impl AsRef<Path>) -> Result<bool> {
+    let pool = connectdb(&db).await?;
+    tableexists(&pool, "meta").await
+}
+
+/// Returns whether a `environment.systemPackages` entry is a plain attribute path
+/// (e.g. `firefox` or `pkgs.python3Packages.requests`) that can be looked up in a package database.
+/// Entries such as `(pkgs.foo.override { ... })` or `(callPackage ./foo.nix {})` are custom derivations
+/// and can't be resolved.
+pub fn isattribute(entry: &str) -> bool {
+    let entry = entry.strip_prefix("pkgs.").unwrap_or(entry);
+    !entry.is_empty()
+        && !entry.starts_with('.')
+        && !entry.ends_with('.')
+        && entry
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-'.".contains(c))
+}
+
+/// Functions that only change the priority or selected output of a package, such as `lib.hiPrio` in `(lib.hiPrio pkgs.vim)`.
+/// The wrapped attribute is what gets installed, so it is looked up instead.
+const ATTRIBUTEWRAPPERS: &[&str] = &["hiPrio", "lowPrio", "getBin", "getLib", "getDev", "getMan"];
+
+/// Turns an entry of a package list such as `environment.systemPackages` into the attribute stored in package databases,
+/// or returns `None` if it is a custom derivation (see [isattribute()]).
+///
+/// Both configuration styles resolve to the same attribute: `firefox`, as written inside `with pkgs; [ ... ]`,
+/// and `pkgs.firefox` both give `firefox`. Parentheses and wrappers that don't change the package,
+/// such as `(lib.lowPrio pkgs.firefox)`, are removed as well.
+/// For [Flake](NixosType::Flake) systems, paths into the flake's package set, such as
+/// `inputs.nixpkgs.legacyPackages.${system}.firefox`, are also resolved.
+pub fn normalize_attribute(entry: &str, nixos: NixosType) -> Option<String> {
+    let mut entry = entry.trim();
+    loop {
+        if let Some(inner) = entry.strip_prefix('(').and_then(|x| x.strip_suffix(')')) {
+            entry = inner.trim();
+            continue;
+        }
+        if let Some((function, argument)) = entry.split_once(char::is_whitespace) {
+            let function = function.strip_prefix("pkgs.").unwrap_or(function);
+            let function = function.strip_prefix("lib.").unwrap_or(function);
+            if ATTRIBUTEWRAPPERS.contains(&function) {
+                entry = argument.trim();
+                continue;
+            }
         }
+        break;
+    }
+    let entry = match nixos {
+        NixosType::Flake => stripflakeprefix(entry),
+        NixosType::Legacy => entry,
     };
-    debug!("Latest NixOS version: {}", latestnixosver);
-
-    let url = format!(
-        "https://channels.nixos.org/nixos-{}/options.json.br",
-        version
-    );
+    if isattribute(entry) {
+        Some(entry.strip_prefix("pkgs.").unwrap_or(entry).to_string())
+    } else {
+        None
+    }
+}
 
-    // Download file with reqwest blocking
-    let client = reqwest::blocking::Client::builder().brotli(true).build()?;
-    let mut resp = client.get(url).send()?;
-    if resp.status().is_success() {
-        let mut out = File::create(&format!("{}/nixosoptions.json", &*CACHEDIR))?;
-        resp.copy_to(&mut out)?;
-        // Write version downloaded to file
-        File::create(format!("{}/nixosoptions.ver", &*CACHEDIR))?
-            .write_all(latestnixosver.as_bytes())?;
+/// Strips the path to a flake's package set, e.g. `inputs.nixpkgs.legacyPackages.${system}.` from
+/// `inputs.nixpkgs.legacyPackages.${system}.firefox`. The system may be written literally or interpolated.
+fn stripflakeprefix(entry: &str) -> &str {
+    let rest = ["inputs.nixpkgs.", "nixpkgs.", ""]
+        .iter()
+        .find_map(|x| entry.strip_prefix(x)?.strip_prefix("legacyPackages."));
+    let rest = match rest {
+        Some(rest) => rest,
+        None => return entry,
+    };
+    let systemend = if rest.starts_with("${") {
+        rest.find('}').map(|x| x + 1)
     } else {
-        return Err(anyhow!("Failed to download latest options.json"));
+        rest.find('.')
+    };
+    systemend
+        .and_then(|x| rest[x..].strip_prefix('.'))
+        .unwrap_or(entry)
+}
+
+/// Reads `environment.systemPackages` from every file in `paths`.
+/// Returns the set of plain attributes (normalized for `nixos` by [normalize_attribute()]),
+/// and separately the set of entries that are custom derivations (see [isattribute()]).
+pub(super) fn readsystempkgs(
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<(HashSet<String>, HashSet<String>)> {
+    readpkglist(paths, "environment.systemPackages", nixos)
+}
+
+/// Like [readsystempkgs()], but reads the package list `option` instead of `environment.systemPackages`.
+fn readpkglist(
+    paths: &[&str],
+    option: &str,
+    nixos: NixosType,
+) -> Result<(HashSet<String>, HashSet<String>)> {
+    let mut attributes = HashSet::new();
+    let mut custom = HashSet::new();
+    for path in paths {
+        if let Ok(filepkgs) = nix_editor::read::getarrvals(&fs::read_to_string(path)?, option) {
+            for pkg in filepkgs {
+                match normalize_attribute(&pkg, nixos) {
+                    Some(attribute) => {
+                        attributes.insert(attribute);
+                    }
+                    None => {
+                        custom.insert(pkg);
+                    }
+                }
+            }
+        }
     }
+    Ok((attributes, custom))
+}
 
-    Ok(format!("{}/nixosoptions.json", &*CACHEDIR))
+/// Reads the `users.users.<name>.packages` lists from every file in `paths`,
+/// returning the plain attributes in each, keyed by user name. Custom derivations are skipped.
+fn readuserpkgs(paths: &[&str], nixos: NixosType) -> Result<HashMap<String, HashSet<String>>> {
+    let mut users: HashMap<String, HashSet<String>> = HashMap::new();
+    for path in paths {
+        let file = fs::read_to_string(path)?;
+        // User names are attribute names, so find them among all the attributes set in the file
+        let attrs = match nix_editor::parse::get_collection(file.clone()) {
+            Ok(attrs) => attrs,
+            Err(_) => continue,
+        };
+        for key in attrs.keys() {
+            let parts = key.split('.').collect::<Vec<_>>();
+            if let ["users", "users", user, "packages"] = parts.as_slice() {
+                let user = user.trim_matches('"').to_string();
+                if let Ok(pkgs) = nix_editor::read::getarrvals(&file, key) {
+                    let userpkgs = users.entry(user).or_default();
+                    userpkgs.extend(pkgs.iter().filter_map(|x| normalize_attribute(x, nixos)));
+                }
+            }
+        }
+    }
+    Ok(users)
 }
 
-pub(super) enum NixosType {
+/// Returns the entries of `environment.systemPackages` in `paths` that are custom derivations
+/// rather than plain attributes, such as `(pkgs.foo.override { ... })` or `(callPackage ./foo.nix {})`.
+/// These can't be looked up in a package database, so they never appear in the output of
+/// [getflakepkgs()](super::flakes::getflakepkgs) or [getlegacypkgs()](super::channel::getlegacypkgs).
+pub fn getcustompkgs(paths: &[&str]) -> Result<HashSet<String>> {
+    Ok(readsystempkgs(paths, pathsnixostype(paths))?.1)
+}
+
+/// Type of NixOS system, which determines where package versions are read from.
+/// - [Flake](NixosType::Flake) systems use the package database built by [flakespkgs()](super::flakes::flakespkgs).
+/// - [Legacy](NixosType::Legacy) systems use the package database built by [legacypkgs()](super::channel::legacypkgs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NixosType {
     Flake,
     Legacy,
 }
 
+/// Guesses whether the NixOS system configured in `config_dir` (usually `/etc/nixos`) is a [Flake](NixosType::Flake)
+/// or [Legacy](NixosType::Legacy) system.
+///
+/// A `flake.nix` in `config_dir` means a flake system. Without it, a system whose configuration lives elsewhere is
+/// still taken to be a flake system if `config_dir` has no `configuration.nix` and the system flake registry
+/// (`/etc/nix/registry.json`) pins `nixpkgs` to a store path, as NixOS does for flake systems that set `nix.registry`.
+/// Anything else, including a missing `config_dir`, is assumed to be legacy.
+pub fn detect_nixos_type(config_dir: &Path) -> NixosType {
+    detectnixostype(config_dir, "/etc/nix/registry.json")
+}
+
+/// Like [detect_nixos_type()], reading the system flake registry from `registry`.
+fn detectnixostype(config_dir: &Path, registry: &str) -> NixosType {
+    let flakeelsewhere = config_dir.exists()
+        && !config_dir.join("configuration.nix").exists()
+        && registrypinsnixpkgs(registry);
+    if config_dir.join("flake.nix").exists() || flakeelsewhere {
+        NixosType::Flake
+    } else {
+        NixosType::Legacy
+    }
+}
+
+/// Whether the flake registry at `path` has a `nixpkgs` entry pointing into the Nix store.
+fn registrypinsnixpkgs(path: &str) -> bool {
+    let registry: serde_json::Value = match fs::read_to_string(path)
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+    {
+        Some(registry) => registry,
+        None => return false,
+    };
+    registry["flakes"]
+        .as_array()
+        .map(|flakes| {
+            flakes.iter().any(|entry| {
+                entry["from"]["id"].as_str() == Some("nixpkgs")
+                    && entry["to"]["path"]
+                        .as_str()
+                        .map(|x| x.starts_with("/nix/store/"))
+                        .unwrap_or(false)
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Like [getflakepkgs()](super::flakes::getflakepkgs) or [getlegacypkgs()](super::channel::getlegacypkgs),
+/// choosing between them with [detect_nixos_type()] on the directory of the first file in `paths`
+/// (or `/etc/nixos` if `paths` is empty).
+pub async fn getnixospkgs_auto(paths: &[&str]) -> Result<HashMap<String, String>> {
+    getnixospkgs(paths, pathsnixostype(paths)).await
+}
+
+/// Runs [detect_nixos_type()] on the directory of the first file in `paths`, or `/etc/nixos` if `paths` is empty.
+fn pathsnixostype(paths: &[&str]) -> NixosType {
+    let dir = paths
+        .first()
+        .and_then(|x| Path::new(x).parent())
+        .unwrap_or_else(|| Path::new("/etc/nixos"));
+    detect_nixos_type(dir)
+}
+
+/// Returns the entry points of the system's NixOS configuration, to pass as `paths` to functions such as [getnixospkgs_auto()].
+///
+/// If the `NIXOS_CONFIG` environment variable is set, it is used instead of `/etc/nixos`. It may name either the configuration file
+/// itself or the directory containing it. In a directory, `configuration.nix` and `flake.nix` are returned, whichever exist,
+/// with `configuration.nix` first. Flake systems usually keep their packages in a `configuration.nix` imported by the flake,
+/// and [detect_nixos_type()] tells the two kinds of system apart from the directory of the first path.
+///
+/// Fails if no configuration is found, for example on systems that aren't NixOS.
+pub fn default_config_paths() -> Result<Vec<PathBuf>> {
+    let config = std::env::var_os("NIXOS_CONFIG")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/etc/nixos"));
+    let paths = if config.is_file() {
+        vec![config.clone()]
+    } else {
+        ["configuration.nix", "flake.nix"]
+            .iter()
+            .map(|x| config.join(x))
+            .filter(|x| x.is_file())
+            .collect()
+    };
+    if paths.is_empty() {
+        return Err(NixDataError::Other(format!(
+            "No NixOS configuration found at {}. Set NIXOS_CONFIG to the configuration to use",
+            config.display()
+        )));
+    }
+    Ok(paths)
+}
+
+/// Like [getnixospkgs_auto()], reading the configuration found by [default_config_paths()].
+pub async fn getnixospkgs_default() -> Result<HashMap<String, String>> {
+    let paths = default_config_paths()?
+        .iter()
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let paths = paths.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    getnixospkgs_auto(&paths).await
+}
+
+/// A package declared in `environment.systemPackages`, with its details in a package database. Returned by [installed_packages()].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    /// Attribute of the package, without any `pkgs.` prefix.
+    pub attribute: String,
+    /// Details of the package in the database, or `None` if it isn't there.
+    pub package: Option<NixPackage>,
+    /// Whether the package is in the database. For the database of a newer channel,
+    /// `false` means the package was removed or renamed and the configuration will fail to build after upgrading.
+    pub still_available: bool,
+}
+
+/// Returns every package declared in `environment.systemPackages` in `paths`, ordered by attribute,
+/// with its details in the package database at `db`, such as the one returned by [nixospkgs()].
+/// Custom derivations (see [getcustompkgs()]) can't be looked up, so they are left out.
+/// Requires a database with a `meta` table.
+pub async fn installed_packages(paths: &[&str], db: impl AsRef<Path>) -> Result<Vec<InstalledPackage>> {
+    let (attributes, _) = readsystempkgs(paths, pathsnixostype(paths))?;
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    let mut found = querypackages(&pool, &attributes).await?;
+    let mut out = attributes
+        .into_iter()
+        .map(|attribute| {
+            let package = found.remove(&attribute);
+            InstalledPackage {
+                still_available: package.is_some(),
+                attribute,
+                package,
+            }
+        })
+        .collect::<Vec<_>>();
+    out.sort_by(|a, b| a.attribute.cmp(&b.attribute));
+    Ok(out)
+}
+
+/// Version of a package declared in `environment.systemPackages`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedVersion {
+    /// The version of the package in the channel.
+    pub version: String,
+    /// Whether the package is declared through an override expression such as `(pkgs.foo.override { ... })`
+    /// or `(foo.overrideAttrs (old: { ... }))`. The override may change the version that actually gets built,
+    /// which can't be determined without evaluating the configuration, so `version` is only the channel version.
+    pub overridden: bool,
+}
+
+/// Returns the attribute being overridden by an entry such as `(pkgs.foo.override { ... })`,
+/// `(foo.overrideAttrs (old: { ... }))` or `(foo.overrideDerivation (old: { ... }))`.
+fn overridebase(entry: &str) -> Option<String> {
+    let entry = entry.trim().trim_start_matches('(').trim_start();
+    let head = entry
+        .split(|c: char| c.is_whitespace() || c == '(')
+        .next()?;
+    let base = [".overrideAttrs", ".overrideDerivation", ".override"]
+        .iter()
+        .find_map(|suffix| head.strip_suffix(suffix))?;
+    if isattribute(base) {
+        Some(base.strip_prefix("pkgs.").unwrap_or(base).to_string())
+    } else {
+        None
+    }
+}
+
+async fn pkgsdb(config: &CacheConfig, nixos: NixosType) -> Result<PathBuf> {
+    match nixos {
+        NixosType::Flake => flakes::flakespkgs_with_config(config).await,
+        NixosType::Legacy => channel::legacypkgs_with_config(config).await,
+    }
+}
+
+pub(super) async fn queryversions(
+    pool: &SqlitePool,
+    pkgs: impl IntoIterator<Item = String>,
+) -> Result<HashMap<String, String>> {
+    let pkgs = pkgs.into_iter().collect::<Vec<_>>();
+    let mut rows: HashMap<String, Vec<String>> = HashMap::new();
+    // Stay well below SQLite's limit on the number of bound parameters
+    for chunk in pkgs.chunks(500) {
+        let mut query =
+            QueryBuilder::<Sqlite>::new(r#"SELECT attribute, version FROM pkgs WHERE attribute IN ("#);
+        let mut separated = query.separated(", ");
+        for pkg in chunk {
+            separated.push_bind(pkg.as_str());
+        }
+        separated.push_unseparated(")");
+        for row in query.build().fetch_all(pool).await? {
+            rows.entry(row.get("attribute"))
+                .or_default()
+                .push(row.get("version"));
+        }
+    }
+    // Attributes with several rows (e.g. for several systems) are ambiguous, so they are left out
+    Ok(rows
+        .into_iter()
+        .filter_map(|(pkg, mut versions)| {
+            if versions.len() == 1 {
+                Some((pkg, versions.pop()?))
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
 pub(super) async fn getnixospkgs(
     paths: &[&str],
     nixos: NixosType,
 ) -> Result<HashMap<String, String>> {
-    let pkgs = {
-        let mut allpkgs: HashSet<String> = HashSet::new();
-        for path in paths {
-            if let Ok(filepkgs) = nix_editor::read::getarrvals(
-                &fs::read_to_string(path)?,
-                "environment.systemPackages",
-            ) {
-                let filepkgset = filepkgs
-                    .into_iter()
-                    .map(|x| x.strip_prefix("pkgs.").unwrap_or(&x).to_string())
-                    .collect::<HashSet<_>>();
-                allpkgs = allpkgs.union(&filepkgset).map(|x| x.to_string()).collect();
-            }
+    getnixospkgs_with_config(&CacheConfig::default(), paths, nixos).await
+}
+
+/// Like [getflakepkgs()](super::flakes::getflakepkgs) or [getlegacypkgs()](super::channel::getlegacypkgs),
+/// depending on `nixos`, but caches the package database in the directory given by `config`.
+pub async fn getnixospkgs_with_config(
+    config: &CacheConfig,
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<HashMap<String, String>> {
+    let (pkgs, custom) = readsystempkgs(paths, nixos)?;
+    debug!("getnixospkgs: {:?}", pkgs);
+    debug!("getnixospkgs custom derivations: {:?}", custom);
+    let pkgsdb = pkgsdb(config, nixos).await?;
+    let pkgs = pkgs.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    PackageDb::open(&pkgsdb).await?.lookup(&pkgs).await
+}
+
+/// Like [getflakepkgs()](super::flakes::getflakepkgs) or [getlegacypkgs()](super::channel::getlegacypkgs),
+/// but also returns the file that declares each package, to find which file of a modular configuration to edit.
+/// If a package is declared in several files, the first of them in `paths` is returned.
+pub async fn getnixospkgs_sources(
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<HashMap<String, (String, PathBuf)>> {
+    getnixospkgs_sources_with_config(&CacheConfig::default(), paths, nixos).await
+}
+
+/// Like [getnixospkgs_sources()], but caches the package database in the directory given by `config`.
+pub async fn getnixospkgs_sources_with_config(
+    config: &CacheConfig,
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<HashMap<String, (String, PathBuf)>> {
+    let mut sources = readsources(paths, nixos)?;
+    let pkgsdb = pkgsdb(config, nixos).await?;
+    let pool = connectdb(&pkgsdb).await?;
+    let versions = queryversions(&pool, sources.keys().cloned()).await?;
+    Ok(versions
+        .into_iter()
+        .filter_map(|(pkg, version)| {
+            let source = sources.remove(&pkg)?;
+            Some((pkg, (version, source)))
+        })
+        .collect())
+}
+
+/// Maps each attribute in `environment.systemPackages` of the files in `paths`, normalized for `nixos`,
+/// to the first of them that declares it.
+fn readsources(paths: &[&str], nixos: NixosType) -> Result<HashMap<String, PathBuf>> {
+    let mut sources = HashMap::new();
+    for path in paths {
+        let (pkgs, _) = readsystempkgs(&[path], nixos)?;
+        for pkg in pkgs {
+            sources.entry(pkg).or_insert_with(|| PathBuf::from(path));
         }
-        allpkgs
+    }
+    Ok(sources)
+}
+
+/// Installed packages of a NixOS system, as returned by [getnixospkgs_with_users()].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SystemAndUserPkgs {
+    /// Attributes and versions of the packages in `environment.systemPackages`.
+    pub system: HashMap<String, String>,
+    /// Attributes and versions of the packages in `users.users.<name>.packages`, keyed by user name.
+    pub users: HashMap<String, HashMap<String, String>>,
+}
+
+/// Like [getflakepkgs()](super::flakes::getflakepkgs) or [getlegacypkgs()](super::channel::getlegacypkgs),
+/// depending on `nixos`, but also returns the packages installed for each user with `users.users.<name>.packages`.
+/// Users can be declared either as `users.users.alice.packages = ...` or inside a `users.users = { ... }` set.
+pub async fn getnixospkgs_with_users(
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<SystemAndUserPkgs> {
+    getnixospkgs_with_users_with_config(&CacheConfig::default(), paths, nixos).await
+}
+
+/// Like [getnixospkgs_with_users()], but caches the package database in the directory given by `config`.
+pub async fn getnixospkgs_with_users_with_config(
+    config: &CacheConfig,
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<SystemAndUserPkgs> {
+    let (systempkgs, _) = readsystempkgs(paths, nixos)?;
+    let userpkgs = readuserpkgs(paths, nixos)?;
+    debug!("getnixospkgs_with_users: {:?}", userpkgs);
+    let pkgsdb = pkgsdb(config, nixos).await?;
+    let mut pkgs = systempkgs.iter().map(|x| x.as_str()).collect::<HashSet<_>>();
+    pkgs.extend(userpkgs.values().flatten().map(|x| x.as_str()));
+    let versions = PackageDb::open(&pkgsdb)
+        .await?
+        .lookup(&pkgs.into_iter().collect::<Vec<_>>())
+        .await?;
+    let resolve = |attrs: &HashSet<String>| {
+        attrs
+            .iter()
+            .filter_map(|x| Some((x.to_string(), versions.get(x)?.to_string())))
+            .collect::<HashMap<_, _>>()
     };
-    debug!("getnixospkgs: {:?}", pkgs);
-    let pkgsdb = match nixos {
-        NixosType::Flake => flakes::flakespkgs().await?,
-        NixosType::Legacy => channel::legacypkgs().await?,
+    Ok(SystemAndUserPkgs {
+        system: resolve(&systempkgs),
+        users: userpkgs
+            .iter()
+            .map(|(user, attrs)| (user.to_string(), resolve(attrs)))
+            .collect(),
+    })
+}
+
+/// Returns a list of all packages in `home.packages` of the home-manager configuration files in `paths`
+/// (such as `~/.config/home-manager/home.nix`) with their attribute and version.
+///
+/// Entries are handled like those of `environment.systemPackages`: both `pkgs.firefox` and `firefox`
+/// (as written inside `with pkgs; [ ... ]`) resolve to `firefox`, and custom derivations are skipped.
+/// Versions are looked up in the same package database as system packages, chosen by `nixos`.
+pub async fn gethomepkgs(paths: &[&str], nixos: NixosType) -> Result<HashMap<String, String>> {
+    gethomepkgs_with_config(&CacheConfig::default(), paths, nixos).await
+}
+
+/// Like [gethomepkgs()], but caches the package database in the directory given by `config`.
+pub async fn gethomepkgs_with_config(
+    config: &CacheConfig,
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<HashMap<String, String>> {
+    let (pkgs, custom) = readpkglist(paths, "home.packages", nixos)?;
+    debug!("gethomepkgs: {:?}", pkgs);
+    debug!("gethomepkgs custom derivations: {:?}", custom);
+    let pkgsdb = pkgsdb(config, nixos).await?;
+    let pool = connectdb(&pkgsdb).await?;
+    queryversions(&pool, pkgs).await
+}
+
+/// Like [getflakepkgs()](super::flakes::getflakepkgs) or [getlegacypkgs()](super::channel::getlegacypkgs),
+/// but returns a [NixPackage] with the package's details for each installed attribute instead of only its version.
+///
+/// Versions are those of the running system, while the remaining details are joined from the `pkgs` and `meta` tables
+/// of the package database at `db`, such as the one returned by [nixospkgs()]. Nothing is downloaded, so `db` should
+/// already be cached. Packages missing from that database only have their attribute and version filled in.
+pub async fn getnixospkgs_detailed(
+    paths: &[&str],
+    nixos: NixosType,
+    db: impl AsRef<Path>,
+) -> Result<HashMap<String, NixPackage>> {
+    let versions = getnixospkgs(paths, nixos).await?;
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    let mut details = querypackages(&pool, versions.keys()).await?;
+    Ok(versions
+        .into_iter()
+        .map(|(attribute, version)| {
+            let pkg = details.remove(&attribute).unwrap_or_else(|| NixPackage {
+                attribute: attribute.clone(),
+                ..Default::default()
+            });
+            (attribute, NixPackage { version, ..pkg })
+        })
+        .collect())
+}
+
+/// Gets the channel version of every package in `environment.systemPackages` of the files in `paths`.
+///
+/// Unlike [getflakepkgs()](super::flakes::getflakepkgs) and [getlegacypkgs()](super::channel::getlegacypkgs),
+/// packages declared through an override expression (e.g. `(pkgs.foo.override { ... })`) are included under
+/// the overridden attribute, flagged with [overridden](ResolvedVersion::overridden).
+/// This crate only knows the version in the channel, not the result of a local override,
+/// so for those packages the reported version may not be the one that gets built.
+/// Other custom derivations (see [getcustompkgs()]) are not included.
+pub async fn resolve_versions(
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<HashMap<String, ResolvedVersion>> {
+    resolve_versions_with_config(&CacheConfig::default(), paths, nixos).await
+}
+
+/// Like [resolve_versions()], but caches the package database in the directory given by `config`.
+pub async fn resolve_versions_with_config(
+    config: &CacheConfig,
+    paths: &[&str],
+    nixos: NixosType,
+) -> Result<HashMap<String, ResolvedVersion>> {
+    let (pkgs, custom) = readsystempkgs(paths, nixos)?;
+    let overridden = custom
+        .iter()
+        .filter_map(|x| overridebase(x))
+        .collect::<HashSet<_>>();
+    let pkgsdb = pkgsdb(config, nixos).await?;
+    let pool = connectdb(&pkgsdb).await?;
+    let versions = queryversions(&pool, pkgs.union(&overridden).cloned()).await?;
+    Ok(versions
+        .into_iter()
+        .map(|(pkg, version)| {
+            // Plain declarations take precedence if a package is declared both ways
+            let overridden = overridden.contains(&pkg) && !pkgs.contains(&pkg);
+            (
+                pkg,
+                ResolvedVersion {
+                    version,
+                    overridden,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Builds a package database named `name` (e.g. `flakespkgs`) in the directory given by `config`
+/// from a map of attribute to version, replacing any existing one. Returns the path to the database.
+/// Fails without touching the existing database if `pkgjson` contains no valid packages.
+/// The database contains a single `pkgs` table with the `attribute` and `version` of each package, and `pname` and `system` columns left empty.
+pub async fn createdb_with_config(
+    config: &CacheConfig,
+    name: &str,
+    pkgjson: &HashMap<String, String>,
+) -> Result<PathBuf> {
+    config.createdir()?;
+    let dbfile = config.file(&format!("{}.db", name));
+    createdb(Path::new(&dbfile), pkgjson).await?;
+    Ok(dbfile.into())
+}
+
+/// Number of packages written to a package database, as returned by [createdb_in()] and
+/// [build_db_from_search_json()](super::flakes::build_db_from_search_json). Within the crate, `createdb`,
+/// `createdbwithpnames` and `updatepkgs` return it too, whether they build a new database or update an existing one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DbImportStats {
+    /// Packages stored in the database.
+    pub inserted: usize,
+    /// Malformed entries that were left out, such as ones with an empty attribute or version.
+    pub skipped: usize,
+}
+
+/// Builds a package database at `dbfile` from `pkgjson`, mapping attributes to versions.
+/// The database is built in a temporary file and only replaces `dbfile` once the import succeeded,
+/// so a failed import leaves the previous database in place.
+/// An import that stores no packages, or fewer than were parsed, counts as failed.
+/// If `dbfile` was built with the current layout, only the packages that changed are written (see [updatepkgsdb()]).
+pub(super) async fn createdb(
+    dbfile: &Path,
+    pkgjson: &HashMap<String, String>,
+) -> Result<DbImportStats> {
+    let pkgs = pkgjson
+        .iter()
+        .map(|(pkg, version)| (pkg.as_str(), None, version.as_str(), None))
+        .collect::<Vec<_>>();
+    createpkgsdb(dbfile, pkgs).await
+}
+
+/// Like [createdb()], but also stores the `pname` and `system` of each package. Packages without a `system` store NULL.
+pub(super) async fn createdbwithpnames(
+    dbfile: &Path,
+    pkgjson: &HashMap<String, NixPkg>,
+) -> Result<DbImportStats> {
+    let pkgs = pkgjson
+        .iter()
+        .map(|(pkg, x)| {
+            (
+                pkg.as_str(),
+                Some(x.pname.as_str()),
+                x.version.as_str(),
+                x.system.as_deref(),
+            )
+        })
+        .collect::<Vec<_>>();
+    createpkgsdb(dbfile, pkgs).await
+}
+
+/// Layout of the databases built by [filldb()], stored as `schema` in `meta_info`.
+/// Needs to be bumped whenever the layout changes, so that existing databases are rebuilt rather than updated.
+const PKGSDBSCHEMA: &str = "2";
+
+async fn createpkgsdb(
+    dbfile: &Path,
+    pkgs: Vec<(&str, Option<&str>, &str, Option<&str>)>,
+) -> Result<DbImportStats> {
+    let tmpfile = withsuffix(dbfile, ".tmp");
+    let _lock = lockfile(dbfile).await?;
+    let result = if updatable(dbfile).await {
+        updatepkgsdb(dbfile, &tmpfile, pkgs).await
+    } else {
+        builddb(&tmpfile, pkgs).await
     };
-    let mut out = HashMap::new();
-    let pool = SqlitePool::connect(&format!("sqlite://{}", pkgsdb)).await?;
-    for pkg in pkgs {
-        let mut sqlout = sqlx::query(
-            r#"
-            SELECT version FROM pkgs WHERE attribute = $1
-            "#,
-        )
-        .bind(&pkg)
-        .fetch_all(&pool)
-        .await?;
-        if sqlout.len() == 1 {
-            let row = sqlout.pop().unwrap();
-            let version: String = row.get("version");
-            out.insert(pkg, version);
+    match result {
+        Ok(stats) => {
+            replacedb(&tmpfile, dbfile)?;
+            debug!("Inserted {} packages into {}", stats.inserted, dbfile.display());
+            Ok(stats)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&tmpfile);
+            Err(e)
         }
     }
-    Ok(out)
 }
 
-pub(super) async fn createdb(dbfile: &str, pkgjson: &HashMap<String, String>) -> Result<()> {
-    let db = format!("sqlite://{}", dbfile);
-    if Path::new(dbfile).exists() {
+/// Whether the existing database at `dbfile` has the current layout, so that it can be updated rather than rebuilt.
+async fn updatable(dbfile: &Path) -> bool {
+    if !dbfile.exists() {
+        return false;
+    }
+    let schema = async {
+        let pool = connectdb(dbfile).await?;
+        let schema = getmetainfo(&pool, "schema").await;
+        pool.close().await;
+        schema
+    };
+    matches!(schema.await, Ok(Some(x)) if x == PKGSDBSCHEMA)
+}
+
+/// Updates a copy of the existing database at `dbfile` at `tmpfile` to hold `pkgjson`, only touching the rows
+/// of packages that were added, removed or changed. Unchanged rows keep their identity, and much less is written
+/// than when building the database from scratch, as channel updates change few packages.
+async fn updatepkgsdb(
+    dbfile: &Path,
+    tmpfile: &Path,
+    pkgjson: Vec<(&str, Option<&str>, &str, Option<&str>)>,
+) -> Result<DbImportStats> {
+    fs::copy(dbfile, tmpfile)?;
+    let pool = connecttmp(tmpfile).await?;
+    let stats = updatepkgs(&pool, pkgjson).await;
+    closetmp(pool).await?;
+    let stats = stats?;
+    if stats.skipped > 0 {
+        warn!(
+            "Skipped {} malformed packages while updating {}",
+            stats.skipped,
+            dbfile.display()
+        );
+    }
+    Ok(stats)
+}
+
+async fn updatepkgs(pool: &SqlitePool, pkgjson: Vec<(&str, Option<&str>, &str, Option<&str>)>) -> Result<DbImportStats> {
+    let total = pkgjson.len();
+    let pkgs = pkgjson
+        .into_iter()
+        .filter(|(pkg, _, version, _)| !pkg.trim().is_empty() && !version.trim().is_empty())
+        .collect::<Vec<_>>();
+    let stats = DbImportStats {
+        inserted: pkgs.len(),
+        skipped: total - pkgs.len(),
+    };
+    let existing = sqlx::query_as::<_, (String, Option<String>, Option<String>, Option<String>)>(
+        r#"SELECT attribute, pname, version, system FROM pkgs"#,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|(attribute, pname, version, system)| (attribute, (pname, version, system)))
+    .collect::<HashMap<_, _>>();
+    let new = pkgs.iter().map(|(pkg, _, _, _)| *pkg).collect::<HashSet<_>>();
+    let removed = existing
+        .keys()
+        .filter(|x| !new.contains(x.as_str()))
+        .collect::<Vec<_>>();
+    let mut added = vec![];
+    let mut changed = vec![];
+    for pkg in &pkgs {
+        let (attribute, pname, version, system) = pkg;
+        match existing.get(*attribute) {
+            None => added.push(pkg),
+            Some((oldpname, oldversion, oldsystem))
+                if oldpname.as_deref() != *pname
+                    || oldversion.as_deref() != Some(*version)
+                    || oldsystem.as_deref() != *system =>
+            {
+                changed.push(pkg)
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+    for chunk in removed.chunks(500) {
+        let mut query = QueryBuilder::<Sqlite>::new(r#"DELETE FROM "pkgs" WHERE "attribute" IN ("#);
+        let mut separated = query.separated(", ");
+        for attribute in chunk {
+            separated.push_bind(attribute.as_str());
+        }
+        separated.push_unseparated(")");
+        query.build().execute(&mut tx).await?;
+    }
+    for (attribute, pname, version, system) in &changed {
+        sqlx::query(r#"UPDATE "pkgs" SET "pname" = $1, "version" = $2, "system" = $3 WHERE "attribute" = $4"#)
+            .bind(*pname)
+            .bind(*version)
+            .bind(*system)
+            .bind(*attribute)
+            .execute(&mut tx)
+            .await?;
+    }
+    for chunk in added.chunks(1000) {
+        let mut query =
+            QueryBuilder::<Sqlite>::new(r#"INSERT INTO "pkgs" ("attribute", "pname", "version", "system") "#);
+        query.push_values(chunk, |mut row, (pkg, pname, version, system)| {
+            row.push_bind(*pkg).push_bind(*pname).push_bind(*version).push_bind(*system);
+        });
+        query.build().execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+    debug!(
+        "Added {}, removed {} and changed {} packages",
+        added.len(),
+        removed.len(),
+        changed.len()
+    );
+    checkpkgcount(pool, stats.inserted).await?;
+    // Of `meta_info`, only the build time changes, callers overwrite whatever else they record
+    setbuilttime(pool).await?;
+    Ok(stats)
+}
+
+/// Checks the built database in `pool` is usable and holds `expected` packages, before callers mark its version as current.
+async fn checkpkgcount(pool: &SqlitePool, expected: usize) -> Result<()> {
+    let (count,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM pkgs"#)
+        .fetch_one(pool)
+        .await?;
+    if count == 0 {
+        return Err(NixDataError::Other(String::from(
+            "Built package database is empty",
+        )));
+    }
+    if count as usize != expected {
+        return Err(NixDataError::Other(format!(
+            "Built package database has {} packages, expected {}",
+            count, expected
+        )));
+    }
+    Ok(())
+}
+
+async fn builddb(dbfile: &Path, pkgjson: Vec<(&str, Option<&str>, &str, Option<&str>)>) -> Result<DbImportStats> {
+    if dbfile.exists() {
         fs::remove_file(dbfile)?;
     }
-    Sqlite::create_database(&db).await?;
-    let pool = SqlitePool::connect(&db).await?;
+    let pool = connecttmp(dbfile).await?;
+    let stats = filldb(&pool, pkgjson).await;
+    closetmp(pool).await?;
+    let stats = stats?;
+    if stats.skipped > 0 {
+        warn!(
+            "Skipped {} malformed packages while building {}",
+            stats.skipped,
+            dbfile.display()
+        );
+    }
+    Ok(stats)
+}
+
+/// Builds a package database from a map of attribute to version in the empty database `pool`,
+/// rather than in a file. This allows building a database entirely in memory, for example for tests:
+/// ```no_run
+/// # async fn example() -> nix_data::error::Result<()> {
+/// use nix_data::cache::{nixos::createdb_in, query::PackageDb};
+/// use sqlx::sqlite::SqlitePoolOptions;
+/// use std::collections::HashMap;
+///
+/// // Every connection to `sqlite::memory:` opens a separate database, so the pool must only hold one
+/// let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+/// let pkgs = HashMap::from([(String::from("hello"), String::from("2.12.1"))]);
+/// createdb_in(&pool, &pkgs).await?;
+/// let versions = PackageDb::from_pool(pool).lookup(&["hello"]).await?;
+/// # Ok(())
+/// # }
+/// ```
+/// Fails if `pkgjson` contains no valid packages. The database is laid out as by [createdb_with_config()].
+pub async fn createdb_in(pool: &SqlitePool, pkgjson: &HashMap<String, String>) -> Result<DbImportStats> {
+    let pkgs = pkgjson
+        .iter()
+        .map(|(pkg, version)| (pkg.as_str(), None, version.as_str(), None))
+        .collect::<Vec<_>>();
+    filldb(pool, pkgs).await
+}
+
+/// Creates the `pkgs` table in the empty database `pool` and inserts `pkgjson` into it,
+/// checking every valid package was stored.
+async fn filldb(pool: &SqlitePool, pkgjson: Vec<(&str, Option<&str>, &str, Option<&str>)>) -> Result<DbImportStats> {
     sqlx::query(
         r#"
             CREATE TABLE "pkgs" (
                 "attribute"	TEXT NOT NULL UNIQUE,
+                "pname"	TEXT,
                 "version"	TEXT,
+                "system"	TEXT,
                 PRIMARY KEY("attribute")
             )
             "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     sqlx::query(
         r#"
         CREATE UNIQUE INDEX "attributes" ON "pkgs" ("attribute")
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
-    // sqlx::query(
-    //     r#"
-    //     CREATE INDEX "pnames" ON "pkgs" ("attribute")
-    //     "#,
-    // )
-    // .execute(&pool)
-    // .await?;
-
-    let mut wtr = csv::Writer::from_writer(vec![]);
-    for (pkg, version) in pkgjson {
-        wtr.serialize((pkg.to_string(), version.to_string()))?;
-    }
-    let data = String::from_utf8(wtr.into_inner()?)?;
-    let mut cmd = Command::new("sqlite3")
-        .arg("-csv")
-        .arg(&dbfile)
-        .arg(".import '|cat -' pkgs")
-        .stdin(Stdio::piped())
-        .spawn()?;
-    let cmd_stdin = cmd.stdin.as_mut().unwrap();
-    cmd_stdin.write_all(data.as_bytes())?;
-    let _status = cmd.wait()?;
-    Ok(())
+    sqlx::query(
+        r#"
+        CREATE INDEX "pnames" ON "pkgs" ("pname")
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    let total = pkgjson.len();
+    let pkgs = pkgjson
+        .into_iter()
+        .filter(|(pkg, _, version, _)| !pkg.trim().is_empty() && !version.trim().is_empty())
+        .collect::<Vec<_>>();
+    let mut stats = DbImportStats {
+        inserted: 0,
+        skipped: total - pkgs.len(),
+    };
+    let mut tx = pool.begin().await?;
+    for chunk in pkgs.chunks(1000) {
+        let mut query =
+            QueryBuilder::<Sqlite>::new(r#"INSERT INTO "pkgs" ("attribute", "pname", "version", "system") "#);
+        query.push_values(chunk, |mut row, (pkg, pname, version, system)| {
+            row.push_bind(*pkg).push_bind(*pname).push_bind(*version).push_bind(*system);
+        });
+        stats.inserted += query.build().execute(&mut tx).await?.rows_affected() as usize;
+    }
+    if stats.inserted < pkgs.len() {
+        return Err(NixDataError::Other(format!(
+            "Only {} of {} packages were inserted into the package database",
+            stats.inserted,
+            pkgs.len()
+        )));
+    }
+    tx.commit().await?;
+    checkpkgcount(pool, pkgs.len()).await?;
+    setbuilttime(pool).await?;
+    setmetainfo(pool, "schema", PKGSDBSCHEMA).await?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{
+        getmetainfo, query::db_version, testconfig, testdir, testpkgsdb, testserver,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Brotli compresses `bytes`, as the nix-data databases are served.
+    fn brotli(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        brotli::BrotliCompress(&mut &bytes[..], &mut out, &Default::default()).unwrap();
+        out
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn download_on_current_thread_runtime() {
+        let dir = testdir("current-thread");
+        let src = dir.join("src.db");
+        testpkgsdb(&src, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let body = brotli(&fs::read(&src).unwrap());
+        let url = testserver(move |_, path| match path {
+            "/nixos-unstable/nixpkgs.db.br" => (200, vec![], body.clone()),
+            _ => (404, vec![], vec![]),
+        });
+
+        // The server runs on its own thread, so only blocking work on the runtime's single thread could hang this
+        let config = testconfig(&dir);
+        let client = reqwest::Client::new();
+        let url = format!("{}/nixos-unstable/nixpkgs.db.br", url);
+        tokio::time::timeout(
+            Duration::from_secs(30),
+            downloaddb(&config, &client, &url, "23.11.1", None, &|_, _, _| {}, &CancellationToken::new()),
+        )
+        .await
+        .expect("download blocked the runtime")
+        .unwrap();
+
+        let pool = SqlitePool::connect(&format!("sqlite://{}", config.file("nixospkgs.db"))).await.unwrap();
+        let (version,): (String,) = sqlx::query_as(r#"SELECT version FROM pkgs WHERE attribute = 'hello'"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(version, "2.12");
+        assert_eq!(getmetainfo(&pool, "system").await.unwrap(), Some(hostsystem()));
+    }
+
+    #[tokio::test]
+    async fn not_modified_updates_version_and_validators() {
+        let dir = testdir("not-modified");
+        fs::write(dir.join("nixospkgs.db"), "cached").unwrap();
+        fs::write(dir.join("nixospkgs.ver"), "23.05.1").unwrap();
+        fs::write(dir.join("nixospkgs.validators"), "ETag: \"v1\"\nLast-Modified: Mon, 01 May 2023 00:00:00 GMT\n").unwrap();
+        let url = testserver(|_, _| (304, vec![("ETag", String::from("\"v2\""))], vec![]));
+
+        let outcome = updatedb(
+            &testconfig(&dir),
+            &reqwest::Client::new(),
+            &format!("{}/nixpkgs.db.br", url),
+            "23.05.2",
+            |_, _, _| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome.path, dir.join("nixospkgs.db"));
+        assert!(!outcome.downloaded);
+        assert_eq!(fs::read_to_string(dir.join("nixospkgs.db")).unwrap(), "cached");
+        assert_eq!(fs::read_to_string(dir.join("nixospkgs.ver")).unwrap(), "23.05.2");
+        // The new ETag replaces the old one, the Last-Modified the server left out is kept
+        assert_eq!(
+            readvalidators(dir.join("nixospkgs.validators").to_str().unwrap()),
+            Some(Validators {
+                etag: Some(String::from("\"v2\"")),
+                lastmodified: Some(String::from("Mon, 01 May 2023 00:00:00 GMT")),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn db_version_matches_ver() {
+        let dir = testdir("db-version");
+        let src = dir.join("src.db");
+        testpkgsdb(&src, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let body = brotli(&fs::read(&src).unwrap());
+        let url = testserver(move |_, path| match path {
+            "/nixpkgs.db.br" => (200, vec![], body.clone()),
+            _ => (404, vec![], vec![]),
+        });
+
+        let outcome = updatedb(
+            &testconfig(&dir),
+            &reqwest::Client::new(),
+            &format!("{}/nixpkgs.db.br", url),
+            "23.11.1",
+            |_, _, _| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert!(outcome.downloaded);
+        let ver = fs::read_to_string(dir.join("nixospkgs.ver")).unwrap();
+        assert_eq!(db_version(&outcome.path).await.unwrap(), Some(ver));
+    }
+
+    #[tokio::test]
+    async fn systems_do_not_collide() {
+        let dir = testdir("systems");
+        let other = if hostsystem() == "aarch64-linux" { "x86_64-linux" } else { "aarch64-linux" };
+        let configs = [
+            testconfig(&dir),
+            CacheConfig {
+                system: Some(String::from(other)),
+                ..testconfig(&dir)
+            },
+        ];
+        for (config, version) in configs.iter().zip(["1.0", "2.0"]) {
+            let src = dir.join(format!("src-{}.db", version));
+            testpkgsdb(&src, &[("hello", "hello", version, "Greeting")]).await.close().await;
+            let body = brotli(&fs::read(&src).unwrap());
+            let url = testserver(move |_, path| match path {
+                "/nixpkgs.db.br" => (200, vec![], body.clone()),
+                _ => (404, vec![], vec![]),
+            });
+            updatedb(
+                config,
+                &reqwest::Client::new(),
+                &format!("{}/nixpkgs.db.br", url),
+                version,
+                |_, _, _| {},
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        }
+        let dbs = [
+            (String::from("nixospkgs"), hostsystem(), "1.0"),
+            (format!("nixospkgs-{}", other), String::from(other), "2.0"),
+        ];
+        for (name, system, version) in dbs {
+            assert_eq!(fs::read_to_string(dir.join(format!("{}.ver", name))).unwrap(), version);
+            let pool = SqlitePool::connect(&format!("sqlite://{}", dir.join(format!("{}.db", name)).display()))
+                .await
+                .unwrap();
+            assert_eq!(getmetainfo(&pool, "system").await.unwrap(), Some(system));
+            let versions = queryversions(&pool, [String::from("hello")]).await.unwrap();
+            assert_eq!(versions["hello"], version, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn configs_do_not_collide() {
+        let dirs = [testdir("config-a"), testdir("config-b")];
+        for (dir, version) in dirs.iter().zip(["1.0", "2.0"]) {
+            let config = testconfig(dir);
+            let pkgs = HashMap::from([(String::from("hello"), String::from(version))]);
+            createdb_with_config(&config, "flakespkgs", &pkgs).await.unwrap();
+            let src = dir.join("src.db");
+            testpkgsdb(&src, &[("hello", "hello", version, "Greeting")]).await.close().await;
+            let body = brotli(&fs::read(&src).unwrap());
+            let url = testserver(move |_, path| match path {
+                "/nixpkgs.db.br" => (200, vec![], body.clone()),
+                _ => (404, vec![], vec![]),
+            });
+            updatedb(
+                &config,
+                &reqwest::Client::new(),
+                &format!("{}/nixpkgs.db.br", url),
+                version,
+                |_, _, _| {},
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        }
+        for (dir, version) in dirs.iter().zip(["1.0", "2.0"]) {
+            assert_eq!(fs::read_to_string(dir.join("nixospkgs.ver")).unwrap(), version);
+            for db in ["flakespkgs.db", "nixospkgs.db"] {
+                let pool = SqlitePool::connect(&format!("sqlite://{}", dir.join(db).display())).await.unwrap();
+                let versions = queryversions(&pool, [String::from("hello")]).await.unwrap();
+                assert_eq!(versions["hello"], version, "{}", db);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn home_packages() {
+        let dir = testdir("home-packages");
+        let home = dir.join("home.nix");
+        fs::write(
+            &home,
+            r#"{ config, pkgs, ... }:
+{
+  home.username = "user";
+  home.packages = with pkgs; [
+    firefox
+    pkgs.ripgrep
+    (pkgs.hello.override { })
+  ];
+  environment.systemPackages = [ pkgs.git ];
+}
+"#,
+        )
+        .unwrap();
+        let (pkgs, custom) = readpkglist(&[home.to_str().unwrap()], "home.packages", NixosType::Legacy).unwrap();
+        assert_eq!(pkgs, HashSet::from([String::from("firefox"), String::from("ripgrep")]));
+        assert_eq!(custom, HashSet::from([String::from("(pkgs.hello.override { })")]));
+
+        let db = dir.join("pkgs.db");
+        let versions = HashMap::from([
+            (String::from("firefox"), String::from("118.0")),
+            (String::from("ripgrep"), String::from("13.0.0")),
+            (String::from("git"), String::from("2.42.0")),
+        ]);
+        createdb(&db, &versions).await.unwrap();
+        let pool = SqlitePool::connect(&format!("sqlite://{}", db.display())).await.unwrap();
+        let found = queryversions(&pool, pkgs).await.unwrap();
+        assert_eq!(
+            found,
+            HashMap::from([
+                (String::from("firefox"), String::from("118.0")),
+                (String::from("ripgrep"), String::from("13.0.0")),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn createdb_skips_malformed() {
+        let json = r#"{"version":2,"packages":{
+            "hello":{"name":"hello-2.12","pname":"hello","version":"2.12"},
+            "broken":{"name":"broken","pname":"broken","version":""},
+            "git":{"name":"git-2.42.0","pname":"git","version":"2.42.0"}
+        }}"#;
+        let mut pkgs = HashMap::new();
+        crate::cache::streampackages(json.as_bytes(), |attribute, pkg| {
+            pkgs.insert(attribute, pkg.version.to_string());
+        })
+        .unwrap();
+        let db = testdir("createdb-malformed").join("pkgs.db");
+        let stats = createdb(&db, &pkgs).await.unwrap();
+        assert_eq!(stats, DbImportStats { inserted: 2, skipped: 1 });
+        let pool = SqlitePool::connect(&format!("sqlite://{}", db.display())).await.unwrap();
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM pkgs").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn detect_flake_or_legacy() {
+        let dir = testdir("detect-nixos-type");
+        let registry = dir.join("registry.json");
+        let registry = registry.to_str().unwrap();
+        let flake = dir.join("flake");
+        let legacy = dir.join("legacy");
+        let empty = dir.join("empty");
+        for sub in [&flake, &legacy, &empty] {
+            fs::create_dir(sub).unwrap();
+        }
+        fs::write(flake.join("flake.nix"), "{ }").unwrap();
+        fs::write(legacy.join("configuration.nix"), "{ }").unwrap();
+
+        // Without a registry pinning nixpkgs, only a flake.nix makes a flake system
+        assert_eq!(detectnixostype(&flake, registry), NixosType::Flake);
+        assert_eq!(detectnixostype(&legacy, registry), NixosType::Legacy);
+        assert_eq!(detectnixostype(&empty, registry), NixosType::Legacy);
+        assert_eq!(detectnixostype(&dir.join("missing"), registry), NixosType::Legacy);
+
+        fs::write(
+            registry,
+            r#"{"version":2,"flakes":[{"from":{"id":"nixpkgs","type":"indirect"},"to":{"path":"/nix/store/abc-source","type":"path"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(detectnixostype(&flake, registry), NixosType::Flake);
+        assert_eq!(detectnixostype(&legacy, registry), NixosType::Legacy);
+        // An empty config dir with a pinned registry is a flake system configured elsewhere
+        assert_eq!(detectnixostype(&empty, registry), NixosType::Flake);
+        assert_eq!(detectnixostype(&dir.join("missing"), registry), NixosType::Legacy);
+    }
+
+    #[test]
+    fn sources_of_two_files() {
+        let dir = testdir("pkgs-sources");
+        let base = dir.join("configuration.nix");
+        let desktop = dir.join("desktop.nix");
+        fs::write(&base, "{ pkgs, ... }: { environment.systemPackages = with pkgs; [ git vim ]; }").unwrap();
+        fs::write(
+            &desktop,
+            "{ pkgs, ... }: { environment.systemPackages = [ pkgs.firefox pkgs.mpv pkgs.git ]; }",
+        )
+        .unwrap();
+        let sources = readsources(&[base.to_str().unwrap(), desktop.to_str().unwrap()], NixosType::Legacy).unwrap();
+        assert_eq!(
+            sources,
+            HashMap::from([
+                (String::from("git"), base.clone()),
+                (String::from("vim"), base.clone()),
+                (String::from("firefox"), desktop.clone()),
+                (String::from("mpv"), desktop.clone()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn installed_and_removed() {
+        let dir = testdir("installed-packages");
+        let config = dir.join("configuration.nix");
+        fs::write(&config, "{ pkgs, ... }: { environment.systemPackages = with pkgs; [ removed hello ]; }").unwrap();
+        let db = dir.join("pkgs.db");
+        testpkgsdb(&db, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let installed = installed_packages(&[config.to_str().unwrap()], &db).await.unwrap();
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed[0].attribute, "hello");
+        assert!(installed[0].still_available);
+        assert_eq!(installed[0].package.as_ref().unwrap().version, "2.12");
+        assert_eq!(
+            installed[1],
+            InstalledPackage {
+                attribute: String::from("removed"),
+                package: None,
+                still_available: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn prefixed_and_bare_entries_match() {
+        for entry in ["firefox", "pkgs.firefox", "(lib.hiPrio pkgs.firefox)", " (pkgs.lowPrio firefox) "] {
+            let normalized = normalize_attribute(entry, NixosType::Legacy);
+            assert_eq!(normalized.as_deref(), Some("firefox"), "{}", entry);
+        }
+        for entry in [
+            "inputs.nixpkgs.legacyPackages.${system}.firefox",
+            "nixpkgs.legacyPackages.x86_64-linux.firefox",
+        ] {
+            let normalized = normalize_attribute(entry, NixosType::Flake);
+            assert_eq!(normalized.as_deref(), Some("firefox"), "{}", entry);
+        }
+        assert_eq!(
+            normalize_attribute("pkgs.python3Packages.requests", NixosType::Legacy).as_deref(),
+            Some("python3Packages.requests")
+        );
+        assert_eq!(normalize_attribute("(pkgs.callPackage ./foo.nix { })", NixosType::Legacy), None);
+
+        let dir = testdir("normalized-entries");
+        let base = dir.join("configuration.nix");
+        let desktop = dir.join("desktop.nix");
+        fs::write(&base, "{ pkgs, ... }: { environment.systemPackages = with pkgs; [ firefox ]; }").unwrap();
+        fs::write(&desktop, "{ pkgs, ... }: { environment.systemPackages = [ pkgs.firefox ]; }").unwrap();
+        let db = dir.join("pkgs.db");
+        testpkgsdb(&db, &[("firefox", "firefox", "120.0", "Web browser")]).await.close().await;
+        let installed = installed_packages(&[base.to_str().unwrap(), desktop.to_str().unwrap()], &db)
+            .await
+            .unwrap();
+        assert_eq!(installed.len(), 1);
+        assert_eq!(installed[0].attribute, "firefox");
+        assert_eq!(installed[0].package.as_ref().unwrap().version, "120.0");
+    }
+
+    #[test]
+    fn nixos_version() {
+        assert_eq!(parsenixosversion("23.05.1234.abcdef (Tapir)\n").unwrap(), "23.05");
+        assert_eq!(parsenixosversion("23.11pre530470.abcdef (Tapir)").unwrap(), "23.11");
+        assert!(matches!(parsenixosversion("oops"), Err(NixDataError::Parse(_))));
+        assert!(matches!(parsenixosversion(""), Err(NixDataError::Parse(_))));
+        assert!(matches!(parsenixosversion("2é.05"), Err(NixDataError::Parse(_))));
+    }
+
+    #[tokio::test]
+    async fn resolve_unstable_and_release() {
+        let url = testserver(|_, path| match path {
+            "/nixos-unstable" => (302, vec![("Location", String::from("/nixos-23.11pre530470.abcdef"))], vec![]),
+            _ => (200, vec![], vec![]),
+        });
+        let unstableurl = format!("{}/nixos-unstable", url);
+        let client = reqwest::Client::new();
+        assert_eq!(resolvechannel(&client, &unstableurl, "23.11").await, "unstable");
+        assert_eq!(resolvechannel(&client, &unstableurl, "23.05").await, "23.05");
+    }
+
+    #[tokio::test]
+    async fn sync_resolves_release_once() {
+        let dir = testdir("sync-all");
+        let src = dir.join("src.db");
+        testpkgsdb(&src, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let db = brotli(&fs::read(&src).unwrap());
+        let options = brotli(br#"{"networking.hostName": {"type": "string"}}"#);
+        let redirects = Arc::new(AtomicUsize::new(0));
+        let counter = redirects.clone();
+        let url = testserver(move |_, path| match path {
+            "/nixos-23.05" => {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (302, vec![("Location", String::from("/nixos/23.05/nixos-23.05.1234.abcdef"))], vec![])
+            }
+            "/nixos/23.05/nixos-23.05.1234.abcdef" => (200, vec![], vec![]),
+            "/nixos/23.05/nixos-23.05.1234.abcdef/options.json.br" => {
+                (200, vec![("Content-Encoding", String::from("br"))], options.clone())
+            }
+            "/nixpkgs.db.br" => (200, vec![], db.clone()),
+            _ => (404, vec![], vec![]),
+        });
+
+        let client = reqwest::Client::builder().brotli(true).build().unwrap();
+        let (pkgs, options) = syncrelease(
+            &testconfig(&dir),
+            &client,
+            &format!("{}/nixos-23.05", url),
+            &format!("{}/nixpkgs.db.br", url),
+        )
+        .await
+        .unwrap();
+        assert!(Path::new(&pkgs).exists());
+        assert!(fs::read_to_string(options).unwrap().contains("networking.hostName"));
+        assert_eq!(redirects.load(Ordering::SeqCst), 1);
+        // Both are recorded as the release the channel redirected to
+        assert_eq!(fs::read_to_string(dir.join("nixospkgs.ver")).unwrap(), "23.05.1234.abcdef");
+        assert_eq!(fs::read_to_string(dir.join("nixosoptions.ver")).unwrap(), "23.05.1234.abcdef");
+    }
+
+    #[tokio::test]
+    async fn concurrent_updates_download_once() {
+        let dir = testdir("concurrent-update");
+        let src = dir.join("src.db");
+        testpkgsdb(&src, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let body = brotli(&fs::read(&src).unwrap());
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counter = downloads.clone();
+        let url = testserver(move |method, path| match path {
+            "/nixpkgs.db.br" if method == "GET" => {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (200, vec![], body.clone())
+            }
+            _ => (404, vec![], vec![]),
+        });
+        let config = testconfig(&dir);
+        let client = reqwest::Client::new();
+        let url = format!("{}/nixpkgs.db.br", url);
+        let cancel = CancellationToken::new();
+        let update = || updatedb(&config, &client, &url, "23.05.1", |_, _, _| {}, &cancel);
+
+        // The second call waits for the first to finish, and then finds the database up to date
+        let (first, second) = tokio::join!(update(), update());
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+        assert!(first.downloaded != second.downloaded);
+        assert_eq!(first.path, second.path);
+    }
+
+    #[tokio::test]
+    async fn force_downloads_again() {
+        let dir = testdir("force-download");
+        let src = dir.join("src.db");
+        testpkgsdb(&src, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let body = brotli(&fs::read(&src).unwrap());
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counter = downloads.clone();
+        let url = testserver(move |method, path| match path {
+            "/nixpkgs.db.br" if method == "GET" => {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (200, vec![], body.clone())
+            }
+            _ => (404, vec![], vec![]),
+        });
+        let url = format!("{}/nixpkgs.db.br", url);
+        let client = reqwest::Client::new();
+        let cancel = CancellationToken::new();
+
+        let config = testconfig(&dir);
+        assert!(updatedb(&config, &client, &url, "23.05.1", |_, _, _| {}, &cancel).await.unwrap().downloaded);
+        assert!(!updatedb(&config, &client, &url, "23.05.1", |_, _, _| {}, &cancel).await.unwrap().downloaded);
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+
+        // The same version is downloaded again when forced
+        let config = CacheConfig {
+            force: true,
+            ..config
+        };
+        assert!(updatedb(&config, &client, &url, "23.05.1", |_, _, _| {}, &cancel).await.unwrap().downloaded);
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn options_ver_is_bare() {
+        let dir = testdir("options-ver");
+        let options = brotli(br#"{"networking.hostName": {"type": "string"}}"#);
+        let downloads = Arc::new(AtomicUsize::new(0));
+        let counter = downloads.clone();
+        let url = testserver(move |method, path| match path {
+            "/nixos-23.05.1234.abcdef/options.json.br" => {
+                if method == "GET" {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+                (200, vec![("Content-Encoding", String::from("br"))], options.clone())
+            }
+            _ => (200, vec![], vec![]),
+        });
+        let config = testconfig(&dir);
+        let client = reqwest::Client::builder().brotli(true).build().unwrap();
+        let releaseurl = format!("{}/nixos-23.05.1234.abcdef", url);
+        let release = "nixos-23.05.1234.abcdef";
+
+        fetchnixosoptions(&config, &client, &releaseurl, release, |_, _| {}, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir.join("nixosoptions.ver")).unwrap(), "23.05.1234.abcdef");
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+
+        // A prefixed version written by an older version of this crate is still up to date
+        fs::write(dir.join("nixosoptions.ver"), "nixos-23.05.1234.abcdef\n").unwrap();
+        fetchnixosoptions(&config, &client, &releaseurl, release, |_, _| {}, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn identical_options_left_untouched() {
+        let dir = testdir("options-unchanged");
+        let options = brotli(br#"{"networking.hostName": {"type": "string"}}"#);
+        let changed = brotli(br#"{"networking.domain": {"type": "string"}}"#);
+        let url = testserver(move |_, path| match path {
+            "/nixos-23.05.1/options.json.br" | "/nixos-23.05.2/options.json.br" => (200, vec![], options.clone()),
+            "/nixos-23.05.3/options.json.br" => (200, vec![], changed.clone()),
+            _ => (200, vec![], vec![]),
+        });
+        let config = testconfig(&dir);
+        let client = reqwest::Client::new();
+        let fetch = |release: &'static str| {
+            let releaseurl = format!("{}/{}", url, release);
+            let (config, client) = (&config, &client);
+            async move {
+                fetchnixosoptions(config, client, &releaseurl, release, |_, _| {}, &CancellationToken::new())
+                    .await
+                    .unwrap()
+            }
+        };
+        let mtime = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+
+        let jsonfile = fetch("nixos-23.05.1").await;
+        let before = mtime(&jsonfile);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // A new version with the same options only updates the recorded version
+        fetch("nixos-23.05.2").await;
+        assert_eq!(mtime(&jsonfile), before);
+        assert_eq!(fs::read_to_string(dir.join("nixosoptions.ver")).unwrap(), "23.05.2");
+
+        fetch("nixos-23.05.3").await;
+        assert_ne!(mtime(&jsonfile), before);
+        assert!(fs::read_to_string(&jsonfile).unwrap().contains("networking.domain"));
+    }
+
+    #[tokio::test]
+    async fn options_raw_brotli() {
+        let options = brotli(br#"{"networking.hostName": {"type": "string"}}"#);
+        for encoding in [None, Some("br")] {
+            let dir = testdir(&format!("options-brotli-{}", encoding.unwrap_or("none")));
+            let body = options.clone();
+            // Some mirrors serve `.br` files as plain content, without `Content-Encoding: br`
+            let url = testserver(move |_, path| match path {
+                "/nixos-23.05.1234.abcdef/options.json.br" => (
+                    200,
+                    encoding
+                        .map(|x| vec![("Content-Encoding", String::from(x))])
+                        .unwrap_or_default(),
+                    body.clone(),
+                ),
+                _ => (404, vec![], vec![]),
+            });
+            let jsonfile = fetchnixosoptions(
+                &testconfig(&dir),
+                &reqwest::Client::new(),
+                &format!("{}/nixos-23.05.1234.abcdef", url),
+                "nixos-23.05.1234.abcdef",
+                |_, _| {},
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+            let parsed = crate::cache::options::parse_options(&jsonfile).unwrap();
+            assert_eq!(parsed[0].name, "networking.hostName");
+        }
+    }
+
+    #[tokio::test]
+    async fn import_valid_and_invalid_prebuilt_db() {
+        let dir = testdir("prebuilt");
+        let config = testconfig(&dir);
+        let valid = dir.join("valid.db");
+        testpkgsdb(&valid, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let db = import_prebuilt_db_with_config(&config, &valid, "23.05.1234.abcdef").await.unwrap();
+        assert_eq!(fs::read_to_string(dir.join(config.pkgsname("ver"))).unwrap(), "23.05.1234.abcdef");
+        assert_eq!(db_version(&db).await.unwrap().as_deref(), Some("23.05.1234.abcdef"));
+        assert!(valid.exists());
+
+        // A database without the meta table is rejected, and the imported one is kept
+        let invalid = dir.join("invalid.db");
+        let pool = testpkgsdb(&invalid, &[("hello", "hello", "2.13", "Greeting")]).await;
+        sqlx::query("DROP TABLE meta").execute(&pool).await.unwrap();
+        pool.close().await;
+        let err = import_prebuilt_db_with_config(&config, &invalid, "23.05.5678.abcdef").await.unwrap_err();
+        assert!(err.to_string().contains("meta"), "{}", err);
+        assert!(!db.with_extension("db.tmp").exists());
+        assert_eq!(db_version(&db).await.unwrap().as_deref(), Some("23.05.1234.abcdef"));
+        assert_eq!(fs::read_to_string(dir.join(config.pkgsname("ver"))).unwrap(), "23.05.1234.abcdef");
+    }
+
+    #[tokio::test]
+    async fn latest_version_follows_redirect() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let url = testserver(move |_, path| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            match path {
+                "/nixos-23.05" => (302, vec![("Location", String::from("/nixos-23.05.1234.abcdef"))], vec![]),
+                "/nixos-23.05.1234.abcdef" => (200, vec![], vec![]),
+                _ => (404, vec![], vec![]),
+            }
+        });
+        // The channel set in the source is ignored in favor of the one asked for
+        let config = CacheConfig {
+            channel_source: ChannelSource {
+                base_url: url,
+                channel: Some(String::from("nixos-unstable")),
+            },
+            ..testconfig(&testdir("latest-version"))
+        };
+        assert_eq!(latest_version_with_config(&config, "23.05").await.unwrap(), "23.05.1234.abcdef");
+        // Only the redirect and the release it points to are requested
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    /// Records every log message, so tests can count the ones they caused.
+    /// The logger is shared by all tests, so they pick out their messages by their own paths.
+    struct CountingLogger;
+
+    static LOGGED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+    impl log::Log for CountingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[tokio::test]
+    async fn download_logs_package_count() {
+        let _ = log::set_logger(&CountingLogger);
+        log::set_max_level(log::LevelFilter::Info);
+        let dir = testdir("log-count");
+        let src = dir.join("src.db");
+        testpkgsdb(
+            &src,
+            &[("hello", "hello", "2.12", "Greeting"), ("firefox", "firefox", "118.0.1", "Web browser")],
+        )
+        .await
+        .close()
+        .await;
+        let body = brotli(&fs::read(&src).unwrap());
+        let url = testserver(move |_, path| match path {
+            "/nixpkgs.db.br" => (200, vec![], body.clone()),
+            _ => (404, vec![], vec![]),
+        });
+
+        let config = testconfig(&dir);
+        let url = format!("{}/nixpkgs.db.br", url);
+        downloaddb(&config, &reqwest::Client::new(), &url, "23.11.1", None, &|_, _, _| {}, &CancellationToken::new())
+            .await
+            .unwrap();
+        let dbfile = config.file("nixospkgs.db");
+        let logged = LOGGED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| x.starts_with(&format!("Updated {} ", dbfile)))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(logged.len(), 1, "{:?}", logged);
+        assert!(logged[0].contains("imported 2 packages"), "{}", logged[0]);
+    }
+
+    #[tokio::test]
+    async fn latest_db_version_reports_network_errors() {
+        // Nothing listens on the discard port, so the connection is refused
+        let client = reqwest::Client::new();
+        let result = latestdbversion(&client, "http://127.0.0.1:9/nixpkgs.ver").await;
+        assert!(matches!(result, Err(NixDataError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn packages_and_options_resolve_same_version() {
+        // The database repository keeps the channel prefix and a trailing newline, the channel redirects to the prefixed release
+        let url = testserver(|_, path| match path {
+            "/nixpkgs.ver" => (200, vec![], b"nixos-23.05.1234.abcdef\n".to_vec()),
+            "/nixos-23.05" => (302, vec![("Location", String::from("/nixos/23.05/nixos-23.05.1234.abcdef"))], vec![]),
+            "/nixos/23.05/nixos-23.05.1234.abcdef" => (200, vec![], vec![]),
+            _ => (404, vec![], vec![]),
+        });
+        let config = CacheConfig {
+            channel_source: ChannelSource {
+                base_url: url.clone(),
+                channel: None,
+            },
+            ..testconfig(&testdir("same-version"))
+        };
+        let pkgsver = latestdbversion(&reqwest::Client::new(), &format!("{}/nixpkgs.ver", url))
+            .await
+            .unwrap();
+        let optionsver = latest_version_with_config(&config, "23.05").await.unwrap();
+        assert_eq!(pkgsver, "23.05.1234.abcdef");
+        assert_eq!(optionsver, pkgsver);
+    }
+
+    /// Imports into the database at `$NIX_DATA_TEST_DB`. Run by [createdb_without_sqlite3()] in a process without `PATH`.
+    #[tokio::test]
+    #[ignore = "run by createdb_without_sqlite3 in a process with an empty environment"]
+    async fn createdb_in_empty_env() {
+        let db = std::env::var("NIX_DATA_TEST_DB").unwrap();
+        let pkgs = HashMap::from([
+            (String::from("hello"), String::from("2.12")),
+            (String::from("python3Packages.requests"), String::from("2.31")),
+        ]);
+        createdb(Path::new(&db), &pkgs).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn createdb_without_sqlite3() {
+        let dir = testdir("createdb");
+        let db = dir.join("pkgs.db");
+        // Nothing can be run without a PATH, so the import fails if it needs an external sqlite3
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "cache::nixos::tests::createdb_in_empty_env", "--ignored"])
+            .env_clear()
+            .env("NIX_DATA_TEST_DB", &db)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        let pool = SqlitePool::connect(&format!("sqlite://{}", db.display())).await.unwrap();
+        let versions = queryversions(&pool, [String::from("hello"), String::from("python3Packages.requests")])
+            .await
+            .unwrap();
+        assert_eq!(
+            versions,
+            HashMap::from([
+                (String::from("hello"), String::from("2.12")),
+                (String::from("python3Packages.requests"), String::from("2.31")),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_import_keeps_old_db() {
+        let dir = testdir("failed-import");
+        let db = dir.join("pkgs.db");
+        let old = HashMap::from([(String::from("hello"), String::from("2.12"))]);
+        createdb(&db, &old).await.unwrap();
+
+        // The new database is built at `pkgs.db.tmp`, so a directory in its way makes the import fail
+        fs::create_dir(dir.join("pkgs.db.tmp")).unwrap();
+        let new = HashMap::from([(String::from("hello"), String::from("2.13"))]);
+        assert!(createdb(&db, &new).await.is_err());
+
+        let pool = SqlitePool::connect(&format!("sqlite://{}", db.display())).await.unwrap();
+        assert_eq!(queryversions(&pool, old.keys().cloned()).await.unwrap(), old);
+    }
+
+    #[tokio::test]
+    async fn in_memory_db() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let pkgs = HashMap::from([
+            (String::from("hello"), String::from("2.12")),
+            (String::from("python3Packages.requests"), String::from("2.31")),
+        ]);
+        let stats = createdb_in(&pool, &pkgs).await.unwrap();
+        assert_eq!(stats.inserted, 2);
+        let versions = PackageDb::from_pool(pool)
+            .lookup(&["hello", "python3Packages.requests", "missing"])
+            .await
+            .unwrap();
+        assert_eq!(versions, pkgs);
+    }
+
+    #[tokio::test]
+    async fn empty_db_fails() {
+        let dir = testdir("empty-db");
+        let db = dir.join("pkgs.db");
+        let err = createdb(&db, &HashMap::new()).await.unwrap_err();
+        assert!(err.to_string().contains("empty"), "{}", err);
+        assert!(!db.exists());
+
+        // Entries that are all malformed leave nothing to store either
+        let malformed = HashMap::from([(String::from("hello"), String::new())]);
+        assert!(createdb(&db, &malformed).await.is_err());
+        assert!(!db.exists());
+    }
+
+    #[tokio::test]
+    async fn not_nixos_without_version() {
+        // With an empty `PATH`, `nixos-version` can't be found, as on any other system
+        let dir = testdir("not-nixos");
+        let err = runningrelease(tokio::process::Command::new("nixos-version").env("PATH", &dir))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, NixDataError::NotNixos), "{}", err);
+
+        let config = CacheConfig {
+            nixos_version: Some(String::from("unstable")),
+            ..testconfig(&dir)
+        };
+        assert_eq!(systemchannel(&config, &httpclient(true).unwrap()).await.unwrap(), "unstable");
+    }
+
+    #[tokio::test]
+    async fn update_keeps_unchanged_rows() {
+        let dir = testdir("update-in-place");
+        let dbfile = dir.join("pkgs.db");
+        createpkgsdb(
+            &dbfile,
+            vec![
+                ("hello", Some("hello"), "2.12", None),
+                ("cowsay", Some("cowsay"), "3.7.0", None),
+                ("old", Some("old"), "1.0", None),
+            ],
+        )
+        .await
+        .unwrap();
+        let rowids = |pool: SqlitePool| async move {
+            let rows: Vec<(String, i64, String)> =
+                sqlx::query_as(r#"SELECT attribute, rowid, version FROM pkgs ORDER BY attribute"#)
+                    .fetch_all(&pool)
+                    .await
+                    .unwrap();
+            let schema = getmetainfo(&pool, "schema").await.unwrap();
+            pool.close().await;
+            (rows, schema)
+        };
+        let url = format!("sqlite://{}", dbfile.display());
+        let connect = || SqlitePool::connect(&url);
+        let (before, _) = rowids(connect().await.unwrap()).await;
+
+        let stats = createpkgsdb(
+            &dbfile,
+            vec![
+                ("hello", Some("hello"), "2.12.1", None),
+                ("cowsay", Some("cowsay"), "3.7.0", None),
+                ("new", Some("new"), "0.1", Some("x86_64-linux")),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.inserted, 3);
+        let (after, schema) = rowids(connect().await.unwrap()).await;
+        assert_eq!(schema.as_deref(), Some(PKGSDBSCHEMA));
+        let row = |rows: &[(String, i64, String)], attribute: &str| rows.iter().find(|x| x.0 == attribute).cloned();
+        // Unchanged and changed packages keep their rows, only the changed version is rewritten
+        assert_eq!(row(&after, "cowsay"), row(&before, "cowsay"));
+        let (_, rowid, version) = row(&after, "hello").unwrap();
+        assert_eq!(rowid, row(&before, "hello").unwrap().1);
+        assert_eq!(version, "2.12.1");
+        assert!(row(&after, "old").is_none());
+        assert!(row(&after, "new").is_some());
+    }
+
+    #[tokio::test]
+    async fn unusual_text_round_trips() {
+        // Descriptions come with the downloaded database, so the text written here is attributes, pnames and versions
+        let text = "says \"hi\", then|\nleaves 🎉 ünïcode";
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let pkgs = HashMap::from([
+            (String::from("plain"), text.to_string()),
+            (text.to_string(), String::from("1.0")),
+        ]);
+        createdb_in(&pool, &pkgs).await.unwrap();
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT attribute, version FROM pkgs")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows.into_iter().collect::<HashMap<_, _>>(), pkgs);
+
+        let db = testdir("round-trip").join("pkgs.db");
+        let pkgs = HashMap::from([(
+            String::from("plain"),
+            NixPkg {
+                pname: text.into(),
+                version: text.into(),
+                system: None,
+            },
+        )]);
+        createdbwithpnames(&db, &pkgs).await.unwrap();
+        let pool = connectdb(&db).await.unwrap();
+        let (pname, version): (String, String) = sqlx::query_as("SELECT pname, version FROM pkgs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(pname.as_bytes(), text.as_bytes());
+        assert_eq!(version.as_bytes(), text.as_bytes());
+    }
+
+    #[test]
+    fn config_paths_from_env() {
+        // The only test reading NIXOS_CONFIG, so setting it can't race with the others
+        let dir = testdir("config-paths");
+        std::env::set_var("NIXOS_CONFIG", &dir);
+        assert!(default_config_paths().is_err());
+
+        fs::write(dir.join("flake.nix"), "{ }").unwrap();
+        fs::write(dir.join("configuration.nix"), "{ }").unwrap();
+        assert_eq!(
+            default_config_paths().unwrap(),
+            vec![dir.join("configuration.nix"), dir.join("flake.nix")]
+        );
+
+        std::env::set_var("NIXOS_CONFIG", dir.join("flake.nix"));
+        assert_eq!(default_config_paths().unwrap(), vec![dir.join("flake.nix")]);
+        std::env::remove_var("NIXOS_CONFIG");
+    }
 }
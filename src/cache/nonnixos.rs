@@ -1,16 +1,17 @@
 use crate::CACHEDIR;
-use anyhow::{anyhow, Result};
+use crate::error::{NixDataError, Result};
 use log::{debug, info};
 use std::{
     fs::{self, File},
     io::{Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+use super::httpclient;
 
 /// Downloads the latest `packages.json` for the system from the Nix cache and returns the path to an SQLite database `nonnixospkgs.db` which contains package data.
 /// Mean for non-NixOS systems.
-pub async fn nixpkgs() -> Result<String> {
+pub async fn nixpkgs() -> Result<PathBuf> {
     // If cache directory doesn't exist, create it
     if !std::path::Path::new(&*CACHEDIR).exists() {
         std::fs::create_dir_all(&*CACHEDIR)?;
@@ -20,7 +21,7 @@ pub async fn nixpkgs() -> Result<String> {
         "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixpkgs-unstable/nixpkgs.ver"
     );
     debug!("Checking nixpkgs version");
-    let resp = reqwest::get(&verurl).await;
+    let resp = httpclient(true)?.get(&verurl).send().await;
     let resp = if let Ok(r) = resp {
         r
     } else {
@@ -29,15 +30,15 @@ pub async fn nixpkgs() -> Result<String> {
         let dbpath = format!("{}/nonnixospkgs.db", &*CACHEDIR);
         if Path::new(&dbpath).exists() {
             info!("Using old database");
-            return Ok(dbpath);
+            return Ok(dbpath.into());
         } else {
-            return Err(anyhow!("Could not find latest nixpkgs version"));
+            return Err(NixDataError::ChannelResolve(String::from("Could not find latest nixpkgs version")));
         }
     };
     let latestnixpkgsver = if resp.status().is_success() {
         resp.text().await?
     } else {
-        return Err(anyhow!("Could not find latest nixpkgs version"));
+        return Err(NixDataError::ChannelResolve(String::from("Could not find latest nixpkgs version")));
     };
     debug!("Latest nixpkgs version: {}", latestnixpkgsver);
 
@@ -46,12 +47,12 @@ pub async fn nixpkgs() -> Result<String> {
         .unwrap_or(&latestnixpkgsver);
     info!("latestnixosver: {}", latestnixpkgsver);
     // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/nonnixospkgs.ver", &*CACHEDIR)) {
+    if let Ok(prevver) = fs::read_to_string(format!("{}/nonnixospkgs.ver", &*CACHEDIR)) {
         if prevver == latestnixpkgsver
             && Path::new(&format!("{}/nonnixospkgs.db", &*CACHEDIR)).exists()
         {
             debug!("No new version of nixpkgs found");
-            return Ok(format!("{}/nonnixospkgs.db", &*CACHEDIR));
+            return Ok(format!("{}/nonnixospkgs.db", &*CACHEDIR).into());
         }
     }
 
@@ -59,11 +60,11 @@ pub async fn nixpkgs() -> Result<String> {
         "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixpkgs-unstable/nixpkgs.db.br"
     );
     debug!("Downloading nix-data database");
-    let client = reqwest::Client::builder().brotli(true).build()?;
+    let client = httpclient(true)?;
     let resp = client.get(url).send().await?;
     if resp.status().is_success() {
         debug!("Writing nix-data database");
-        let mut out = File::create(&format!("{}/nonnixospkgs.db", &*CACHEDIR))?;
+        let mut out = File::create(format!("{}/nonnixospkgs.db", &*CACHEDIR))?;
         {
             let bytes = resp.bytes().await?;
             let mut reader = brotli::Decompressor::new(
@@ -95,7 +96,7 @@ pub async fn nixpkgs() -> Result<String> {
         File::create(format!("{}/nonnixospkgs.ver", &*CACHEDIR))?
             .write_all(latestnixpkgsver.as_bytes())?;
     } else {
-        return Err(anyhow!("Failed to download latest nonnixospkgs.db.br"));
+        return Err(NixDataError::Download(String::from("Failed to download latest nonnixospkgs.db.br")));
     }
-    Ok(format!("{}/nonnixospkgs.db", &*CACHEDIR))
+    Ok(format!("{}/nonnixospkgs.db", &*CACHEDIR).into())
 }
@@ -0,0 +1,919 @@
+use crate::error::{tooloutput_async, NixDataError, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{
+    closetmp, columnexists, connectdb, connecttmp, getmetainfo, lockfile,
+    nixos::{nixosoptions_with_config, stripchannelprefix},
+    replacedb, setbuildinfo, tableexists, withsuffix, CacheConfig,
+};
+
+/// A NixOS option, as described in `options.json`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NixosOption {
+    /// Full option name, e.g. `networking.firewall.enable`.
+    pub name: String,
+    /// Option description. Older `options.json` files wrap this in an `mdDoc` object, which is unwrapped.
+    pub description: Option<String>,
+    /// Option type as a human readable string, e.g. `boolean` or `list of string`.
+    pub optiontype: String,
+    /// Default value. This is arbitrary JSON, and may be a `literalExpression` object.
+    pub default: Option<serde_json::Value>,
+    /// Example value. This is arbitrary JSON, and may be a `literalExpression` object.
+    pub example: Option<serde_json::Value>,
+    /// Files in nixpkgs declaring the option.
+    pub declarations: Vec<String>,
+}
+
+impl NixosOption {
+    /// The [option type](NixosOption::optiontype), parsed with [OptionType::parse()].
+    pub fn parsed_type(&self) -> OptionType {
+        OptionType::parse(&self.optiontype)
+    }
+}
+
+/// Type of a NixOS option, parsed from its human readable description such as `null or list of string`,
+/// for example to pick the widget editing the option. Types without a variant of their own, such as submodules,
+/// attribute sets and most unions, are kept as [Unknown](OptionType::Unknown).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OptionType {
+    /// `boolean`.
+    Bool,
+    /// Any integer type, e.g. `signed integer` or `16 bit unsigned integer; between 0 and 65535 (both inclusive)`.
+    Int,
+    /// Any string type, e.g. `string`, `single-line string` or `strings concatenated with "\n"`.
+    Str,
+    /// `path` or `absolute path`.
+    Path,
+    /// `list of <type>`.
+    List(Box<OptionType>),
+    /// `null or <type>`.
+    Nullable(Box<OptionType>),
+    /// `one of "a", "b"`, with the allowed values. Values that aren't strings are given as JSON, e.g. `1`.
+    Enum(Vec<String>),
+    /// Any other type, with its full description.
+    Unknown(String),
+}
+
+impl OptionType {
+    /// Parses the type description `optiontype` of an option, as found in `options.json`.
+    /// Descriptions that aren't recognized give [Unknown](OptionType::Unknown) rather than an error.
+    pub fn parse(optiontype: &str) -> OptionType {
+        let t = stripparens(optiontype.trim());
+        if let Some(rest) = t.strip_prefix("null or ") {
+            return OptionType::Nullable(Box::new(OptionType::parse(rest)));
+        }
+        if let Some(rest) = t.strip_prefix("non-empty ") {
+            return OptionType::parse(rest);
+        }
+        if let Some(rest) = t.strip_prefix("list of ") {
+            return OptionType::List(Box::new(OptionType::parse(rest)));
+        }
+        if let Some(rest) = t.strip_prefix("one of ") {
+            return OptionType::Enum(enumvalues(rest));
+        }
+        // Other unions can't be represented
+        if t.contains(" or ") {
+            return OptionType::Unknown(t.to_string());
+        }
+        // Constraints follow the type, e.g. `positive integer, meaning >0` or `string, not containing newlines`
+        let head = t.split([';', ',']).next().unwrap_or_default().trim();
+        if head == "boolean" {
+            OptionType::Bool
+        } else if head.ends_with("integer") || head.starts_with("integer between") {
+            OptionType::Int
+        } else if head == "path" || head.ends_with(" path") {
+            OptionType::Path
+        } else if head.starts_with("string")
+            || (head.ends_with("string") && !head.contains(" of "))
+        {
+            OptionType::Str
+        } else {
+            OptionType::Unknown(t.to_string())
+        }
+    }
+}
+
+/// Value of an option, coerced to its [OptionType]. Returned by [default_value()].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypedValue {
+    /// Value of a [Bool](OptionType::Bool) option.
+    Bool(bool),
+    /// Value of an [Int](OptionType::Int) option.
+    Int(i64),
+    /// Value of a [Str](OptionType::Str) option.
+    Str(String),
+    /// Value of a [Path](OptionType::Path) option.
+    Path(String),
+    /// Value of a [List](OptionType::List) option, with each element coerced to the element type.
+    List(Vec<TypedValue>),
+    /// `null`, for a [Nullable](OptionType::Nullable) option.
+    Null,
+    /// One of the values of an [Enum](OptionType::Enum) option.
+    Enum(String),
+}
+
+/// Returns the default value of `option`, coerced to its [parsed type](NixosOption::parsed_type).
+/// Defaults given as a `literalExpression` are only understood if they are a plain literal such as `true`, `8080` or `"info"`.
+/// Returns `None` if the option has no default, the default is a more complex expression,
+/// the type is [Unknown](OptionType::Unknown), or the default doesn't match the type.
+pub fn default_value(option: &NixosOption) -> Option<TypedValue> {
+    typedvalue(&literaldefault(option.default.as_ref()?)?, &option.parsed_type())
+}
+
+/// Returns the option default `default` as JSON, if it is either plain JSON or a `literalExpression` that is a plain literal.
+fn literaldefault(default: &serde_json::Value) -> Option<serde_json::Value> {
+    match default.get("_type").and_then(|x| x.as_str()) {
+        Some("literalExpression") => nixliteral(default.get("text")?.as_str()?),
+        Some(_) => None,
+        None => Some(default.clone()),
+    }
+}
+
+/// Writes a scalar `value` as a Nix literal, e.g. `true`, `8080` or `"info"`, to be compared by [options_by_default()].
+/// Returns `None` for lists and attribute sets.
+fn scalarliteral(value: &serde_json::Value) -> Option<String> {
+    use serde_json::Value;
+    match value {
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => Some(value.to_string()),
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+/// Parses the Nix expression `text` if it is a plain literal: a boolean, `null`, an integer, a string without interpolation, or an empty list.
+fn nixliteral(text: &str) -> Option<serde_json::Value> {
+    let text = text.trim();
+    match text {
+        "true" => Some(serde_json::Value::Bool(true)),
+        "false" => Some(serde_json::Value::Bool(false)),
+        "null" => Some(serde_json::Value::Null),
+        _ if text.starts_with('[') && text[1..].trim() == "]" => Some(serde_json::Value::Array(vec![])),
+        _ if text.starts_with('"') && !text.contains("${") => serde_json::from_str(text).ok(),
+        _ => text.parse::<i64>().ok().map(serde_json::Value::from),
+    }
+}
+
+/// Coerces the JSON `value` to `optiontype`, or returns `None` if it doesn't match.
+fn typedvalue(value: &serde_json::Value, optiontype: &OptionType) -> Option<TypedValue> {
+    use serde_json::Value;
+    match (optiontype, value) {
+        (OptionType::Nullable(_), Value::Null) => Some(TypedValue::Null),
+        (OptionType::Nullable(inner), value) => typedvalue(value, inner),
+        (OptionType::Bool, Value::Bool(b)) => Some(TypedValue::Bool(*b)),
+        (OptionType::Int, Value::Number(n)) => n.as_i64().map(TypedValue::Int),
+        (OptionType::Str, Value::String(s)) => Some(TypedValue::Str(s.clone())),
+        (OptionType::Path, Value::String(s)) => Some(TypedValue::Path(s.clone())),
+        (OptionType::List(inner), Value::Array(values)) => values
+            .iter()
+            .map(|x| typedvalue(x, inner))
+            .collect::<Option<Vec<_>>>()
+            .map(TypedValue::List),
+        (OptionType::Enum(allowed), value) => {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                x => x.to_string(),
+            };
+            allowed.contains(&value).then_some(TypedValue::Enum(value))
+        }
+        _ => None,
+    }
+}
+
+/// Strips parentheses enclosing the whole of `t`, as in `list of (list of string)`.
+fn stripparens(t: &str) -> &str {
+    let Some(inner) = t.strip_prefix('(').and_then(|x| x.strip_suffix(')')) else {
+        return t;
+    };
+    // Only strip if the opening parenthesis is closed at the very end, unlike `(a) or (b)`
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return t,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    stripparens(inner.trim())
+}
+
+/// Parses the comma separated values of an enum type, e.g. `"a", "b"` or `1, 2`.
+fn enumvalues(values: &str) -> Vec<String> {
+    match serde_json::from_str::<Vec<serde_json::Value>>(&format!("[{}]", values)) {
+        Ok(values) => values
+            .into_iter()
+            .map(|x| match x {
+                serde_json::Value::String(s) => s,
+                x => x.to_string(),
+            })
+            .collect(),
+        Err(_) => values
+            .split(',')
+            .map(|x| x.trim().trim_matches('"').to_string())
+            .collect(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OptionOut {
+    description: Option<serde_json::Value>,
+    #[serde(rename = "type")]
+    optiontype: String,
+    default: Option<serde_json::Value>,
+    example: Option<serde_json::Value>,
+    #[serde(default)]
+    declarations: Vec<serde_json::Value>,
+}
+
+/// Descriptions are either plain strings or `{ "_type": "mdDoc", "text": "..." }` objects.
+fn descriptiontext(description: Option<serde_json::Value>) -> Option<String> {
+    match description? {
+        serde_json::Value::String(s) => Some(s),
+        serde_json::Value::Object(o) => o.get("text")?.as_str().map(|x| x.to_string()),
+        _ => None,
+    }
+}
+
+/// Declarations are either plain paths, or `{ "name": "...", "url": "..." }` objects in home-manager and nix-darwin.
+fn declarationpath(declaration: serde_json::Value) -> Option<String> {
+    match declaration {
+        serde_json::Value::String(s) => Some(s),
+        serde_json::Value::Object(o) => o
+            .get("url")
+            .or_else(|| o.get("name"))?
+            .as_str()
+            .map(|x| x.to_string()),
+        _ => None,
+    }
+}
+
+/// Parses the `options.json` file at `path`, such as the one downloaded by [nixosoptions()](super::nixos::nixosoptions),
+/// into a list of options. The order of the options is unspecified.
+pub fn parse_options(path: impl AsRef<Path>) -> Result<Vec<NixosOption>> {
+    let options: HashMap<String, OptionOut> =
+        serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    Ok(options
+        .into_iter()
+        .map(|(name, o)| NixosOption {
+            name,
+            description: descriptiontext(o.description),
+            optiontype: o.optiontype,
+            default: o.default,
+            example: o.example,
+            declarations: o
+                .declarations
+                .into_iter()
+                .filter_map(declarationpath)
+                .collect(),
+        })
+        .collect())
+}
+
+/// Builds an SQLite database at `dbfile` containing an `options` table from the `options.json` file at `jsonfile`,
+/// such as the one downloaded by [nixosoptions()](super::nixos::nixosoptions).
+/// Any existing database at `dbfile` is replaced once the new one has been built, and kept as `<dbfile>.bak`.
+pub async fn createoptionsdb(jsonfile: impl AsRef<Path>, dbfile: impl AsRef<Path>) -> Result<()> {
+    let options = parse_options(jsonfile)?;
+    debug!("Read {} options", options.len());
+
+    let tmpfile = withsuffix(dbfile.as_ref(), ".tmp");
+    if tmpfile.exists() {
+        fs::remove_file(&tmpfile)?;
+    }
+    let pool = connecttmp(&tmpfile).await?;
+    if let Err(e) = filloptionsdb(&pool, &options).await {
+        pool.close().await;
+        let _ = fs::remove_file(&tmpfile);
+        return Err(e);
+    }
+    closetmp(pool).await?;
+    replacedb(&tmpfile, dbfile)
+}
+
+/// Writes `options` to the new, empty database in `pool`.
+async fn filloptionsdb(pool: &SqlitePool, options: &[NixosOption]) -> Result<()> {
+    sqlx::query(
+        r#"
+            CREATE TABLE "options" (
+                "name"	TEXT NOT NULL UNIQUE,
+                "type"	TEXT,
+                "description"	TEXT,
+                "default"	JSON,
+                "example"	JSON,
+                "declarations"	JSON,
+                "defaultvalue"	TEXT,
+                PRIMARY KEY("name")
+            )
+            "#,
+    )
+    .execute(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    for option in options {
+        sqlx::query(r#"INSERT INTO "options" VALUES ($1, $2, $3, $4, $5, $6, $7)"#)
+            .bind(&option.name)
+            .bind(&option.optiontype)
+            .bind(&option.description)
+            .bind(option.default.as_ref().map(|x| x.to_string()))
+            .bind(option.example.as_ref().map(|x| x.to_string()))
+            .bind(serde_json::to_string(&option.declarations)?)
+            .bind(normaldefault(option))
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    createdefaultsindex(pool).await?;
+    createoptionsfts(pool).await?;
+    Ok(())
+}
+
+/// Default of `option` as stored in the `defaultvalue` column: a Nix literal for simple scalar defaults, and `None` otherwise.
+fn normaldefault(option: &NixosOption) -> Option<String> {
+    scalarliteral(&literaldefault(option.default.as_ref()?)?)
+}
+
+async fn createdefaultsindex(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(r#"CREATE INDEX IF NOT EXISTS "defaults" ON "options" ("defaultvalue")"#)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Adds the `defaultvalue` column to options databases built by older versions of this crate.
+async fn adddefaultvalues(pool: &SqlitePool) -> Result<()> {
+    let rows: Vec<OptionRow> = sqlx::query_as(
+        &format!(r#"SELECT {} FROM "options""#, OPTIONCOLUMNS),
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut tx = pool.begin().await?;
+    sqlx::query(r#"ALTER TABLE "options" ADD COLUMN "defaultvalue" TEXT"#)
+        .execute(&mut tx)
+        .await?;
+    for option in rows.into_iter().map(optionfromrow) {
+        if let Some(value) = normaldefault(&option) {
+            sqlx::query(r#"UPDATE "options" SET "defaultvalue" = $1 WHERE "name" = $2"#)
+                .bind(value)
+                .bind(&option.name)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+    createdefaultsindex(pool).await
+}
+
+/// Returns the options in the options database at `db` (see [createoptionsdb()]) whose default is `value`, ordered by name,
+/// e.g. every option that defaults to `false`. `value` is a Nix literal, such as `true`, `8080`, `"info"` or `null`.
+///
+/// Only simple scalar defaults are indexed, whether given as JSON or as a `literalExpression` that is a plain literal,
+/// so options defaulting to lists, attribute sets or other expressions are never returned.
+/// Databases built by older versions of this crate are indexed on first use.
+pub async fn options_by_default(db: impl AsRef<Path>, value: &str) -> Result<Vec<NixosOption>> {
+    let Some(value) = nixliteral(value).as_ref().and_then(scalarliteral) else {
+        return Ok(vec![]);
+    };
+    let pool = connectdb(&db).await?;
+    if !columnexists(&pool, "options", "defaultvalue").await? {
+        adddefaultvalues(&pool).await?;
+    }
+    let rows: Vec<OptionRow> = sqlx::query_as(&format!(
+        r#"SELECT {} FROM "options" WHERE "defaultvalue" = $1 ORDER BY "name""#,
+        OPTIONCOLUMNS
+    ))
+    .bind(value)
+    .fetch_all(&pool)
+    .await?;
+    Ok(rows.into_iter().map(optionfromrow).collect())
+}
+
+/// Builds the `options_fts` full-text index over the name and description of every option.
+async fn createoptionsfts(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(r#"DROP TABLE IF EXISTS "options_fts""#)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE "options_fts" USING fts5(
+            name,
+            description
+        )
+        "#,
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO options_fts (name, description)
+        SELECT "name", "description" FROM "options"
+        "#,
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Downloads the latest `options.json` for the system with [nixosoptions()](super::nixos::nixosoptions)
+/// and returns the path to an SQLite database `nixosoptions.db` built from it (see [createoptionsdb()]).
+/// The database is only rebuilt when a new NixOS version is available.
+/// Set [force](CacheConfig::force) in the `_with_config` variant to rebuild it regardless.
+/// Will only work on NixOS systems, unless a [NixOS version](CacheConfig::nixos_version) is given to the `_with_config` variant.
+/// Elsewhere, fails with [NixDataError::NotNixos].
+pub async fn optionsdb() -> Result<PathBuf> {
+    optionsdb_with_config(&CacheConfig::default()).await
+}
+
+/// Like [optionsdb()], but caches the options in the directory given by `config`.
+pub async fn optionsdb_with_config(config: &CacheConfig) -> Result<PathBuf> {
+    config.createdir()?;
+    let dbfile = config.file("nixosoptions.db");
+    // Held until the database is rebuilt, so concurrent callers wait and then find it up to date
+    let _lock = lockfile(&dbfile).await?;
+    if let Some(cached) = config.offlinefile("nixosoptions.db") {
+        return cached;
+    }
+    let jsonfile = nixosoptions_with_config(config).await?;
+    let latest = stripchannelprefix(fs::read_to_string(config.file("nixosoptions.ver"))?.trim());
+    if Path::new(&dbfile).exists() && !config.force {
+        let pool = connectdb(&dbfile).await?;
+        let prevver = getmetainfo(&pool, "version").await?;
+        pool.close().await;
+        if prevver.map(|x| stripchannelprefix(&x)).as_deref() == Some(latest.as_str()) {
+            debug!("No new version of NixOS options found");
+            return Ok(dbfile.into());
+        }
+    }
+    createoptionsdb(&jsonfile, &dbfile).await?;
+    let pool = connectdb(&dbfile).await?;
+    setbuildinfo(&pool, &latest).await?;
+    pool.close().await;
+    Ok(dbfile.into())
+}
+
+/// Columns selected to build an [OptionRow]. The `JSON` columns have numeric affinity,
+/// so SQLite stores defaults such as `8080` as numbers, which need to be read back as text.
+const OPTIONCOLUMNS: &str = r#""options"."name", "options"."type", "options"."description",
+    CAST("options"."default" AS TEXT), CAST("options"."example" AS TEXT), "options"."declarations""#;
+
+type OptionRow = (
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+fn optionfromrow(row: OptionRow) -> NixosOption {
+    let (name, optiontype, description, default, example, declarations) = row;
+    NixosOption {
+        name,
+        description,
+        optiontype: optiontype.unwrap_or_default(),
+        default: default.and_then(|x| serde_json::from_str(&x).ok()),
+        example: example.and_then(|x| serde_json::from_str(&x).ok()),
+        declarations: declarations
+            .and_then(|x| serde_json::from_str(&x).ok())
+            .unwrap_or_default(),
+    }
+}
+
+/// Fetches the options named in `names` from the options database at `db` (see [createoptionsdb()]) in a single query.
+/// Names that don't exist in the database are omitted from the output.
+pub async fn get_options(db: impl AsRef<Path>, names: &[&str]) -> Result<HashMap<String, NixosOption>> {
+    if names.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let pool = connectdb(&db).await?;
+    let mut out = HashMap::new();
+    // Stay well below SQLite's limit on the number of bound parameters
+    for chunk in names.chunks(500) {
+        let mut query = QueryBuilder::<Sqlite>::new(format!(
+            r#"SELECT {} FROM "options" WHERE "name" IN ("#,
+            OPTIONCOLUMNS
+        ));
+        let mut separated = query.separated(", ");
+        for name in chunk {
+            separated.push_bind(*name);
+        }
+        separated.push_unseparated(")");
+        let rows: Vec<OptionRow> = query.build_query_as().fetch_all(&pool).await?;
+        for row in rows {
+            let option = optionfromrow(row);
+            out.insert(option.name.clone(), option);
+        }
+    }
+    Ok(out)
+}
+
+/// Returns where the option `name` is declared, given either the `options.json` file or the options database
+/// (see [createoptionsdb()]) at `path`. Paths are relative to the root of the nixpkgs repository,
+/// e.g. `nixos/modules/services/networking/ssh/sshd.nix`, so that they can be linked to as
+/// `https://github.com/NixOS/nixpkgs/blob/<revision>/<path>`. Nix store paths and `<nixpkgs/...>` lookup paths
+/// are made relative, and declarations that are already URLs, such as those in home-manager, are returned as they are.
+/// Returns an empty list if the option doesn't exist.
+pub async fn option_declarations(path: impl AsRef<Path>, name: &str) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let option = if path.extension().is_some_and(|x| x == "json") {
+        parse_options(path)?.into_iter().find(|x| x.name == name)
+    } else {
+        get_options(path, &[name]).await?.remove(name)
+    };
+    Ok(option
+        .map(|x| x.declarations.iter().map(|x| repopath(x)).collect())
+        .unwrap_or_default())
+}
+
+/// Makes the declaration `path` relative to the root of the nixpkgs repository.
+fn repopath(path: &str) -> String {
+    if path.contains("://") {
+        return path.to_string();
+    }
+    if let Some(path) = path.strip_prefix("<nixpkgs/") {
+        return path.trim_end_matches('>').to_string();
+    }
+    if let Some(storepath) = path.strip_prefix("/nix/store/") {
+        // Drop the `<hash>-source` directory nixpkgs was copied to
+        if let Some((_, path)) = storepath.split_once('/') {
+            return path.to_string();
+        }
+    }
+    path.trim_start_matches("./").to_string()
+}
+
+/// Full-text searches the name and description of every option in the options database at `db`
+/// (see [createoptionsdb()]), returning matches ranked by relevance (bm25), with matches in the name ranking higher.
+/// Options matching more of the words in `query` rank higher.
+pub async fn searchoptions(db: impl AsRef<Path>, query: &str) -> Result<Vec<NixosOption>> {
+    let pool = connectdb(&db).await?;
+    if !tableexists(&pool, "options_fts").await? {
+        createoptionsfts(&pool).await?;
+    }
+    // Quote every word so FTS5 query syntax in user input is matched literally
+    let terms = query
+        .split_whitespace()
+        .map(|x| format!("\"{}\"", x.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    if terms.is_empty() {
+        return Ok(vec![]);
+    }
+    let rows: Vec<OptionRow> = sqlx::query_as(&format!(
+        r#"
+        SELECT {}
+        FROM options_fts JOIN "options" ON "options"."name" = options_fts.name
+        WHERE options_fts MATCH $1
+        ORDER BY bm25(options_fts, 10.0, 1.0)
+        "#,
+        OPTIONCOLUMNS
+    ))
+    .bind(terms.join(" OR "))
+    .fetch_all(&pool)
+    .await?;
+    Ok(rows.into_iter().map(optionfromrow).collect())
+}
+
+/// Project publishing a set of options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionsSource {
+    /// NixOS options, downloaded from the NixOS channel.
+    Nixos,
+    /// [home-manager](https://github.com/nix-community/home-manager) options, built from its flake.
+    HomeManager,
+    /// [nix-darwin](https://github.com/LnL7/nix-darwin) options, built from its flake.
+    NixDarwin,
+}
+
+impl OptionsSource {
+    fn name(&self) -> &'static str {
+        match self {
+            OptionsSource::Nixos => "nixos",
+            OptionsSource::HomeManager => "homemanager",
+            OptionsSource::NixDarwin => "nixdarwin",
+        }
+    }
+
+    /// Flake reference and output containing `options.json`, for sources built with `nix build`.
+    fn flake(&self, version: &str) -> Option<(String, &'static str, &'static str)> {
+        match self {
+            OptionsSource::Nixos => None,
+            OptionsSource::HomeManager => {
+                let branch = if version == "unstable" {
+                    String::from("master")
+                } else {
+                    format!("release-{}", version)
+                };
+                Some((
+                    format!("github:nix-community/home-manager/{}", branch),
+                    "docs-json",
+                    "share/doc/home-manager/options.json",
+                ))
+            }
+            OptionsSource::NixDarwin => {
+                let branch = if version == "unstable" {
+                    String::from("master")
+                } else {
+                    format!("nix-darwin-{}", version)
+                };
+                Some((
+                    format!("github:LnL7/nix-darwin/{}", branch),
+                    "optionsJSON",
+                    "share/doc/darwin/options.json",
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FlakeMetadata {
+    revision: Option<String>,
+}
+
+/// Returns the latest revision of the options built from the flake `flake`,
+/// used to decide whether the cached options are up to date.
+async fn latestoptionsrev(flake: &str) -> Result<String> {
+    let output = tooloutput_async(
+        tokio::process::Command::new("nix")
+            .arg("flake")
+            .arg("metadata")
+            .arg("--json")
+            .arg(flake),
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(NixDataError::ChannelResolve(format!(
+            "Failed to get metadata of {}: {}",
+            flake,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let metadata: FlakeMetadata = serde_json::from_slice(&output.stdout)?;
+    metadata.revision.ok_or_else(|| {
+        NixDataError::ChannelResolve(format!("Could not find latest revision of {}", flake))
+    })
+}
+
+/// Builds the `options.json` in `path` of the output `output` of the flake `flake`, and copies it to `jsonfile`.
+async fn buildflakeoptions(flake: &str, output: &str, path: &str, jsonfile: &str) -> Result<()> {
+    let out = tooloutput_async(
+        tokio::process::Command::new("nix")
+            .arg("build")
+            .arg("--no-link")
+            .arg("--print-out-paths")
+            .arg(format!("{}#{}", flake, output)),
+    )
+    .await?;
+    if !out.status.success() {
+        return Err(NixDataError::Other(format!(
+            "Failed to build {}#{}: {}",
+            flake,
+            output,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    let outpath = String::from_utf8(out.stdout)?;
+    tokio::fs::copy(format!("{}/{}", outpath.trim(), path), jsonfile).await?;
+    Ok(())
+}
+
+/// Downloads or builds the options of `source` and returns the path to an SQLite options database built from them
+/// (see [createoptionsdb()]). The database is cached, and only rebuilt when a new revision of the options is available,
+/// or when [force](CacheConfig::force) is set in the `_with_config` variant.
+///
+/// `version` is either a release like `23.05` or `unstable`. NixOS options are downloaded from the matching channel
+/// and cached as `nixosoptions.db`, the same database as [optionsdb()] with a [NixOS version](CacheConfig::nixos_version) set.
+/// Home-manager and nix-darwin options are built with `nix build` from the matching release branch
+/// (`master` for `unstable`), so those require a working `nix` with flakes enabled.
+pub async fn options_db(source: OptionsSource, version: &str) -> Result<PathBuf> {
+    options_db_with_config(&CacheConfig::default(), source, version).await
+}
+
+/// Like [options_db()], but caches the database in the directory given by `config`.
+pub async fn options_db_with_config(
+    config: &CacheConfig,
+    source: OptionsSource,
+    version: &str,
+) -> Result<PathBuf> {
+    let Some((flake, output, path)) = source.flake(version) else {
+        let config = CacheConfig {
+            nixos_version: Some(version.to_string()),
+            ..config.clone()
+        };
+        return optionsdb_with_config(&config).await;
+    };
+    config.createdir()?;
+    let name = format!("{}options-{}", source.name(), version);
+    let dbfile = config.file(&format!("{}.db", name));
+    let verfile = config.file(&format!("{}.ver", name));
+    let jsonfile = config.file(&format!("{}.json", name));
+    let _lock = lockfile(&dbfile).await?;
+    if let Some(cached) = config.offlinefile(&format!("{}.db", name)) {
+        return cached;
+    }
+
+    let latest = match latestoptionsrev(&flake).await {
+        Ok(latest) => latest,
+        Err(e) => {
+            // Check if we can use the old database
+            if Path::new(&dbfile).exists() {
+                warn!("Could not check for new {} options, using the old database: {}", source.name(), e);
+                return Ok(dbfile.into());
+            }
+            return Err(e);
+        }
+    };
+    debug!("Latest {} options revision: {}", source.name(), latest);
+
+    // Check if latest version is already built
+    if let Ok(prevver) = fs::read_to_string(&verfile) {
+        if prevver == latest && Path::new(&dbfile).exists() && !config.force {
+            debug!("No new version of {} options found", source.name());
+            return Ok(dbfile.into());
+        }
+    }
+
+    buildflakeoptions(&flake, output, path, &jsonfile).await?;
+    createoptionsdb(&jsonfile, &dbfile).await?;
+    File::create(&verfile)?.write_all(latest.as_bytes())?;
+    Ok(dbfile.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testdir;
+
+    const OPTIONS: &str = r#"{
+  "networking.firewall.enable": {
+    "declarations": ["nixos/modules/services/networking/firewall.nix"],
+    "default": true,
+    "description": { "_type": "mdDoc", "text": "Whether to enable the firewall." },
+    "example": false,
+    "loc": ["networking", "firewall", "enable"],
+    "readOnly": false,
+    "type": "boolean"
+  },
+  "networking.firewall.allowedTCPPorts": {
+    "declarations": ["nixos/modules/services/networking/firewall.nix"],
+    "default": [],
+    "description": "List of TCP ports on which incoming connections are accepted.",
+    "example": { "_type": "literalExpression", "text": "[ 22 80 ]" },
+    "type": "list of 16 bit unsigned integer; between 0 and 65535 (both inclusive)"
+  },
+  "services.nginx.enable": {
+    "declarations": [{ "name": "<nixpkgs/nixos/modules/services/web-servers/nginx>", "url": "https://github.com/NixOS/nixpkgs/blob/master/nixos/modules/services/web-servers/nginx" }],
+    "default": false,
+    "description": "Whether to enable Nginx Web Server.",
+    "type": "boolean"
+  }
+}"#;
+
+    /// Writes [OPTIONS] to `options.json` in a new test directory named `name`.
+    fn optionsjson(name: &str) -> String {
+        let path = testdir(name).join("options.json");
+        fs::write(&path, OPTIONS).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn parse_options_json() {
+        let options = parse_options(optionsjson("parse-options")).unwrap();
+        assert_eq!(options.len(), 3);
+        let option = options.iter().find(|x| x.name == "networking.firewall.enable").unwrap();
+        assert_eq!(option.optiontype, "boolean");
+        assert_eq!(option.description.as_deref(), Some("Whether to enable the firewall."));
+        assert_eq!(option.default, Some(serde_json::Value::Bool(true)));
+        assert_eq!(option.declarations, vec!["nixos/modules/services/networking/firewall.nix"]);
+        let option = options.iter().find(|x| x.name == "services.nginx.enable").unwrap();
+        assert_eq!(
+            option.declarations,
+            vec!["https://github.com/NixOS/nixpkgs/blob/master/nixos/modules/services/web-servers/nginx"]
+        );
+    }
+
+    #[tokio::test]
+    async fn search_options_db() {
+        let jsonfile = optionsjson("search-options");
+        let db = jsonfile.replace("options.json", "options.db");
+        createoptionsdb(&jsonfile, &db).await.unwrap();
+        let found = searchoptions(&db, "networking.firewall").await.unwrap();
+        let names = found.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+        assert!(names.contains(&"networking.firewall.enable"), "{:?}", names);
+        assert!(!names.contains(&"services.nginx.enable"), "{:?}", names);
+        let enable = found.iter().find(|x| x.name == "networking.firewall.enable").unwrap();
+        assert_eq!(enable.optiontype, "boolean");
+        assert!(searchoptions(&db, "").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn option_types() {
+        use OptionType::*;
+        assert_eq!(OptionType::parse("boolean"), Bool);
+        assert_eq!(OptionType::parse("signed integer"), Int);
+        assert_eq!(
+            OptionType::parse("16 bit unsigned integer; between 0 and 65535 (both inclusive)"),
+            Int
+        );
+        assert_eq!(OptionType::parse("strings concatenated with \"\\n\""), Str);
+        assert_eq!(OptionType::parse("absolute path"), Path);
+        assert_eq!(OptionType::parse("list of string"), List(Box::new(Str)));
+        assert_eq!(
+            OptionType::parse("null or (list of (list of string))"),
+            Nullable(Box::new(List(Box::new(List(Box::new(Str))))))
+        );
+        assert_eq!(
+            OptionType::parse(r#"one of "info", "warn", 1"#),
+            Enum(vec![String::from("info"), String::from("warn"), String::from("1")])
+        );
+        assert_eq!(
+            OptionType::parse("attribute set of (submodule)"),
+            Unknown(String::from("attribute set of (submodule)"))
+        );
+        assert_eq!(
+            OptionType::parse("string or signed integer"),
+            Unknown(String::from("string or signed integer"))
+        );
+    }
+
+    #[tokio::test]
+    async fn declaration_paths() {
+        let jsonfile = optionsjson("option-declarations");
+        let db = jsonfile.replace("options.json", "options.db");
+        createoptionsdb(&jsonfile, &db).await.unwrap();
+        for path in [&jsonfile, &db] {
+            let declarations = option_declarations(path, "networking.firewall.enable").await.unwrap();
+            assert!(!declarations.is_empty());
+            assert_eq!(declarations, vec!["nixos/modules/services/networking/firewall.nix"]);
+            assert!(option_declarations(path, "does.not.exist").await.unwrap().is_empty());
+        }
+        assert_eq!(
+            repopath("/nix/store/0123abcd-source/nixos/modules/services/networking/ssh/sshd.nix"),
+            "nixos/modules/services/networking/ssh/sshd.nix"
+        );
+        assert_eq!(
+            repopath("<nixpkgs/nixos/modules/services/web-servers/nginx>"),
+            "nixos/modules/services/web-servers/nginx"
+        );
+    }
+
+    #[tokio::test]
+    async fn options_defaulting_to() {
+        let jsonfile = optionsjson("options-by-default");
+        let db = jsonfile.replace("options.json", "options.db");
+        createoptionsdb(&jsonfile, &db).await.unwrap();
+        let names = |options: Vec<NixosOption>| options.into_iter().map(|x| x.name).collect::<Vec<_>>();
+        assert_eq!(names(options_by_default(&db, "true").await.unwrap()), vec!["networking.firewall.enable"]);
+        assert_eq!(names(options_by_default(&db, "false").await.unwrap()), vec!["services.nginx.enable"]);
+        // Lists aren't indexed
+        assert!(options_by_default(&db, "[ ]").await.unwrap().is_empty());
+
+        // Databases built before the column existed are indexed on first use
+        let pool = connectdb(&db).await.unwrap();
+        sqlx::query(r#"DROP INDEX "defaults""#).execute(&pool).await.unwrap();
+        sqlx::query(r#"ALTER TABLE "options" DROP COLUMN "defaultvalue""#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        assert_eq!(names(options_by_default(&db, "true").await.unwrap()), vec!["networking.firewall.enable"]);
+    }
+
+    #[test]
+    fn typed_defaults() {
+        let option = |optiontype: &str, default: serde_json::Value| NixosOption {
+            name: String::from("test.option"),
+            description: None,
+            optiontype: optiontype.to_string(),
+            default: Some(default),
+            example: None,
+            declarations: vec![],
+        };
+        let literal = |text: &str| serde_json::json!({ "_type": "literalExpression", "text": text });
+        assert_eq!(
+            default_value(&option("boolean", serde_json::json!(true))),
+            Some(TypedValue::Bool(true))
+        );
+        assert_eq!(
+            default_value(&option("signed integer", serde_json::json!(8080))),
+            Some(TypedValue::Int(8080))
+        );
+        // A default that doesn't match the type isn't coerced
+        assert_eq!(default_value(&option("signed integer", serde_json::json!("8080"))), None);
+        assert_eq!(
+            default_value(&option("null or string", literal("\"info\""))),
+            Some(TypedValue::Str(String::from("info")))
+        );
+        assert_eq!(default_value(&option("package", literal("pkgs.hello"))), None);
+        assert_eq!(default_value(&option("string", literal("config.networking.hostName"))), None);
+    }
+}
@@ -1,20 +1,16 @@
-use crate::{
-    cache::{nixos, NixPkgList},
-    CACHEDIR,
-};
-use anyhow::{anyhow, Context, Result};
+use crate::CACHEDIR;
+use crate::error::{CommandExt, NixDataError, Result};
 use log::{debug, info};
 use serde::Deserialize;
-use sqlx::{Row, SqlitePool};
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{BufReader, Write, Read},
-    path::Path,
+    io::{Write, Read},
+    path::{Path, PathBuf},
     process::Command,
 };
 
-use super::{flakes::getflakepkgs, nixos::nixospkgs};
+use super::{connectdb, httpclient, nixos::nixospkgs, requiremeta};
 
 #[derive(Debug, Deserialize)]
 struct ProfilePkgsRoot {
@@ -44,7 +40,7 @@ pub fn getprofilepkgs() -> Result<HashMap<String, ProfilePkg>> {
     if !Path::new(&format!("{}/.nix-profile/manifest.json", std::env::var("HOME")?)).exists() {
         return Ok(HashMap::new());
     }
-    let profileroot: ProfilePkgsRoot = serde_json::from_reader(File::open(&format!(
+    let profileroot: ProfilePkgsRoot = serde_json::from_reader(File::open(format!(
         "{}/.nix-profile/manifest.json",
         std::env::var("HOME")?
     ))?)?;
@@ -56,15 +52,15 @@ pub fn getprofilepkgs() -> Result<HashMap<String, ProfilePkg>> {
                     .split('.')
                     .collect::<Vec<_>>()
                     .get(2..)
-                    .context("Failed to get legacyPackage attribute")?
+                    .ok_or_else(|| NixDataError::Parse(String::from("Failed to get legacyPackage attribute")))?
                     .join(".")
             } else {
                 format!("{}#{}", originalurl, attrpath)
             };
-            if let Some(first) = pkg.storepaths.get(0) {
+            if let Some(first) = pkg.storepaths.first() {
                 let ver = first
                     .get(44..)
-                    .context("Failed to get pkg name from store path")?;
+                    .ok_or_else(|| NixDataError::Parse(String::from("Failed to get pkg name from store path")))?;
                 out.insert(
                     attr,
                     ProfilePkg {
@@ -86,15 +82,15 @@ pub async fn getprofilepkgs_versioned() -> Result<HashMap<String, String>> {
     }
     let profilepkgs = getprofilepkgs()?;
     let latestpkgs = if Path::new(&format!("{}/nixpkgs.db", &*CACHEDIR)).exists() {
-        format!("{}/nixpkgs.db", &*CACHEDIR)
+        PathBuf::from(format!("{}/nixpkgs.db", &*CACHEDIR))
     } else {
         // Change to something else if overridden
         nixpkgslatest().await?
     };
     let mut out = HashMap::new();
-    let pool = SqlitePool::connect(&format!("sqlite://{}", latestpkgs)).await?;
-    for (pkg, v) in profilepkgs {
-        let mut versions: Vec<(String,)> = sqlx::query_as(
+    let pool = connectdb(&latestpkgs).await?;
+    for (pkg, _v) in profilepkgs {
+        let versions: Vec<(String,)> = sqlx::query_as(
             r#"
             SELECT version FROM pkgs WHERE attribute = $1
             "#,
@@ -103,7 +99,7 @@ pub async fn getprofilepkgs_versioned() -> Result<HashMap<String, String>> {
             .fetch_all(&pool)
             .await?;
         if !versions.is_empty() {
-            out.insert(pkg, versions.get(0).unwrap().0.to_string());
+            out.insert(pkg, versions.first().unwrap().0.to_string());
         }
     }
     Ok(out)
@@ -111,14 +107,14 @@ pub async fn getprofilepkgs_versioned() -> Result<HashMap<String, String>> {
 
 /// Downloads the latest `packages.json` from nixpkgs-unstable
 /// and returns the path to the file.
-pub async fn nixpkgslatest() -> Result<String> {
+pub async fn nixpkgslatest() -> Result<PathBuf> {
     // If cache directory doesn't exist, create it
     if !std::path::Path::new(&*CACHEDIR).exists() {
         std::fs::create_dir_all(&*CACHEDIR)?;
     }
 
     let mut nixpkgsver = None;
-    let regout = Command::new("nix").arg("registry").arg("list").output()?;
+    let regout = Command::new("nix").arg("registry").arg("list").tooloutput()?;
     let reg = String::from_utf8(regout.stdout)?.replace("   ", " ");
     for l in reg.split('\n') {
         let parts = l.split(' ').collect::<Vec<_>>();
@@ -141,7 +137,7 @@ pub async fn nixpkgslatest() -> Result<String> {
         String::from("https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixpkgs-unstable/nixpkgs.ver")
     };
     debug!("Checking nixpkgs version");
-    let resp = reqwest::get(&verurl).await;
+    let resp = httpclient(true)?.get(&verurl).send().await;
     let resp = if let Ok(r) = resp {
         r
     } else {
@@ -150,24 +146,24 @@ pub async fn nixpkgslatest() -> Result<String> {
         let dbpath = format!("{}/nixpkgs.db", &*CACHEDIR);
         if Path::new(&dbpath).exists() {
             info!("Using old database");
-            return Ok(dbpath);
+            return Ok(dbpath.into());
         } else {
-            return Err(anyhow!("Could not find latest nixpkgs version"));
+            return Err(NixDataError::ChannelResolve(String::from("Could not find latest nixpkgs version")));
         }
     };
     let latestnixpkgsver = if resp.status().is_success() {
         resp.text().await?
     } else {
-        return Err(anyhow!("Could not find latest nixpkgs version"));
+        return Err(NixDataError::ChannelResolve(String::from("Could not find latest nixpkgs version")));
     };
     debug!("Latest nixpkgs version: {}", latestnixpkgsver);
 
     // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/nixpkgs.ver", &*CACHEDIR)) {
+    if let Ok(prevver) = fs::read_to_string(format!("{}/nixpkgs.ver", &*CACHEDIR)) {
         if prevver == latestnixpkgsver && Path::new(&format!("{}/nixpkgs.db", &*CACHEDIR)).exists()
         {
             debug!("No new version of nixpkgs found");
-            return Ok(format!("{}/nixpkgs.db", &*CACHEDIR));
+            return Ok(format!("{}/nixpkgs.db", &*CACHEDIR).into());
         }
     }
 
@@ -180,11 +176,11 @@ pub async fn nixpkgslatest() -> Result<String> {
         String::from("https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixpkgs-unstable/nixpkgs_versions.db.br")
     };
     debug!("Downloading nix-data database");
-    let client = reqwest::Client::builder().brotli(true).build()?;
+    let client = httpclient(true)?;
     let resp = client.get(url).send().await?;
     if resp.status().is_success() {
         debug!("Writing nix-data database");
-        let mut out = File::create(&format!("{}/nixpkgs.db", &*CACHEDIR))?;
+        let mut out = File::create(format!("{}/nixpkgs.db", &*CACHEDIR))?;
         {
             let bytes = resp.bytes().await?;
             let mut reader = brotli::Decompressor::new(
@@ -216,16 +212,16 @@ pub async fn nixpkgslatest() -> Result<String> {
         File::create(format!("{}/nixpkgs.ver", &*CACHEDIR))?
             .write_all(latestnixpkgsver.as_bytes())?;
     } else {
-        return Err(anyhow!("Failed to download latest nixpkgs.db.br"));
+        return Err(NixDataError::Download(String::from("Failed to download latest nixpkgs.db.br")));
     }
-    Ok(format!("{}/nixpkgs.db", &*CACHEDIR))
+    Ok(format!("{}/nixpkgs.db", &*CACHEDIR).into())
 }
 
 pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
     let nixpath = Command::new("nix")
         .arg("eval")
         .arg("nixpkgs#path")
-        .output()?
+        .tooloutput()?
         .stdout;
     let nixpath = String::from_utf8(nixpath)?;
     let nixpath = nixpath.trim();
@@ -233,9 +229,9 @@ pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
     let aliases = Command::new("nix-instantiate")
         .arg("--eval")
         .arg("-E")
-        .arg(&format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
+        .arg(format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
         .arg("--json")
-        .output()?;
+        .tooloutput()?;
     let aliasstr = String::from_utf8(aliases.stdout)?;
     let aliasesout: HashSet<String> = serde_json::from_str(&aliasstr)?;
 
@@ -245,13 +241,13 @@ pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
         if aliasesout.contains(pkg) && Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?.status.success() {
+                .arg(format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
+                .tooloutput()?.status.success() {
             let out = Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?;
+                .arg(format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
+                .tooloutput()?;
             let err = String::from_utf8(out.stderr)?;
             let err = err.strip_prefix("error: ").unwrap_or(&err).trim();
             unavailable.insert(pkg.to_string(), err.to_string());
@@ -259,12 +255,13 @@ pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
     }
 
     let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
+    let pool = connectdb(&nixospkgs).await?;
+    requiremeta(&pool).await?;
 
     for pkg in flakespkgs.keys() {
         let (x, broken, insecure): (String, u8, u8) =
             sqlx::query_as("SELECT attribute,broken,insecure FROM meta WHERE attribute = $1")
-                .bind(&pkg)
+                .bind(pkg)
                 .fetch_one(&pool)
                 .await?;
         if &x != pkg {
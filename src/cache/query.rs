@@ -0,0 +1,1750 @@
+use crate::error::{tooloutput_async, NixDataError, Result};
+use futures::{stream, Stream, StreamExt};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteConnectOptions, FromRow, QueryBuilder, SqlitePool};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+use super::{columnexists, connectdb, getmetainfo, nixos::queryversions, requiremeta, tableexists};
+
+/// Details about a package, combining its entries in the `pkgs` and `meta` tables of a package database.
+/// Serializes to an object with the field names below, with `null` for missing values.
+#[derive(Debug, Clone, PartialEq, Eq, Default, FromRow, Serialize, Deserialize)]
+pub struct NixPackage {
+    /// Attribute path, e.g. `python3Packages.requests`.
+    pub attribute: String,
+    /// Package name without the version, e.g. `python3.10-requests`.
+    pub pname: Option<String>,
+    /// Package version.
+    pub version: String,
+    /// Short description of the package.
+    pub description: Option<String>,
+    /// Whether the package is marked as broken.
+    pub broken: bool,
+    /// Whether the package is marked as insecure.
+    pub insecure: bool,
+    /// Whether the package has an unfree license.
+    pub unfree: bool,
+    /// Homepage of the package. For packages with several homepages, this is the first;
+    /// see [package_homepages()](super::meta::package_homepages) for all of them.
+    pub homepage: Option<String>,
+}
+
+/// Serializes `value`, such as a [NixPackage] or a list of them, as compact JSON, e.g. for output from a command line tool.
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+/// Like [to_json()], but indents the output to be read by people.
+pub fn to_json_pretty<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string_pretty(value)?)
+}
+
+/// Columns selected to build a [NixPackage], for a query joining `pkgs` with `meta`.
+pub(super) const PACKAGECOLUMNS: &str = r#"
+    pkgs.attribute AS attribute,
+    pkgs.pname AS pname,
+    COALESCE(pkgs.version, '') AS version,
+    meta.description AS description,
+    COALESCE(meta.broken, 0) AS broken,
+    COALESCE(meta.insecure, 0) AS insecure,
+    COALESCE(meta.unfree, 0) AS unfree,
+    CASE WHEN meta.homepage LIKE '[%' THEN json_extract(meta.homepage, '$[0]') ELSE meta.homepage END AS homepage
+"#;
+
+/// Looks up each attribute in `attributes` in a database containing both `pkgs` and `meta` tables.
+/// Attributes missing from `pkgs` are omitted from the output.
+pub(super) async fn querypackages(
+    pool: &SqlitePool,
+    attributes: impl IntoIterator<Item = &String>,
+) -> Result<HashMap<String, NixPackage>> {
+    let attributes = attributes.into_iter().collect::<Vec<_>>();
+    let mut out = HashMap::new();
+    // Stay well below SQLite's limit on the number of bound parameters
+    for chunk in attributes.chunks(500) {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT {} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute WHERE pkgs.attribute IN (",
+            PACKAGECOLUMNS
+        ));
+        let mut separated = query.separated(", ");
+        for attribute in chunk {
+            separated.push_bind(attribute.as_str());
+        }
+        separated.push_unseparated(")");
+        let pkgs: Vec<NixPackage> = query.build_query_as().fetch_all(pool).await?;
+        out.extend(pkgs.into_iter().map(|x| (x.attribute.clone(), x)));
+    }
+    Ok(out)
+}
+
+/// Returns everything known about `attribute` in the package database at `db`, from both its `pkgs` and `meta` tables.
+/// Returns `None` if the attribute doesn't exist. Attributes without a `meta` entry have empty metadata.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn package_info(db: impl AsRef<Path>, attribute: &str) -> Result<Option<NixPackage>> {
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    let pkg = sqlx::query_as(&format!(
+        "SELECT {} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute WHERE pkgs.attribute = $1",
+        PACKAGECOLUMNS
+    ))
+    .bind(attribute)
+    .fetch_optional(&pool)
+    .await?;
+    Ok(pkg)
+}
+
+/// Returns the `pname`s shared by the most attributes in the package database at `db`, with the number of attributes sharing each,
+/// ordered from most to least common. At most `limit` pnames are returned.
+///
+/// This highlights version proliferation in a channel, such as the many `linux_x_y` kernel attributes sharing the `linux` pname.
+/// Requires a database with a `pname` column, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn pname_collisions(db: impl AsRef<Path>, limit: usize) -> Result<Vec<(String, usize)>> {
+    let pool = connectdb(&db).await?;
+    let rows: Vec<(String, i64)> = sqlx::query_as(
+        r#"
+        SELECT pname, COUNT(*) AS count FROM pkgs
+        WHERE pname IS NOT NULL
+        GROUP BY pname
+        ORDER BY count DESC, pname
+        LIMIT $1
+        "#,
+    )
+    .bind(limit as i64)
+    .fetch_all(&pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(pname, count)| (pname, count as usize))
+        .collect())
+}
+
+/// Returns the attributes with the given `pname` in the package database at `db`, with their versions.
+/// Uses the index on `pname`, so unlike a search it doesn't scan the whole database.
+/// Databases built without pnames, such as flake databases built from nixpkgs-version-data, have no matches.
+pub async fn pname_attributes(db: impl AsRef<Path>, pname: &str) -> Result<HashMap<String, String>> {
+    let pool = connectdb(&db).await?;
+    querypname(&pool, pname).await
+}
+
+/// Returns every package with the given `pname` in the package database at `db`, ordered by attribute.
+/// Several attributes often share a pname, such as the versioned variants `firefox` and `firefox-esr`.
+/// Uses the index on `pname`. Databases without a `meta` table, such as flake databases, give packages with empty metadata,
+/// and databases built without pnames have no matches.
+pub async fn find_by_pname(db: impl AsRef<Path>, pname: &str) -> Result<Vec<NixPackage>> {
+    let pool = connectdb(&db).await?;
+    if !columnexists(&pool, "pkgs", "pname").await? {
+        return Ok(vec![]);
+    }
+    if !tableexists(&pool, "meta").await? {
+        let mut pkgs = querypname(&pool, pname)
+            .await?
+            .into_iter()
+            .map(|(attribute, version)| NixPackage {
+                attribute,
+                pname: Some(pname.to_string()),
+                version,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        pkgs.sort_by(|a, b| a.attribute.cmp(&b.attribute));
+        return Ok(pkgs);
+    }
+    let pkgs = sqlx::query_as(&format!(
+        "SELECT {} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute WHERE pkgs.pname = $1 ORDER BY pkgs.attribute",
+        PACKAGECOLUMNS
+    ))
+    .bind(pname)
+    .fetch_all(&pool)
+    .await?;
+    Ok(pkgs)
+}
+
+/// Whether `attribute` is in the package database at `db`, without fetching its version or metadata.
+pub async fn attribute_exists(db: impl AsRef<Path>, attribute: &str) -> Result<bool> {
+    let pool = connectdb(&db).await?;
+    let row: Option<(i64,)> = sqlx::query_as(r#"SELECT 1 FROM pkgs WHERE attribute = $1 LIMIT 1"#)
+        .bind(attribute)
+        .fetch_optional(&pool)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Like [attribute_exists()], but checks every attribute in `attributes` at once,
+/// e.g. to warn about packages in a configuration that are missing from a new channel.
+/// Every attribute is in the output, mapped to whether it exists.
+pub async fn attributes_exist(db: impl AsRef<Path>, attributes: &[&str]) -> Result<HashMap<String, bool>> {
+    let pool = connectdb(&db).await?;
+    let mut out = attributes
+        .iter()
+        .map(|x| (x.to_string(), false))
+        .collect::<HashMap<_, _>>();
+    for chunk in attributes.chunks(500) {
+        let mut query = QueryBuilder::new("SELECT attribute FROM pkgs WHERE attribute IN (");
+        let mut separated = query.separated(", ");
+        for attribute in chunk {
+            separated.push_bind(*attribute);
+        }
+        separated.push_unseparated(")");
+        let rows: Vec<(String,)> = query.build_query_as().fetch_all(&pool).await?;
+        for (attribute,) in rows {
+            out.insert(attribute, true);
+        }
+    }
+    Ok(out)
+}
+
+/// Returns up to `limit` packages from the package database at `db` ordered by attribute, skipping the first `offset`,
+/// so that the whole package set can be paged through without loading it all at once. Returns no packages past the end.
+/// Databases without a `meta` table, such as flake databases, give packages with empty metadata.
+pub async fn all_packages(db: impl AsRef<Path>, offset: usize, limit: usize) -> Result<Vec<NixPackage>> {
+    let pool = connectdb(&db).await?;
+    let sql = if tableexists(&pool, "meta").await? {
+        format!(
+            "SELECT {} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute ORDER BY pkgs.attribute LIMIT $1 OFFSET $2",
+            PACKAGECOLUMNS
+        )
+    } else {
+        let pname = if columnexists(&pool, "pkgs", "pname").await? {
+            "pname"
+        } else {
+            "NULL"
+        };
+        format!(
+            r#"
+            SELECT attribute, {} AS pname, COALESCE(version, '') AS version, NULL AS description,
+                0 AS broken, 0 AS insecure, 0 AS unfree, NULL AS homepage
+            FROM pkgs ORDER BY attribute LIMIT $1 OFFSET $2
+            "#,
+            pname
+        )
+    };
+    let pkgs = sqlx::query_as(&sql)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&pool)
+        .await?;
+    Ok(pkgs)
+}
+
+/// Returns up to `limit` attributes in the package database at `db` starting with `prefix`, in alphabetical order,
+/// e.g. for tab completion. Unlike a search, the match is anchored to the start of the attribute and case sensitive,
+/// so it can use the index on `attribute`.
+pub async fn complete_attribute(db: impl AsRef<Path>, prefix: &str, limit: usize) -> Result<Vec<String>> {
+    let pool = connectdb(&db).await?;
+    let rows: Vec<(String,)> = sqlx::query_as(
+        r#"SELECT attribute FROM pkgs WHERE attribute GLOB $1 ORDER BY attribute LIMIT $2"#,
+    )
+    .bind(format!("{}*", escapeglob(prefix)))
+    .bind(limit as i64)
+    .fetch_all(&pool)
+    .await?;
+    Ok(rows.into_iter().map(|(x,)| x).collect())
+}
+
+/// Escapes `*`, `?` and `[` so `text` is matched literally in a `GLOB` pattern.
+fn escapeglob(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '*' | '?' | '[' => format!("[{}]", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Returns the package with the given `pname` and `version` in the package database at `db`,
+/// e.g. to find the package a store path such as `/nix/store/<hash>-firefox-120.0` was built from.
+/// If several attributes match, such as an alias and the package it points to, the shortest attribute is returned,
+/// as top-level attributes are usually the canonical ones. Returns `None` if no package matches.
+pub async fn package_by_name_version(
+    db: impl AsRef<Path>,
+    pname: &str,
+    version: &str,
+) -> Result<Option<NixPackage>> {
+    Ok(find_by_pname(db, pname)
+        .await?
+        .into_iter()
+        .filter(|x| x.version == version)
+        .min_by(|a, b| {
+            a.attribute
+                .len()
+                .cmp(&b.attribute.len())
+                .then_with(|| a.attribute.cmp(&b.attribute))
+        }))
+}
+
+async fn querypname(pool: &SqlitePool, pname: &str) -> Result<HashMap<String, String>> {
+    if !columnexists(pool, "pkgs", "pname").await? {
+        return Ok(HashMap::new());
+    }
+    let rows: Vec<(String, String)> =
+        sqlx::query_as(r#"SELECT attribute, version FROM pkgs WHERE pname = $1"#)
+            .bind(pname)
+            .fetch_all(pool)
+            .await?;
+    Ok(rows.into_iter().collect())
+}
+
+/// Returns the system the package database at `db` was built for, e.g. `x86_64-linux`.
+/// Returns `None` for databases that don't record it, such as ones downloaded by older versions of this crate.
+pub async fn db_system(db: impl AsRef<Path>) -> Result<Option<String>> {
+    let pool = connectdb(&db).await?;
+    getmetainfo(&pool, "system").await
+}
+
+/// Returns the channel version the package database at `db` was built from, as stored in the database itself.
+/// Unlike the `.ver` file next to it, this can't get out of sync with the database.
+/// Returns `None` for databases that don't record it, such as ones downloaded by older versions of this crate.
+pub async fn db_version(db: impl AsRef<Path>) -> Result<Option<String>> {
+    let pool = connectdb(&db).await?;
+    getmetainfo(&pool, "version").await
+}
+
+/// Returns the nixpkgs git revision the package database at `db` was built from,
+/// for example to link packages to their source in nixpkgs at the right commit.
+/// Recorded by [flakespkgs()](super::flakes::flakespkgs), [flakespkgs_for()](super::flakes::flakespkgs_for),
+/// [legacypkgs()](super::channel::legacypkgs) and [localpkgs()](super::channel::localpkgs). Returns `None` if the database doesn't record it,
+/// including when the revision couldn't be found while the database was built.
+pub async fn db_revision(db: impl AsRef<Path>) -> Result<Option<String>> {
+    let pool = connectdb(&db).await?;
+    getmetainfo(&pool, "revision").await
+}
+
+/// How a package database was built, as returned by [db_source()].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DbSource {
+    /// Downloaded from the versions published for each nixpkgs revision in nixpkgs-version-data. These have no pnames.
+    VersionData,
+    /// Downloaded from the `packages.json` published with the NixOS release.
+    Release,
+    /// Evaluated locally with `nix search`.
+    NixSearch,
+    /// Evaluated locally with `nix-env`, as by [localpkgs()](super::channel::localpkgs).
+    Evaluation,
+}
+
+impl DbSource {
+    /// Name of the source, as stored in the database.
+    pub(super) fn as_str(&self) -> &'static str {
+        match self {
+            DbSource::VersionData => "nixpkgs-version-data",
+            DbSource::Release => "release",
+            DbSource::NixSearch => "nix-search",
+            DbSource::Evaluation => "nix-env",
+        }
+    }
+
+    fn parse(source: &str) -> Option<Self> {
+        [
+            DbSource::VersionData,
+            DbSource::Release,
+            DbSource::NixSearch,
+            DbSource::Evaluation,
+        ]
+        .into_iter()
+        .find(|x| x.as_str() == source)
+    }
+}
+
+/// Returns how the package database at `db` was built, e.g. whether [legacypkgs()](super::channel::legacypkgs)
+/// downloaded it or evaluated nixpkgs locally. Recorded by the functions building databases for flake and legacy systems
+/// and [localpkgs()](super::channel::localpkgs). Returns `None` for databases that don't record it.
+pub async fn db_source(db: impl AsRef<Path>) -> Result<Option<DbSource>> {
+    let pool = connectdb(&db).await?;
+    Ok(getmetainfo(&pool, "source")
+        .await?
+        .and_then(|x| DbSource::parse(&x)))
+}
+
+/// Package counts and version of a package database, returned by [db_stats()].
+/// Serializes to an object with the field names below.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbStats {
+    /// Number of packages in the database.
+    pub total: usize,
+    /// Number of packages marked as broken.
+    pub broken: usize,
+    /// Number of packages with an unfree license.
+    pub unfree: usize,
+    /// Version of the channel the database was built from, as returned by [db_version()],
+    /// or read from the `.ver` file next to the database if it doesn't record it.
+    /// `None` if neither is available, as for databases built by [flakespkgs_for()](super::flakes::flakespkgs_for).
+    pub version: Option<String>,
+}
+
+/// Returns the number of packages in the package database at `db`, how many of them are broken or unfree, and its channel version.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn db_stats(db: impl AsRef<Path>) -> Result<DbStats> {
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    let (total,): (i64,) = sqlx::query_as(r#"SELECT COUNT(*) FROM pkgs"#)
+        .fetch_one(&pool)
+        .await?;
+    let (broken, unfree): (i64, i64) = sqlx::query_as(
+        r#"SELECT COALESCE(SUM(broken = 1), 0), COALESCE(SUM(unfree = 1), 0) FROM meta"#,
+    )
+    .fetch_one(&pool)
+    .await?;
+    let version = match getmetainfo(&pool, "version").await? {
+        Some(version) => Some(version),
+        None => fs::read_to_string(db.as_ref().with_extension("ver"))
+            .ok()
+            .map(|x| x.trim().to_string()),
+    };
+    Ok(DbStats {
+        total: total as usize,
+        broken: broken as usize,
+        unfree: unfree as usize,
+        version,
+    })
+}
+
+/// Packages that differ between two package databases, returned by [diff_dbs()].
+/// Serializes to an object with the field names below, with each entry of `changed` as an `[attribute, old, new]` array.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct PkgDiff {
+    /// Packages only in the new database.
+    pub added: Vec<NixPackage>,
+    /// Attributes only in the old database.
+    pub removed: Vec<String>,
+    /// Attributes in both databases with a different version, with their old and new version.
+    pub changed: Vec<(String, String, String)>,
+}
+
+/// Compares the package databases at `old` and `new`, e.g. the databases of two channel versions,
+/// returning which packages were added, removed or changed version. Each list is sorted by attribute.
+/// The databases are joined in SQLite rather than loaded into memory.
+/// `new` requires a `meta` table, such as the database returned by [nixospkgs()](super::nixos::nixospkgs).
+pub async fn diff_dbs(old: impl AsRef<Path>, new: impl AsRef<Path>) -> Result<PkgDiff> {
+    let pool = connectdb(&new).await?;
+    requiremeta(&pool).await?;
+    // Attached databases are per connection, so every query runs on the same one
+    let mut conn = pool.acquire().await?;
+    sqlx::query(r#"ATTACH DATABASE $1 AS prev"#)
+        .bind(old.as_ref().to_string_lossy())
+        .execute(&mut *conn)
+        .await?;
+    let added = sqlx::query_as(&format!(
+        r#"
+        SELECT DISTINCT {} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute
+        WHERE pkgs.attribute NOT IN (SELECT attribute FROM prev.pkgs)
+        ORDER BY pkgs.attribute
+        "#,
+        PACKAGECOLUMNS
+    ))
+    .fetch_all(&mut *conn)
+    .await?;
+    let removed: Vec<(String,)> = sqlx::query_as(
+        r#"
+        SELECT DISTINCT attribute FROM prev.pkgs
+        WHERE attribute NOT IN (SELECT attribute FROM main.pkgs)
+        ORDER BY attribute
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let changed = sqlx::query_as(
+        r#"
+        SELECT DISTINCT new.attribute, COALESCE(old.version, ''), COALESCE(new.version, '')
+        FROM main.pkgs AS new JOIN prev.pkgs AS old ON new.attribute = old.attribute
+        WHERE new.version IS NOT old.version
+        ORDER BY new.attribute
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(PkgDiff {
+        added,
+        removed: removed.into_iter().map(|(x,)| x).collect(),
+        changed,
+    })
+}
+
+/// Stores closure sizes (in bytes) for the attributes in `sizes` in a `sizes` table in the package database at `db`,
+/// creating the table if needed. Existing sizes for the same attributes are replaced.
+///
+/// Neither the channel `packages.json` nor the prebuilt nix-data databases contain size information,
+/// so this data has to come from elsewhere, for example by summing the `NarSize` of every path in the closure
+/// as reported by the `.narinfo` files on `cache.nixos.org`, or from `nix path-info --closure-size`.
+pub async fn importclosuresizes(db: impl AsRef<Path>, sizes: &HashMap<String, u64>) -> Result<()> {
+    let pool = connectdb(&db).await?;
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "sizes" (
+            "attribute"	TEXT NOT NULL UNIQUE,
+            "closuresize"	INTEGER,
+            PRIMARY KEY("attribute")
+        )
+        "#,
+    )
+    .execute(&mut tx)
+    .await?;
+    for (attribute, size) in sizes {
+        sqlx::query(r#"INSERT OR REPLACE INTO "sizes" VALUES ($1, $2)"#)
+            .bind(attribute)
+            .bind(*size as i64)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Returns the closure size (in bytes) of `attribute` in the package database at `db`,
+/// i.e. roughly how much would be downloaded to install it on a system with an empty store.
+/// Returns `None` if no size is known. Sizes are only available once imported with [importclosuresizes()].
+pub async fn closure_size(db: impl AsRef<Path>, attribute: &str) -> Result<Option<u64>> {
+    let pool = connectdb(&db).await?;
+    if !tableexists(&pool, "sizes").await? {
+        return Ok(None);
+    }
+    let row: Option<(Option<i64>,)> =
+        sqlx::query_as(r#"SELECT closuresize FROM sizes WHERE attribute = $1"#)
+            .bind(attribute)
+            .fetch_optional(&pool)
+            .await?;
+    Ok(row.and_then(|(size,)| size).map(|x| x as u64))
+}
+
+/// Stores a list of `(attribute, file path)` pairs in a `files` table in the package database at `db`,
+/// creating the table if needed. Paths are relative to the package output, e.g. `include/openssl/ssl.h`.
+///
+/// This data isn't part of `packages.json`, and is typically generated from a [nix-index](https://github.com/nix-community/nix-index) database.
+pub async fn importfiles(db: impl AsRef<Path>, files: &[(String, String)]) -> Result<()> {
+    let pool = connectdb(&db).await?;
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "files" (
+            "attribute"	TEXT NOT NULL,
+            "path"	TEXT NOT NULL,
+            UNIQUE("attribute", "path")
+        )
+        "#,
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(r#"CREATE INDEX IF NOT EXISTS "filepaths" ON "files" ("path")"#)
+        .execute(&mut tx)
+        .await?;
+    for (attribute, path) in files {
+        sqlx::query(r#"INSERT OR IGNORE INTO "files" VALUES ($1, $2)"#)
+            .bind(attribute)
+            .bind(path.trim_start_matches('/'))
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Returns the packages providing a file whose path ends with `path`, e.g. `openssl/ssl.h` or `bin/ls`.
+///
+/// If the package database at `db` contains file data (see [importfiles()]), it is used.
+/// Otherwise this falls back to querying the local nix-index database with `nix-locate`,
+/// in which case attributes are returned with the output containing the file, e.g. `openssl.dev`.
+/// Fails with [NixDataError::MissingTool] if the database has no file data and `nix-locate` isn't installed.
+pub async fn package_providing_file(db: impl AsRef<Path>, path: &str) -> Result<Vec<String>> {
+    let path = path.trim_start_matches('/');
+    let pool = connectdb(&db).await?;
+    if tableexists(&pool, "files").await? {
+        let rows: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT attribute FROM files
+            WHERE path = $1 OR path LIKE '%/' || $2 ESCAPE '\'
+            ORDER BY attribute
+            "#,
+        )
+        .bind(path)
+        .bind(escapelike(path))
+        .fetch_all(&pool)
+        .await?;
+        return Ok(rows.into_iter().map(|(x,)| x).collect());
+    }
+
+    debug!("No file data in database, querying nix-locate");
+    let output = tooloutput_async(
+        tokio::process::Command::new("nix-locate")
+            .arg("--minimal")
+            .arg("--top-level")
+            .arg(format!("/{}", path)),
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(NixDataError::Other(format!(
+            "nix-locate failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let mut out = String::from_utf8(output.stdout)?
+        .lines()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+    out.sort();
+    out.dedup();
+    Ok(out)
+}
+
+/// Escapes `%`, `_` and `\` so `text` is matched literally in a `LIKE ... ESCAPE '\'` pattern.
+fn escapelike(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Writes every package in the package database at `db` to `writer` as tab separated `attribute`, `pname` and `version` columns,
+/// with a header line, sorted by attribute.
+///
+/// The output is deterministic, so committing successive exports to version control gives clean line-based diffs
+/// of what changed between channel versions. Databases without a `pname` column (such as those built by
+/// [flakespkgs()](super::flakes::flakespkgs)) have an empty `pname` column.
+pub async fn export_stable_tsv<W: Write>(db: impl AsRef<Path>, mut writer: W) -> Result<()> {
+    let pool = connectdb(&db).await?;
+    let query = if columnexists(&pool, "pkgs", "pname").await? {
+        r#"SELECT attribute, pname, version FROM pkgs ORDER BY attribute"#
+    } else {
+        r#"SELECT attribute, NULL, version FROM pkgs ORDER BY attribute"#
+    };
+    let rows: Vec<(String, Option<String>, Option<String>)> =
+        sqlx::query_as(query).fetch_all(&pool).await?;
+
+    fn field(x: &str) -> String {
+        x.replace(['\t', '\n', '\r'], " ")
+    }
+    writeln!(writer, "attribute\tpname\tversion")?;
+    for (attribute, pname, version) in rows {
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            field(&attribute),
+            field(&pname.unwrap_or_default()),
+            field(&version.unwrap_or_default())
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Which packages to return from searches, based on the flags in the `meta` table.
+/// The default excludes broken, insecure and unsupported packages, which are unlikely to build, but includes unfree ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageFilter {
+    /// Include packages marked as broken.
+    pub include_broken: bool,
+    /// Include packages marked as insecure.
+    pub include_insecure: bool,
+    /// Include packages not supported on the system the database was built for.
+    pub include_unsupported: bool,
+    /// Include packages with an unfree license.
+    pub include_unfree: bool,
+    /// Only include packages whose attribute matches this glob, e.g. `gnomeExtensions.*` to search within a package set.
+    /// Matching is case sensitive, with `*` matching any characters and `?` any single character. `None` to include all attributes.
+    pub attribute_glob: Option<String>,
+}
+
+impl Default for PackageFilter {
+    fn default() -> Self {
+        PackageFilter {
+            include_broken: false,
+            include_insecure: false,
+            include_unsupported: false,
+            include_unfree: true,
+            attribute_glob: None,
+        }
+    }
+}
+
+impl PackageFilter {
+    /// Returns this filter as `WHERE` conditions on a query joining `meta`, each starting with ` AND `.
+    /// Databases without an `unsupported` column don't filter on it.
+    /// The [attribute glob](PackageFilter::attribute_glob) is compared to the parameter `$<param>`,
+    /// which callers bind to the glob whether it is set or not.
+    async fn conditions(&self, pool: &SqlitePool, param: usize) -> Result<String> {
+        let mut conditions = String::new();
+        for (include, column) in [
+            (self.include_broken, "broken"),
+            (self.include_insecure, "insecure"),
+            (self.include_unsupported, "unsupported"),
+            (self.include_unfree, "unfree"),
+        ] {
+            if !include && columnexists(pool, "meta", column).await? {
+                conditions.push_str(&format!(" AND COALESCE(meta.{}, 0) = 0", column));
+            }
+        }
+        if self.attribute_glob.is_some() {
+            conditions.push_str(&format!(" AND pkgs.attribute GLOB ${}", param));
+        }
+        Ok(conditions)
+    }
+}
+
+/// Flag in the `meta` table of a package database, for [list_flagged()].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PackageFlag {
+    /// Packages marked as broken.
+    Broken,
+    /// Packages marked as insecure.
+    Insecure,
+    /// Packages not supported on the system the database was built for.
+    Unsupported,
+    /// Packages with an unfree license.
+    Unfree,
+}
+
+impl PackageFlag {
+    fn column(&self) -> &'static str {
+        match self {
+            PackageFlag::Broken => "broken",
+            PackageFlag::Insecure => "insecure",
+            PackageFlag::Unsupported => "unsupported",
+            PackageFlag::Unfree => "unfree",
+        }
+    }
+}
+
+/// Returns every package in the package database at `db` with `flag` set, sorted by attribute,
+/// for example to audit which packages of a channel are broken.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+/// Databases that don't record `flag` return no packages.
+pub async fn list_flagged(db: impl AsRef<Path>, flag: PackageFlag) -> Result<Vec<NixPackage>> {
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    if !columnexists(&pool, "meta", flag.column()).await? {
+        return Ok(vec![]);
+    }
+    let sql = format!(
+        r#"
+        SELECT {} FROM pkgs JOIN meta ON pkgs.attribute = meta.attribute
+        WHERE meta.{} = 1
+        ORDER BY pkgs.attribute
+        "#,
+        PACKAGECOLUMNS,
+        flag.column()
+    );
+    let pkgs = sqlx::query_as(&sql).fetch_all(&pool).await?;
+    Ok(pkgs)
+}
+
+/// Searches the package database at `db` for packages whose `pname` or description contains `query` (case insensitive).
+/// Returns at most `limit` packages matching `filter`, ordered by relevance: exact `pname` matches first, then `pname` prefix matches,
+/// then other `pname` matches, and finally description matches.
+/// Requires a database with a `meta` table, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+///
+/// Exact and prefix matches are looked up on an index of `pname`, built along with the full-text index (see [fts_search()]),
+/// comparing against the lowercased query as pnames in nixpkgs are lowercase by convention.
+/// The substring and description matches need a full scan of the table,
+/// so they are only searched if the exact and prefix matches don't already fill `limit`.
+pub async fn searchpkgs(
+    db: impl AsRef<Path>,
+    query: &str,
+    limit: usize,
+    filter: &PackageFilter,
+) -> Result<Vec<NixPackage>> {
+    PackageDb::open(db).await?.search(query, limit, filter).await
+}
+
+/// Like [searchpkgs()], but only returns packages available on `platform`, e.g. `aarch64-darwin`.
+///
+/// Packages are available on a platform if it is listed in their `meta.platforms`. Packages whose platforms are given
+/// by a more complex predicate than a list of systems, or that have no platforms recorded, are left out.
+pub async fn searchpkgs_for_platform(
+    db: impl AsRef<Path>,
+    query: &str,
+    limit: usize,
+    filter: &PackageFilter,
+    platform: &str,
+) -> Result<Vec<NixPackage>> {
+    PackageDb::open(db)
+        .await?
+        .search_for_platform(query, limit, filter, platform)
+        .await
+}
+
+async fn searchpool(
+    pool: &SqlitePool,
+    query: &str,
+    limit: usize,
+    filter: &PackageFilter,
+    platform: Option<&str>,
+) -> Result<Vec<NixPackage>> {
+    requiremeta(pool).await?;
+    let lower = query.to_lowercase();
+    let escaped = escapelike(query);
+    let mut conditions = filter.conditions(pool, 7).await?;
+    if platform.is_some() {
+        conditions.push_str(
+            r#" AND json_type(meta.platforms) = 'array'
+                AND EXISTS (SELECT 1 FROM json_each(meta.platforms) WHERE json_each.value = $6)"#,
+        );
+    }
+    let sql = searchsql(&conditions);
+    let pkgs = sqlx::query_as(&sql)
+        .bind(&lower)
+        // Every pname starting with `lower` sorts below this
+        .bind(format!("{}\u{10FFFF}", lower))
+        .bind(format!("{}%", escaped))
+        .bind(format!("%{}%", escaped))
+        .bind(limit as i64)
+        // Only used if `platform` is set
+        .bind(platform)
+        .bind(filter.attribute_glob.as_deref())
+        .fetch_all(pool)
+        .await?;
+    Ok(pkgs)
+}
+
+/// Like [searchpkgs()], but returns every match as a stream, so that results can be shown as they arrive
+/// and the search stops early once the stream is dropped. Rows are read in the background, a few ahead of the consumer.
+///
+/// ```no_run
+/// # async fn example() -> nix_data::error::Result<()> {
+/// use futures::StreamExt;
+/// use nix_data::cache::query::{search_stream, PackageFilter};
+///
+/// let results = search_stream("nixospkgs.db", "firefox", &PackageFilter::default()).await?;
+/// // Only the first ten results are read
+/// let first = results.take(10).collect::<Vec<_>>().await;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn search_stream(
+    db: impl AsRef<Path>,
+    query: &str,
+    filter: &PackageFilter,
+) -> Result<impl Stream<Item = Result<NixPackage>>> {
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    let sql = searchsql(&filter.conditions(&pool, 7).await?);
+    let (stream, _) = spawnsearch(pool, sql, query, filter.attribute_glob.clone());
+    Ok(stream)
+}
+
+/// Runs the search query `sql` from [searchsql()] on `pool` in a background task, returning the stream of its results
+/// and a handle to the task, which ends once every row is read or the stream is dropped.
+fn spawnsearch(
+    pool: SqlitePool,
+    sql: String,
+    query: &str,
+    glob: Option<String>,
+) -> (impl Stream<Item = Result<NixPackage>>, JoinHandle<()>) {
+    let lower = query.to_lowercase();
+    let escaped = escapelike(query);
+    let (tx, rx) = mpsc::channel(64);
+    let task = tokio::spawn(async move {
+        // A negative limit returns every row
+        let mut rows = sqlx::query_as::<_, NixPackage>(&sql)
+            .bind(&lower)
+            .bind(format!("{}\u{10FFFF}", lower))
+            .bind(format!("{}%", escaped))
+            .bind(format!("%{}%", escaped))
+            .bind(-1)
+            .bind(None::<&str>)
+            .bind(glob)
+            .fetch(&pool);
+        while let Some(row) = rows.next().await {
+            // Fails once the stream is dropped
+            if tx.send(row.map_err(NixDataError::from)).await.is_err() {
+                break;
+            }
+        }
+    });
+    let stream = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|x| (x, rx)) });
+    (stream, task)
+}
+
+/// Query used by [searchpkgs()], with the `conditions` of a [PackageFilter] applied to each part.
+/// Each part keeps its own order, and SQLite stops evaluating parts once the limit is reached.
+fn searchsql(conditions: &str) -> String {
+    format!(
+        r#"
+        SELECT * FROM (
+            SELECT {columns} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute
+            WHERE pkgs.pname = $1{conditions}
+            ORDER BY pkgs.attribute
+        )
+        UNION ALL
+        SELECT * FROM (
+            SELECT {columns} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute
+            WHERE pkgs.pname > $1 AND pkgs.pname < $2{conditions}
+            ORDER BY pkgs.attribute
+        )
+        UNION ALL
+        SELECT * FROM (
+            SELECT {columns} FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute
+            WHERE (pkgs.pname IS NULL OR pkgs.pname < $1 OR pkgs.pname >= $2)
+                AND (pkgs.pname LIKE $4 ESCAPE '\' OR meta.description LIKE $4 ESCAPE '\'){conditions}
+            ORDER BY
+                CASE
+                    WHEN pkgs.pname LIKE $3 ESCAPE '\' THEN 0
+                    WHEN pkgs.pname LIKE $4 ESCAPE '\' THEN 1
+                    ELSE 2
+                END,
+                pkgs.attribute
+        )
+        LIMIT $5
+        "#,
+        columns = PACKAGECOLUMNS,
+        conditions = conditions
+    )
+}
+
+/// An open, read-only connection to a package database, for applications making many lookups.
+/// The free functions in this crate connect to the database on every call instead.
+///
+/// Databases are replaced with a new file when they are updated, e.g. by [nixospkgs()](super::nixos::nixospkgs),
+/// so a database opened with [open()](PackageDb::open) is checked before each query and reopened if its file was replaced.
+/// Clones share the connection, so reopening one reopens all of them.
+#[derive(Debug, Clone)]
+pub struct PackageDb {
+    state: Arc<Mutex<PackageDbState>>,
+}
+
+#[derive(Debug)]
+struct PackageDbState {
+    pool: SqlitePool,
+    /// Path of the database and the version of the file that is open, for databases opened with [PackageDb::open()].
+    file: Option<(PathBuf, Option<FileVersion>)>,
+}
+
+/// Modification time and size of a database file, which change when it is replaced.
+type FileVersion = (SystemTime, u64);
+
+fn fileversion(path: &Path) -> Option<FileVersion> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+async fn openreadonly(path: &Path) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    Ok(SqlitePool::connect_with(options).await?)
+}
+
+impl PackageDb {
+    /// Opens the package database at `db` read-only, such as one returned by [nixospkgs()](super::nixos::nixospkgs).
+    pub async fn open(db: impl AsRef<Path>) -> Result<Self> {
+        let path = db.as_ref().to_path_buf();
+        let version = fileversion(&path);
+        let pool = openreadonly(&path).await?;
+        Ok(PackageDb {
+            state: Arc::new(Mutex::new(PackageDbState {
+                pool,
+                file: Some((path, version)),
+            })),
+        })
+    }
+
+    /// Uses the already open database `pool`, such as an in-memory database built with
+    /// [createdb_in()](super::nixos::createdb_in). Unlike [open()](PackageDb::open), the database isn't made read-only,
+    /// and isn't reopened as there is no file to check.
+    pub fn from_pool(pool: SqlitePool) -> Self {
+        PackageDb {
+            state: Arc::new(Mutex::new(PackageDbState { pool, file: None })),
+        }
+    }
+
+    /// Reopens the database file, whether or not it was replaced. Does nothing for databases given to [from_pool()](PackageDb::from_pool).
+    pub async fn reload(&self) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if let Some((path, version)) = &mut state.file {
+            *version = fileversion(path);
+            let pool = openreadonly(path).await?;
+            state.pool = pool;
+        }
+        Ok(())
+    }
+
+    /// Returns the pool to query, reopening the database first if its file was replaced.
+    /// Queries already running on the previous pool finish on it.
+    async fn pool(&self) -> Result<SqlitePool> {
+        let mut state = self.state.lock().await;
+        if let Some((path, version)) = &state.file {
+            let current = fileversion(path);
+            if current.is_some() && current != *version {
+                debug!("{} was replaced, reopening it", path.display());
+                let pool = openreadonly(path).await?;
+                state.file = Some((path.clone(), current));
+                state.pool = pool;
+            }
+        }
+        Ok(state.pool.clone())
+    }
+
+    /// Looks up the version of each attribute in `attributes`.
+    /// Attributes that are missing, or that have several rows (e.g. for several systems), are omitted from the output.
+    pub async fn lookup(&self, attributes: &[&str]) -> Result<HashMap<String, String>> {
+        queryversions(&self.pool().await?, attributes.iter().map(|x| x.to_string())).await
+    }
+
+    /// Like [lookup()](PackageDb::lookup), but only considers the rows for `system` (e.g. `aarch64-linux`),
+    /// for databases holding packages for several systems.
+    pub async fn lookup_for_system(
+        &self,
+        attributes: &[&str],
+        system: &str,
+    ) -> Result<HashMap<String, String>> {
+        let pool = self.pool().await?;
+        let mut out = HashMap::new();
+        for chunk in attributes.chunks(500) {
+            let mut query = QueryBuilder::new("SELECT attribute, version FROM pkgs WHERE system = ");
+            query.push_bind(system);
+            query.push(" AND attribute IN (");
+            let mut separated = query.separated(", ");
+            for attribute in chunk {
+                separated.push_bind(*attribute);
+            }
+            separated.push_unseparated(")");
+            let rows: Vec<(String, String)> = query.build_query_as().fetch_all(&pool).await?;
+            out.extend(rows);
+        }
+        Ok(out)
+    }
+
+    /// Like [pname_attributes()], on this database.
+    pub async fn lookup_pname(&self, pname: &str) -> Result<HashMap<String, String>> {
+        querypname(&self.pool().await?, pname).await
+    }
+
+    /// Like [searchpkgs()], on this database.
+    pub async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        filter: &PackageFilter,
+    ) -> Result<Vec<NixPackage>> {
+        searchpool(&self.pool().await?, query, limit, filter, None).await
+    }
+
+    /// Like [searchpkgs_for_platform()], on this database.
+    pub async fn search_for_platform(
+        &self,
+        query: &str,
+        limit: usize,
+        filter: &PackageFilter,
+        platform: &str,
+    ) -> Result<Vec<NixPackage>> {
+        searchpool(&self.pool().await?, query, limit, filter, Some(platform)).await
+    }
+
+    /// Looks up the details of each attribute in `attributes`. Requires a database with a `meta` table.
+    /// Attributes that are missing are omitted from the output.
+    pub async fn detailed(&self, attributes: &[&str]) -> Result<HashMap<String, NixPackage>> {
+        let pool = self.pool().await?;
+        requiremeta(&pool).await?;
+        let attributes = attributes.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        querypackages(&pool, &attributes).await
+    }
+}
+
+/// (Re)creates the `pkgs_fts` full-text index over the `pname`, `description` and `longdescription` of every package,
+/// and the `pnames` index used by [searchpkgs()].
+/// Needs to be run whenever the `pkgs` or `meta` tables change so the index doesn't go stale.
+pub(super) async fn createfts(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(r#"CREATE INDEX IF NOT EXISTS "pnames" ON "pkgs" ("pname")"#)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(r#"DROP TABLE IF EXISTS "pkgs_fts""#)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE "pkgs_fts" USING fts5(
+            attribute UNINDEXED,
+            pname,
+            description,
+            longdescription
+        )
+        "#,
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO pkgs_fts (attribute, pname, description, longdescription)
+        SELECT pkgs.attribute, pkgs.pname, meta.description, meta.longdescription
+        FROM pkgs LEFT JOIN meta ON pkgs.attribute = meta.attribute
+        "#,
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Full-text searches the `pname`, description and long description of every package in the package database at `db`,
+/// returning matches to `filter` ranked by relevance (bm25). Packages matching more of the words in `query` rank higher.
+///
+/// The index is built when [nixospkgs()](super::nixos::nixospkgs) downloads a new database.
+/// Databases without it, such as ones downloaded by older versions of this crate, are indexed on first use.
+pub async fn fts_search(db: impl AsRef<Path>, query: &str, filter: &PackageFilter) -> Result<Vec<NixPackage>> {
+    let pool = connectdb(&db).await?;
+    requiremeta(&pool).await?;
+    if !tableexists(&pool, "pkgs_fts").await? {
+        createfts(&pool).await?;
+    }
+    // Quote every word so FTS5 query syntax in user input is matched literally
+    let terms = query
+        .split_whitespace()
+        .map(|x| format!("\"{}\"", x.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    if terms.is_empty() {
+        return Ok(vec![]);
+    }
+    let sql = format!(
+        r#"
+        SELECT {} FROM pkgs_fts
+        JOIN pkgs ON pkgs.attribute = pkgs_fts.attribute
+        LEFT JOIN meta ON pkgs.attribute = meta.attribute
+        WHERE pkgs_fts MATCH $1{}
+        ORDER BY bm25(pkgs_fts)
+        "#,
+        PACKAGECOLUMNS,
+        filter.conditions(&pool, 2).await?
+    );
+    let pkgs = sqlx::query_as(&sql)
+        .bind(terms.join(" OR "))
+        .bind(filter.attribute_glob.as_deref())
+        .fetch_all(&pool)
+        .await?;
+    Ok(pkgs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{replacedb, testdir, testpkgsdb};
+
+    #[tokio::test]
+    async fn package_info_lookup() {
+        let dir = testdir("package-info");
+        let db = dir.join("pkgs.db");
+        let pool = testpkgsdb(
+            &db,
+            &[
+                ("hello", "hello", "2.12", "A program that produces a familiar, friendly greeting"),
+                ("nometa", "nometa", "1.0", ""),
+            ],
+        )
+        .await;
+        sqlx::query("DELETE FROM meta WHERE attribute = 'nometa'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let db = db.to_str().unwrap();
+        let hello = package_info(db, "hello").await.unwrap().unwrap();
+        assert_eq!(hello.version, "2.12");
+        assert_eq!(
+            hello.description.as_deref(),
+            Some("A program that produces a familiar, friendly greeting")
+        );
+        assert!(package_info(db, "missing").await.unwrap().is_none());
+        let nometa = package_info(db, "nometa").await.unwrap().unwrap();
+        assert_eq!(nometa.version, "1.0");
+        assert_eq!(nometa.description, None);
+        assert!(!nometa.broken);
+    }
+
+    #[tokio::test]
+    async fn find_by_pname_multiple_attributes() {
+        let dir = testdir("find-by-pname");
+        let db = dir.join("pkgs.db");
+        let pool = testpkgsdb(
+            &db,
+            &[
+                ("firefox-esr", "firefox", "115.3.1esr", "Web browser, extended support release"),
+                ("firefox", "firefox", "118.0.1", "Web browser"),
+                ("hello", "hello", "2.12", "Greeting"),
+            ],
+        )
+        .await;
+        let db = db.to_str().unwrap();
+        let pkgs = find_by_pname(db, "firefox").await.unwrap();
+        assert_eq!(
+            pkgs.iter().map(|x| (x.attribute.as_str(), x.version.as_str())).collect::<Vec<_>>(),
+            vec![("firefox", "118.0.1"), ("firefox-esr", "115.3.1esr")]
+        );
+        assert_eq!(pkgs[0].description.as_deref(), Some("Web browser"));
+        assert!(find_by_pname(db, "missing").await.unwrap().is_empty());
+
+        // Without a meta table, the packages are still found with empty metadata
+        sqlx::query("DROP TABLE meta").execute(&pool).await.unwrap();
+        pool.close().await;
+        let pkgs = find_by_pname(db, "firefox").await.unwrap();
+        assert_eq!(pkgs.len(), 2);
+        assert_eq!(pkgs[1].attribute, "firefox-esr");
+        assert_eq!(pkgs[1].description, None);
+    }
+
+    #[tokio::test]
+    async fn search_ordering() {
+        let dir = testdir("search-ordering");
+        let db = dir.join("pkgs.db");
+        testpkgsdb(
+            &db,
+            &[
+                ("greeter", "greeter", "1.0", "Says hello"),
+                ("libhello", "libhello", "1.0", "Library"),
+                ("hello-wayland", "hello-wayland", "1.0", "Wayland client"),
+                ("hello", "hello", "2.12", "A program that produces a familiar, friendly greeting"),
+                ("Hello-GTK", "Hello-GTK", "1.0", "A pname that isn't lowercase"),
+                ("unrelated", "unrelated", "1.0", "Nothing to see"),
+            ],
+        )
+        .await
+        .close()
+        .await;
+        let db = db.to_str().unwrap();
+        let attributes = |pkgs: Vec<NixPackage>| pkgs.into_iter().map(|x| x.attribute).collect::<Vec<_>>();
+        // Exact pname match, then prefix, then substring, then description
+        let found = searchpkgs(db, "HELLO", 10, &PackageFilter::default()).await.unwrap();
+        assert_eq!(
+            attributes(found),
+            vec!["hello", "hello-wayland", "Hello-GTK", "libhello", "greeter"]
+        );
+        // Filled from the indexed exact and prefix matches alone
+        let found = searchpkgs(db, "hello", 2, &PackageFilter::default()).await.unwrap();
+        assert_eq!(attributes(found), vec!["hello", "hello-wayland"]);
+        // LIKE wildcards in the query are matched literally
+        assert!(searchpkgs(db, "h_llo", 10, &PackageFilter::default()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn filter_flags() {
+        let dir = testdir("package-filter");
+        let db = dir.join("pkgs.db");
+        let flags = ["broken", "insecure", "unsupported", "unfree"];
+        let mut rows = vec![("tool-ok", "tool-ok", "1.0", "A tool")];
+        rows.extend(flags.iter().map(|flag| (*flag, *flag, "1.0", "A tool")));
+        let pool = testpkgsdb(&db, &rows).await;
+        for flag in flags {
+            sqlx::query(&format!("UPDATE meta SET {} = 1 WHERE attribute = $1", flag))
+                .bind(flag)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        createfts(&pool).await.unwrap();
+        pool.close().await;
+
+        let db = db.to_str().unwrap();
+        let search = |filter: PackageFilter| async move {
+            let mut found = searchpkgs(db, "tool", 10, &filter)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|x| x.attribute)
+                .collect::<Vec<_>>();
+            found.sort();
+            let mut ftsfound = fts_search(db, "tool", &filter)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|x| x.attribute)
+                .collect::<Vec<_>>();
+            ftsfound.sort();
+            assert_eq!(found, ftsfound);
+            found
+        };
+        // Unfree packages are included by default, the others aren't
+        assert_eq!(search(PackageFilter::default()).await, ["tool-ok", "unfree"]);
+        let toggled = [
+            ("broken", PackageFilter { include_broken: true, ..Default::default() }),
+            ("insecure", PackageFilter { include_insecure: true, ..Default::default() }),
+            ("unsupported", PackageFilter { include_unsupported: true, ..Default::default() }),
+        ];
+        for (flag, filter) in toggled {
+            let mut expected = vec![flag, "tool-ok", "unfree"];
+            expected.sort();
+            assert_eq!(search(filter).await, expected);
+        }
+        let nounfree = PackageFilter { include_unfree: false, ..Default::default() };
+        assert_eq!(search(nounfree).await, ["tool-ok"]);
+    }
+
+    #[tokio::test]
+    async fn db_stats_counts() {
+        let dir = testdir("db-stats");
+        let db = dir.join("nixospkgs.db");
+        let pool = testpkgsdb(
+            &db,
+            &[
+                ("hello", "hello", "2.12", "Greeting"),
+                ("steam", "steam", "1.0", "Games"),
+                ("spotify", "spotify", "1.2", "Music"),
+            ],
+        )
+        .await;
+        sqlx::query("UPDATE meta SET unfree = 1 WHERE attribute IN ('steam', 'spotify')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE meta SET broken = 1 WHERE attribute = 'spotify'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let stats = db_stats(db.to_str().unwrap()).await.unwrap();
+        assert_eq!(
+            stats,
+            DbStats {
+                total: 3,
+                broken: 1,
+                unfree: 2,
+                version: None,
+            }
+        );
+        fs::write(dir.join("nixospkgs.ver"), "23.05.1234.abcdef\n").unwrap();
+        let stats = db_stats(db.to_str().unwrap()).await.unwrap();
+        assert_eq!(stats.version.as_deref(), Some("23.05.1234.abcdef"));
+    }
+
+    #[test]
+    fn package_json_roundtrip() {
+        let pkg = NixPackage {
+            attribute: String::from("python3Packages.requests"),
+            pname: Some(String::from("python3.10-requests")),
+            version: String::from("2.31.0"),
+            description: None,
+            broken: false,
+            insecure: true,
+            unfree: false,
+            homepage: Some(String::from("https://requests.readthedocs.io")),
+        };
+        let json = to_json(&pkg).unwrap();
+        assert!(json.contains(r#""attribute":"python3Packages.requests""#));
+        assert!(json.contains(r#""description":null"#));
+        assert_eq!(serde_json::from_str::<NixPackage>(&json).unwrap(), pkg);
+        let pretty = to_json_pretty(std::slice::from_ref(&pkg)).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<NixPackage>>(&pretty).unwrap(), vec![pkg]);
+    }
+
+    #[tokio::test]
+    async fn list_flagged_packages() {
+        let dir = testdir("list-flagged");
+        let db = dir.join("nixospkgs.db");
+        let pool = testpkgsdb(
+            &db,
+            &[
+                ("hello", "hello", "2.12", "Greeting"),
+                ("steam", "steam", "1.0", "Games"),
+                ("spotify", "spotify", "1.2", "Music"),
+            ],
+        )
+        .await;
+        sqlx::query("UPDATE meta SET unfree = 1 WHERE attribute IN ('steam', 'spotify')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE meta SET broken = 1 WHERE attribute = 'spotify'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let db = db.to_str().unwrap();
+        let attributes = |pkgs: Vec<NixPackage>| pkgs.into_iter().map(|x| x.attribute).collect::<Vec<_>>();
+        assert_eq!(
+            attributes(list_flagged(db, PackageFlag::Unfree).await.unwrap()),
+            vec!["spotify", "steam"]
+        );
+        let broken = list_flagged(db, PackageFlag::Broken).await.unwrap();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].attribute, "spotify");
+        assert!(broken[0].broken && broken[0].unfree);
+        assert!(list_flagged(db, PackageFlag::Insecure).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_for_platform() {
+        let dir = testdir("search-platform");
+        let db = dir.join("nixospkgs.db");
+        let pool = testpkgsdb(
+            &db,
+            &[
+                ("hello", "hello", "2.12", "Greeting"),
+                ("hello-linux", "hello-linux", "2.12", "Greeting for Linux"),
+            ],
+        )
+        .await;
+        for (attribute, platforms) in [
+            ("hello", r#"["x86_64-linux","x86_64-darwin"]"#),
+            ("hello-linux", r#"["x86_64-linux"]"#),
+        ] {
+            sqlx::query("UPDATE meta SET platforms = $1 WHERE attribute = $2")
+                .bind(platforms)
+                .bind(attribute)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        pool.close().await;
+
+        let db = PackageDb::open(db.to_str().unwrap()).await.unwrap();
+        let search = |platform| {
+            let db = db.clone();
+            async move {
+                db.search_for_platform("hello", 10, &PackageFilter::default(), platform)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|x| x.attribute)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(search("x86_64-linux").await, vec!["hello", "hello-linux"]);
+        assert_eq!(search("x86_64-darwin").await, vec!["hello"]);
+    }
+
+    #[tokio::test]
+    async fn diff_added_removed_changed() {
+        let dir = testdir("diff-dbs");
+        let old = dir.join("old.db");
+        let new = dir.join("new.db");
+        testpkgsdb(
+            &old,
+            &[
+                ("hello", "hello", "2.12", "Greeting"),
+                ("firefox", "firefox", "118.0", "Browser"),
+                ("atom", "atom", "1.60", "Editor"),
+            ],
+        )
+        .await
+        .close()
+        .await;
+        testpkgsdb(
+            &new,
+            &[
+                ("hello", "hello", "2.12", "Greeting"),
+                ("firefox", "firefox", "119.0", "Browser"),
+                ("zed", "zed", "0.1", "Editor"),
+            ],
+        )
+        .await
+        .close()
+        .await;
+
+        let diff = diff_dbs(old.to_str().unwrap(), new.to_str().unwrap()).await.unwrap();
+        assert_eq!(
+            diff.added.iter().map(|x| (x.attribute.as_str(), x.version.as_str())).collect::<Vec<_>>(),
+            vec![("zed", "0.1")]
+        );
+        assert_eq!(diff.added[0].description.as_deref(), Some("Editor"));
+        assert_eq!(diff.removed, vec![String::from("atom")]);
+        assert_eq!(
+            diff.changed,
+            vec![(String::from("firefox"), String::from("118.0"), String::from("119.0"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn many_lookups_through_one_handle() {
+        let dir = testdir("package-db");
+        let db = dir.join("pkgs.db");
+        let pkgs = (0..100)
+            .map(|i| (format!("pkg{}", i), format!("{}.0", i)))
+            .collect::<Vec<_>>();
+        let rows = pkgs
+            .iter()
+            .map(|(attr, version)| (attr.as_str(), attr.as_str(), version.as_str(), "Package"))
+            .collect::<Vec<_>>();
+        testpkgsdb(&db, &rows).await.close().await;
+
+        let pkgdb = PackageDb::open(db.to_str().unwrap()).await.unwrap();
+        for (attr, version) in &pkgs {
+            let found = pkgdb.lookup(&[attr.as_str(), "missing"]).await.unwrap();
+            assert_eq!(found, HashMap::from([(attr.clone(), version.clone())]));
+        }
+        let details = pkgdb.detailed(&["pkg1", "pkg2"]).await.unwrap();
+        assert_eq!(details["pkg2"].version, "2.0");
+        assert_eq!(pkgdb.search("pkg42", 1, &PackageFilter::default()).await.unwrap()[0].attribute, "pkg42");
+        // Opened read-only, so the database can't be changed through the handle
+        assert!(sqlx::query("DELETE FROM pkgs").execute(&pkgdb.pool().await.unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handle_follows_replaced_file() {
+        let dir = testdir("replaced-handle");
+        let db = dir.join("pkgs.db");
+        testpkgsdb(&db, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        let pkgdb = PackageDb::open(&db).await.unwrap();
+        let clone = pkgdb.clone();
+        assert_eq!(pkgdb.lookup(&["hello"]).await.unwrap()["hello"], "2.12");
+
+        // Updates build a new file and rename it over the old one
+        let tmpfile = dir.join("pkgs.db.tmp");
+        testpkgsdb(
+            &tmpfile,
+            &[("hello", "hello", "2.13", "Greeting"), ("cowsay", "cowsay", "3.7.0", "Talking cow")],
+        )
+        .await
+        .close()
+        .await;
+        replacedb(&tmpfile, &db).unwrap();
+        let found = pkgdb.lookup(&["hello", "cowsay"]).await.unwrap();
+        assert_eq!(found["hello"], "2.13");
+        assert_eq!(found["cowsay"], "3.7.0");
+        assert_eq!(clone.lookup(&["cowsay"]).await.unwrap()["cowsay"], "3.7.0");
+    }
+
+    #[tokio::test]
+    async fn attribute_presence() {
+        let dir = testdir("attribute-exists");
+        let db = dir.join("pkgs.db");
+        testpkgsdb(&db, &[("hello", "hello", "2.12", "Greeting")]).await.close().await;
+        assert!(attribute_exists(&db, "hello").await.unwrap());
+        assert!(!attribute_exists(&db, "missing").await.unwrap());
+        let found = attributes_exist(&db, &["hello", "missing"]).await.unwrap();
+        assert_eq!(
+            found,
+            HashMap::from([(String::from("hello"), true), (String::from("missing"), false)])
+        );
+        assert!(attributes_exist(&db, &[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_stops_search() {
+        let dir = testdir("search-stream");
+        let db = dir.join("pkgs.db");
+        let names = (0..500).map(|i| format!("pkg{:03}", i)).collect::<Vec<_>>();
+        let rows = names
+            .iter()
+            .map(|x| (x.as_str(), x.as_str(), "1.0", "A package"))
+            .collect::<Vec<_>>();
+        testpkgsdb(&db, &rows).await.close().await;
+
+        let first = search_stream(&db, "pkg", &PackageFilter::default())
+            .await
+            .unwrap()
+            .take(3)
+            .map(|x| x.unwrap().attribute)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(first, vec!["pkg000", "pkg001", "pkg002"]);
+
+        let pool = connectdb(&db).await.unwrap();
+        let sql = searchsql(&PackageFilter::default().conditions(&pool, 7).await.unwrap());
+        let (stream, task) = spawnsearch(pool, sql, "pkg", None);
+        let mut stream = Box::pin(stream);
+        for _ in 0..10 {
+            stream.next().await.unwrap().unwrap();
+        }
+        // The task waits for the consumer rather than reading every row ahead
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+        drop(stream);
+        tokio::time::timeout(std::time::Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn pname_version_lookup() {
+        let dir = testdir("name-version");
+        let db = dir.join("pkgs.db");
+        testpkgsdb(
+            &db,
+            &[
+                ("firefox", "firefox", "120.0", "Web browser"),
+                ("firefox-wayland", "firefox", "120.0", "Web browser"),
+                ("firefox-esr", "firefox", "115.5.0esr", "Web browser"),
+            ],
+        )
+        .await
+        .close()
+        .await;
+        let found = package_by_name_version(&db, "firefox", "120.0").await.unwrap().unwrap();
+        assert_eq!(found.attribute, "firefox");
+        let found = package_by_name_version(&db, "firefox", "115.5.0esr").await.unwrap().unwrap();
+        assert_eq!(found.attribute, "firefox-esr");
+        assert!(package_by_name_version(&db, "firefox", "1.0").await.unwrap().is_none());
+        assert!(package_by_name_version(&db, "chromium", "120.0").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn search_uses_pname_index() {
+        let dir = testdir("search-index");
+        let db = dir.join("pkgs.db");
+        let pool = testpkgsdb(&db, &[("hello", "hello", "2.12", "Greeting")]).await;
+        createfts(&pool).await.unwrap();
+        pool.close().await;
+        let pool = SqlitePool::connect(&format!("sqlite://{}", db.display())).await.unwrap();
+        let plan: Vec<(i64, i64, i64, String)> = sqlx::query_as(&format!("EXPLAIN QUERY PLAN {}", searchsql("")))
+            .bind("hello")
+            .bind("hello\u{10FFFF}")
+            .bind("hello%")
+            .bind("%hello%")
+            .bind(1)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        let indexed = plan.iter().filter(|(_, _, _, detail)| detail.contains("USING INDEX pnames")).count();
+        // The exact and the prefix match
+        assert!(indexed >= 2, "{:?}", plan);
+    }
+
+    #[tokio::test]
+    async fn fts_multiword_ranking() {
+        let dir = testdir("fts-ranking");
+        let db = dir.join("pkgs.db");
+        testpkgsdb(
+            &db,
+            &[
+                ("editor", "editor", "1.0", "A text editor"),
+                ("vim", "vim", "9.0", "The most popular clone of the vi text editor"),
+                ("mpv", "mpv", "0.36", "General-purpose media player"),
+            ],
+        )
+        .await
+        .close()
+        .await;
+        let found = fts_search(db.to_str().unwrap(), "vi editor", &PackageFilter::default()).await.unwrap();
+        let attributes = found.into_iter().map(|x| x.attribute).collect::<Vec<_>>();
+        // Both words beat only one, and packages with neither aren't returned
+        assert_eq!(attributes, vec!["vim", "editor"]);
+    }
+
+    #[tokio::test]
+    async fn querypackages_batches() {
+        let dir = testdir("querypackages");
+        let attributes = (0..1200).map(|i| format!("pkg{}", i)).collect::<Vec<_>>();
+        let pkgs = attributes
+            .iter()
+            .take(50)
+            .map(|x| (x.as_str(), x.as_str(), "1.0", "A package"))
+            .collect::<Vec<_>>();
+        let pool = testpkgsdb(&dir.join("pkgs.db"), &pkgs).await;
+        // The first 50 exist, the rest span several chunks and are missing
+        let found = querypackages(&pool, &attributes).await.unwrap();
+        assert_eq!(found.len(), 50);
+        for attribute in attributes.iter().take(50) {
+            assert_eq!(&found[attribute].attribute, attribute);
+            assert_eq!(found[attribute].description.as_deref(), Some("A package"));
+        }
+        assert!(querypackages(&pool, &[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_attribute_glob() {
+        let dir = testdir("search-glob");
+        let db = dir.join("pkgs.db");
+        testpkgsdb(
+            &db,
+            &[
+                ("requests", "requests", "1.0", "HTTP library"),
+                ("python3Packages.requests", "requests", "2.31", "HTTP library for Python"),
+                ("it's", "its", "1.0", "A package with a quote in its attribute"),
+            ],
+        )
+        .await
+        .close()
+        .await;
+        let search = |glob: &str| {
+            let filter = PackageFilter {
+                attribute_glob: Some(glob.to_string()),
+                ..Default::default()
+            };
+            let db = db.clone();
+            async move {
+                searchpkgs(&db, "", 10, &filter)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|x| x.attribute)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(search("python3Packages.*").await, vec!["python3Packages.requests"]);
+        assert_eq!(search("it's").await, vec!["it's"]);
+        // The glob is bound as a parameter, never spliced into the query
+        assert_eq!(search("' OR 1=1 --").await, Vec::<String>::new());
+        let all = searchpkgs(&db, "", 10, &PackageFilter::default()).await.unwrap();
+        assert_eq!(all.len(), 3);
+
+        let filter = PackageFilter {
+            attribute_glob: Some(String::from("python3Packages.*")),
+            ..Default::default()
+        };
+        let found = fts_search(&db, "http", &filter).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].attribute, "python3Packages.requests");
+        let streamed = search_stream(&db, "requests", &filter).await.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(streamed.len(), 1);
+        assert_eq!(streamed[0].as_ref().unwrap().attribute, "python3Packages.requests");
+    }
+
+    #[tokio::test]
+    async fn complete_python_packages() {
+        let dir = testdir("complete-attribute");
+        let db = dir.join("pkgs.db");
+        let pkgs = HashMap::from(
+            [
+                "python3Packages.requests-toolbelt",
+                "python3Packages.requests",
+                "python3Packages.requests-oauthlib",
+                "python3Packages.rich",
+                "python3Packages.req*",
+                "python311Packages.requests",
+                "requests",
+            ]
+            .map(|x| (x.to_string(), String::from("1.0"))),
+        );
+        crate::cache::nixos::createdb(&db, &pkgs).await.unwrap();
+        assert_eq!(
+            complete_attribute(&db, "python3Packages.req", 10).await.unwrap(),
+            vec![
+                "python3Packages.req*",
+                "python3Packages.requests",
+                "python3Packages.requests-oauthlib",
+                "python3Packages.requests-toolbelt",
+            ]
+        );
+        assert_eq!(
+            complete_attribute(&db, "python3Packages.req", 2).await.unwrap(),
+            vec!["python3Packages.req*", "python3Packages.requests"]
+        );
+        // Glob characters in the prefix are matched literally
+        assert_eq!(complete_attribute(&db, "python3Packages.req*", 10).await.unwrap(), vec!["python3Packages.req*"]);
+    }
+
+    #[tokio::test]
+    async fn page_through_packages() {
+        let dir = testdir("all-packages");
+        let attributes = (0..23).map(|i| format!("pkg{:02}", i)).collect::<Vec<_>>();
+        let pkgs = attributes
+            .iter()
+            .rev()
+            .map(|x| (x.as_str(), x.as_str(), "1.0", "A package"))
+            .collect::<Vec<_>>();
+        let db = dir.join("pkgs.db");
+        testpkgsdb(&db, &pkgs).await.close().await;
+        // A database without meta, as built for flakes
+        let flakedb = dir.join("flakepkgs.db");
+        let versions = attributes.iter().map(|x| (x.clone(), String::from("1.0"))).collect();
+        crate::cache::nixos::createdb(&flakedb, &versions).await.unwrap();
+
+        for db in [db, flakedb] {
+            let mut paged = Vec::new();
+            for offset in (0..30).step_by(5) {
+                let page = all_packages(&db, offset, 5).await.unwrap();
+                assert!(page.len() <= 5);
+                paged.extend(page.into_iter().map(|x| x.attribute));
+            }
+            // Sorted, with no duplicates or gaps
+            assert_eq!(paged, attributes);
+            assert!(all_packages(&db, 100, 5).await.unwrap().is_empty());
+        }
+    }
+}
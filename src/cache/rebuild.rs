@@ -0,0 +1,78 @@
+use crate::error::Result;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+
+use super::{checkcancelled, httpclient, nixos, CacheConfig};
+
+/// Phase of [rebuild_packages()], reported through its progress callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebuildPhase {
+    /// Downloading the compressed database. Progress is in bytes downloaded.
+    Download,
+    /// Decompressing and reading the downloaded database. Progress is in bytes written.
+    Parse,
+    /// Checking the new database contains packages. Progress is in steps completed.
+    Verify,
+    /// Recording the system of the packages and indexing them for search. Progress is in steps completed.
+    Insert,
+}
+
+/// Options for [rebuild_packages()].
+#[derive(Debug, Clone, Default)]
+pub struct RebuildOptions {
+    /// Rebuild even if the cached database is already the latest version.
+    pub force: bool,
+}
+
+/// Result of [rebuild_packages()].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuildOutcome {
+    /// Path to the package database.
+    pub path: PathBuf,
+    /// Whether a new database was downloaded. `false` if the cached database was already up to date.
+    pub downloaded: bool,
+    /// Version of the package database.
+    pub version: String,
+}
+
+/// Rebuilds the NixOS package database `nixospkgs.db` (see [nixospkgs()](super::nixos::nixospkgs)),
+/// reporting progress and supporting cancellation. Meant for interactive use, such as behind a progress bar in a GUI.
+/// The database is downloaded as by [nixospkgs()](super::nixos::nixospkgs), with the same retries and checksum verification.
+///
+/// `progress` is called with the current [RebuildPhase], the amount of work done in that phase, and the total amount of work if known.
+/// Servers often don't report the size of compressed downloads, so the total may be `None`.
+///
+/// Cancelling `cancel` aborts the rebuild between downloaded chunks and at phase boundaries.
+/// The new database is built in a temporary file and only moved into place once it is complete and contains packages,
+/// so a cancelled or failed rebuild leaves the previous database untouched. The previous database is kept as `nixospkgs.db.bak`.
+pub async fn rebuild_packages(
+    options: &RebuildOptions,
+    progress: impl Fn(RebuildPhase, u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<RebuildOutcome> {
+    let config = CacheConfig {
+        force: options.force,
+        ..CacheConfig::default()
+    };
+    rebuild_packages_with_config(&config, progress, cancel).await
+}
+
+/// Like [rebuild_packages()], but caches the database in the directory given by `config`.
+/// [force](CacheConfig::force) takes the place of [RebuildOptions::force].
+pub async fn rebuild_packages_with_config(
+    config: &CacheConfig,
+    progress: impl Fn(RebuildPhase, u64, Option<u64>),
+    cancel: &CancellationToken,
+) -> Result<RebuildOutcome> {
+    if let Some(cached) = config.offlinefile(&config.pkgsname("db")) {
+        return Ok(RebuildOutcome {
+            path: cached?,
+            downloaded: false,
+            version: std::fs::read_to_string(config.file(&config.pkgsname("ver"))).unwrap_or_default(),
+        });
+    }
+    let client = httpclient(true)?;
+    let channel = nixos::systemchannel(config, &client).await?;
+    checkcancelled(cancel)?;
+    nixos::fetchnixospkgs(config, &client, &channel, progress, cancel).await
+}
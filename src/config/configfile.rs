@@ -1,6 +1,6 @@
 
 use crate::{CONFIG, SYSCONFIG, CONFIGDIR};
-use anyhow::{Result, anyhow};
+use crate::error::{NixDataError, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{self, File},
@@ -47,7 +47,7 @@ pub fn getconfig() -> Result<NixDataConfig> {
         let config: NixDataConfig = serde_json::from_reader(BufReader::new(File::open(SYSCONFIG)?))?;
         Ok(config)
     }  else {
-        Err(anyhow!("No config file found"))
+        Err(NixDataError::Other(String::from("No config file found")))
     }
 }
 
@@ -0,0 +1,210 @@
+use crate::cache::options::OptionType;
+use crate::error::{NixDataError, Result};
+use std::fs;
+
+const SYSTEMPACKAGES: &str = "environment.systemPackages";
+
+/// Entries of `environment.systemPackages` in `contents` that refer to `attribute`, with or without a `pkgs.` prefix.
+fn matchingentries(contents: &str, attribute: &str) -> Vec<String> {
+    let attribute = attribute.strip_prefix("pkgs.").unwrap_or(attribute);
+    nix_editor::read::getarrvals(contents, SYSTEMPACKAGES)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|x| x.strip_prefix("pkgs.").unwrap_or(x) == attribute)
+        .collect()
+}
+
+/// Returns the value assigned to the option `option` (e.g. `networking.hostName`) in the NixOS configuration files `paths`,
+/// as the Nix expression written in the file, e.g. `"nixos"` or `true`. Returns `None` if no file sets the option.
+/// If several files set it, the value from the first one in `paths` is returned.
+///
+/// Options set inside an attribute set, as in `networking = { hostName = "nixos"; };`, are found as well.
+/// Files that can't be parsed as a NixOS module are skipped.
+pub fn read_option_value(paths: &[&str], option: &str) -> Result<Option<String>> {
+    for path in paths {
+        if let Ok(value) = nix_editor::read::readvalue(&fs::read_to_string(path)?, option) {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the contents of the NixOS configuration file at `path` with the option `option` (e.g. `networking.hostName`) set to `value`,
+/// replacing any existing assignment.
+///
+/// With the [OptionType] of the option, `value` is given as plain text and quoted as needed:
+/// strings, paths and string enum values are written as Nix strings, while booleans, integers and `null` are written as they are.
+/// Without a type, or for types such as lists that can't be quoted this way, `value` must be a Nix expression, e.g. `"nixos"` or `[ "wheel" ]`.
+///
+/// The file itself isn't modified, so the caller can decide how to write it (for example with elevated privileges).
+pub fn write_option_value(
+    path: &str,
+    option: &str,
+    value: &str,
+    optiontype: Option<&OptionType>,
+) -> Result<String> {
+    let contents = fs::read_to_string(path)?;
+    let value = match optiontype {
+        Some(optiontype) => nixvalue(value, optiontype),
+        None => value.to_string(),
+    };
+    nix_editor::write::write(&contents, option, &value)
+        .map_err(|e| NixDataError::Other(format!("Failed to set {} in {}: {}", option, path, e)))
+}
+
+/// Writes the plain text `value` as a Nix expression of type `optiontype`.
+fn nixvalue(value: &str, optiontype: &OptionType) -> String {
+    match optiontype {
+        OptionType::Nullable(_) if value == "null" => value.to_string(),
+        OptionType::Nullable(inner) => nixvalue(value, inner),
+        OptionType::Str | OptionType::Path => nixstring(value),
+        // Values of enums that aren't strings are given as JSON, which is also how Nix writes them
+        OptionType::Enum(_) if serde_json::from_str::<serde_json::Value>(value).is_ok_and(|x| !x.is_string()) => {
+            value.to_string()
+        }
+        OptionType::Enum(_) => nixstring(value),
+        _ => value.to_string(),
+    }
+}
+
+/// Quotes `s` as a Nix string, escaping anything that would otherwise be interpreted.
+fn nixstring(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{}\"", escaped)
+}
+
+/// Returns the contents of the NixOS configuration file at `path` with `attribute` added to `environment.systemPackages`.
+/// The list is created if the file doesn't have one yet, and the contents are returned unchanged if `attribute` is already in it.
+///
+/// The file itself isn't modified, so the caller can decide how to write it (for example with elevated privileges).
+pub fn add_package(path: &str, attribute: &str) -> Result<String> {
+    let contents = fs::read_to_string(path)?;
+    if !matchingentries(&contents, attribute).is_empty() {
+        return Ok(contents);
+    }
+    // A newly created list isn't wrapped in `with pkgs;`, so the attribute needs the prefix
+    let entry = if nix_editor::read::getarrvals(&contents, SYSTEMPACKAGES).is_err()
+        && !attribute.starts_with("pkgs.")
+    {
+        format!("pkgs.{}", attribute)
+    } else {
+        attribute.to_string()
+    };
+    nix_editor::write::addtoarr(&contents, SYSTEMPACKAGES, vec![entry])
+        .map_err(|e| NixDataError::Other(format!("Failed to add {} to {}: {}", attribute, path, e)))
+}
+
+/// Returns the contents of the NixOS configuration file at `path` with `attribute` removed from `environment.systemPackages`,
+/// whether it is written with a `pkgs.` prefix or not. The contents are returned unchanged if `attribute` isn't in the list.
+///
+/// The file itself isn't modified, so the caller can decide how to write it (for example with elevated privileges).
+pub fn remove_package(path: &str, attribute: &str) -> Result<String> {
+    let contents = fs::read_to_string(path)?;
+    let entries = matchingentries(&contents, attribute);
+    if entries.is_empty() {
+        return Ok(contents);
+    }
+    nix_editor::write::rmarr(&contents, SYSTEMPACKAGES, entries)
+        .map_err(|e| {
+            NixDataError::Other(format!(
+                "Failed to remove {} from {}: {}",
+                attribute, path, e
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::testdir;
+
+    const WITHLIST: &str = r#"{ config, pkgs, ... }:
+{
+  networking.hostName = "nixos";
+  environment.systemPackages = with pkgs; [
+    firefox
+    git
+  ];
+}
+"#;
+
+    const WITHOUTLIST: &str = r#"{ config, pkgs, ... }:
+{
+  networking.hostName = "nixos";
+}
+"#;
+
+    /// Writes `contents` to `configuration.nix` in a new test directory named `name`.
+    fn configfile(name: &str, contents: &str) -> String {
+        let path = testdir(name).join("configuration.nix");
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn systempackages(contents: &str) -> Vec<String> {
+        nix_editor::read::getarrvals(contents, SYSTEMPACKAGES).unwrap()
+    }
+
+    #[test]
+    fn read_hostname() {
+        let path = configfile("read-option", WITHLIST);
+        assert_eq!(read_option_value(&[&path], "networking.hostName").unwrap().as_deref(), Some("\"nixos\""));
+        assert_eq!(read_option_value(&[&path], "services.nginx.enable").unwrap(), None);
+
+        // Options set inside an attribute set are found, and the first file setting the option wins
+        let nested = configfile("read-option-nested", "{ config, pkgs, ... }:\n{\n  networking = { hostName = \"desktop\"; };\n}\n");
+        assert_eq!(
+            read_option_value(&[&nested, &path], "networking.hostName").unwrap().as_deref(),
+            Some("\"desktop\"")
+        );
+    }
+
+    #[test]
+    fn write_typed_values() {
+        let path = configfile("write-option", WITHLIST);
+        let read = |contents: &str, option: &str| nix_editor::read::readvalue(contents, option).unwrap();
+
+        // Strings are quoted and escaped, replacing the existing value
+        let contents = write_option_value(&path, "networking.hostName", "my \"box\" ${x}", Some(&OptionType::Str)).unwrap();
+        assert_eq!(read(&contents, "networking.hostName"), r#""my \"box\" \${x}""#);
+        assert_eq!(contents.matches("hostName").count(), 1);
+
+        let contents = write_option_value(&path, "services.openssh.enable", "true", Some(&OptionType::Bool)).unwrap();
+        assert_eq!(read(&contents, "services.openssh.enable"), "true");
+        assert_eq!(read(&contents, "networking.hostName"), "\"nixos\"");
+
+        // Without a type, the value is written as the Nix expression given
+        let contents = write_option_value(&path, "networking.hostName", "\"laptop\"", None).unwrap();
+        assert_eq!(read(&contents, "networking.hostName"), "\"laptop\"");
+        assert_eq!(fs::read_to_string(&path).unwrap(), WITHLIST);
+    }
+
+    #[test]
+    fn add_to_existing_list() {
+        let path = configfile("add-package", WITHLIST);
+        let contents = add_package(&path, "hello").unwrap();
+        assert_eq!(systempackages(&contents), vec!["firefox", "git", "hello"]);
+        // The file itself is left alone
+        assert_eq!(fs::read_to_string(&path).unwrap(), WITHLIST);
+        assert_eq!(add_package(&path, "pkgs.git").unwrap(), WITHLIST);
+    }
+
+    #[test]
+    fn add_without_list() {
+        let path = configfile("add-package-nolist", WITHOUTLIST);
+        let contents = add_package(&path, "hello").unwrap();
+        assert_eq!(systempackages(&contents), vec!["pkgs.hello"]);
+        assert_eq!(nix_editor::read::readvalue(&contents, "networking.hostName").unwrap(), "\"nixos\"");
+    }
+
+    #[test]
+    fn remove_present_and_absent() {
+        let path = configfile("remove-package", WITHLIST);
+        let contents = remove_package(&path, "pkgs.git").unwrap();
+        assert_eq!(systempackages(&contents), vec!["firefox"]);
+        assert_eq!(remove_package(&path, "hello").unwrap(), WITHLIST);
+    }
+}
@@ -2,3 +2,5 @@
 /// contains the locations of system configuration
 /// files and some user configuration.
 pub mod configfile;
+/// Read option values and add and remove packages in NixOS configuration files.
+pub mod edit;
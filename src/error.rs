@@ -0,0 +1,190 @@
+use std::{fmt, io, process};
+
+use crate::cache::ChecksumMismatch;
+
+/// Error returned by the functions in this crate.
+#[derive(Debug)]
+pub enum NixDataError {
+    /// The system isn't running NixOS (`nixos-version` is not available), but the function only works on NixOS.
+    NotNixos,
+    /// A request failed, for example because there is no internet connection.
+    Network(reqwest::Error),
+    /// A request took longer than allowed by the [HttpConfig](crate::cache::HttpConfig) timeouts.
+    Timeout(String),
+    /// A download was answered with an unsuccessful status, such as `404 Not Found`.
+    Download(String),
+    /// The latest version of a channel or package set could not be determined.
+    ChannelResolve(String),
+    /// A download didn't match the SHA-256 hash published alongside it. Retrying the download may succeed.
+    ChecksumMismatch(ChecksumMismatch),
+    /// A file, or the output of a command, could not be parsed.
+    Parse(String),
+    /// A query on a package or options database failed.
+    Sqlite(sqlx::Error),
+    /// An external command needed by the function, such as `nix`, is not installed.
+    MissingTool(String),
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// The operation was cancelled by the caller.
+    Cancelled,
+    /// Running [offline](crate::cache::CacheConfig::offline), and the file at the given path hasn't been cached yet.
+    NotCached(String),
+    /// Any other error, such as an unexpected database layout or a failed `nix` command.
+    Other(String),
+}
+
+/// Result type returned by the functions in this crate.
+pub type Result<T, E = NixDataError> = std::result::Result<T, E>;
+
+impl fmt::Display for NixDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NixDataError::NotNixos => write!(f, "Not running on NixOS"),
+            NixDataError::Network(e) => write!(f, "Network error: {}", e),
+            NixDataError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            NixDataError::Download(msg) => write!(f, "{}", msg),
+            NixDataError::ChannelResolve(msg) => write!(f, "{}", msg),
+            NixDataError::ChecksumMismatch(e) => write!(f, "{}", e),
+            NixDataError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            NixDataError::Sqlite(e) => write!(f, "Database error: {}", e),
+            NixDataError::MissingTool(tool) => write!(f, "{} is not installed", tool),
+            NixDataError::Io(e) => write!(f, "{}", e),
+            NixDataError::Cancelled => write!(f, "Cancelled"),
+            NixDataError::NotCached(path) => write!(f, "Offline, and {} is not cached", path),
+            NixDataError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for NixDataError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NixDataError::Network(e) => Some(e),
+            NixDataError::ChecksumMismatch(e) => Some(e),
+            NixDataError::Sqlite(e) => Some(e),
+            NixDataError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for NixDataError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            NixDataError::Timeout(e.to_string())
+        } else {
+            NixDataError::Network(e)
+        }
+    }
+}
+
+impl From<sqlx::Error> for NixDataError {
+    fn from(e: sqlx::Error) -> Self {
+        NixDataError::Sqlite(e)
+    }
+}
+
+impl From<io::Error> for NixDataError {
+    fn from(e: io::Error) -> Self {
+        NixDataError::Io(e)
+    }
+}
+
+impl From<ChecksumMismatch> for NixDataError {
+    fn from(e: ChecksumMismatch) -> Self {
+        NixDataError::ChecksumMismatch(e)
+    }
+}
+
+impl From<serde_json::Error> for NixDataError {
+    fn from(e: serde_json::Error) -> Self {
+        NixDataError::Parse(e.to_string())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for NixDataError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        NixDataError::Parse(e.to_string())
+    }
+}
+
+impl From<std::env::VarError> for NixDataError {
+    fn from(e: std::env::VarError) -> Self {
+        NixDataError::Other(e.to_string())
+    }
+}
+
+impl From<tokio::task::JoinError> for NixDataError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        NixDataError::Other(e.to_string())
+    }
+}
+
+/// Running external commands, reporting a missing executable as [NixDataError::MissingTool],
+/// or [NixDataError::NotNixos] if it is `nixos-version`.
+pub(crate) trait CommandExt {
+    /// Like [Command::output](process::Command::output), with missing executables reported as above.
+    fn tooloutput(&mut self) -> Result<process::Output>;
+}
+
+impl CommandExt for process::Command {
+    fn tooloutput(&mut self) -> Result<process::Output> {
+        self.output().map_err(|e| missingtool(self.get_program(), e))
+    }
+}
+
+/// Async version of [CommandExt::tooloutput()].
+pub(crate) async fn tooloutput_async(cmd: &mut tokio::process::Command) -> Result<process::Output> {
+    let program = cmd.as_std().get_program().to_owned();
+    cmd.output().await.map_err(|e| missingtool(&program, e))
+}
+
+fn missingtool(program: &std::ffi::OsStr, e: io::Error) -> NixDataError {
+    if e.kind() != io::ErrorKind::NotFound {
+        return NixDataError::Io(e);
+    }
+    match program.to_string_lossy().as_ref() {
+        "nixos-version" => NixDataError::NotNixos,
+        program => NixDataError::MissingTool(program.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory to use as `PATH`, so that no tool can be found.
+    fn emptypath(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("nix-data-test-{}-{}", name, process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn missing_tool() {
+        let err = process::Command::new("sqlite3")
+            .env("PATH", emptypath("missing-tool"))
+            .tooloutput()
+            .unwrap_err();
+        assert!(matches!(err, NixDataError::MissingTool(tool) if tool == "sqlite3"));
+    }
+
+    #[tokio::test]
+    async fn missing_tool_async() {
+        let err = tooloutput_async(
+            tokio::process::Command::new("sqlite3").env("PATH", emptypath("missing-tool-async")),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, NixDataError::MissingTool(tool) if tool == "sqlite3"));
+    }
+
+    #[test]
+    fn missing_nixos_version() {
+        let err = process::Command::new("nixos-version")
+            .env("PATH", emptypath("missing-nixos-version"))
+            .tooloutput()
+            .unwrap_err();
+        assert!(matches!(err, NixDataError::NotNixos));
+    }
+}
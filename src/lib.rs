@@ -8,11 +8,12 @@
 //! This can be useful so that not ever application/utility needs to maintain their own config files and preferences.
 //! 
 //! # Example
-//! ```
+//! ```no_run
 //! extern crate nix_data;
 //!  
-//! fn main() {
-//!     let userpkgs = nix_data::cache::profile::getprofilepkgs_versioned();
+//! #[tokio::main]
+//! async fn main() {
+//!     let userpkgs = nix_data::cache::profile::getprofilepkgs_versioned().await;
 //!     if let Ok(pkgs) = userpkgs {
 //!         println!("List of installed nix profile packages");
 //!         println!("===");
@@ -27,6 +28,8 @@
 pub mod cache;
 /// A module for managing the configuration containing user and system options.
 pub mod config;
+/// The error type returned by this crate.
+pub mod error;
 
 pub mod utils;
 
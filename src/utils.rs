@@ -1,8 +1,8 @@
 use crate::HOME;
-use anyhow::{Context, Result};
+use crate::error::{NixDataError, Result};
 use std::{
     fs::{self, File},
-    path::Path, io::{Read, Write}, os::unix::prelude::PermissionsExt,
+    path::Path, io::{Read, Write},
 };
 
 /// Refreshes desktop icons for applications installed with Nix
@@ -10,7 +10,7 @@ pub fn refreshicons() -> Result<()> {
     let desktoppath = &format!("{}/.local/share/applications", &*HOME);
     let iconpath = &format!("{}/.local/share/icons/nixrefresh.png", &*HOME);
     fs::create_dir_all(desktoppath)?;
-    fs::create_dir_all(&format!("{}/.local/share/icons", &*HOME))?;
+    fs::create_dir_all(format!("{}/.local/share/icons", &*HOME))?;
 
     // Clean up old files
     for filename in (fs::read_dir(desktoppath)?).flatten() {
@@ -20,13 +20,13 @@ pub fn refreshicons() -> Result<()> {
     }
 
     for filename in
-        (fs::read_dir(&format!("{}/.nix-profile/share/applications", &*HOME))?).flatten()
+        (fs::read_dir(format!("{}/.nix-profile/share/applications", &*HOME))?).flatten()
     {
-        let filepath = filename.path().to_str().context("file path")?.to_string();
+        let filepath = filename.path().to_str().ok_or_else(|| NixDataError::Parse(String::from("Invalid file path")))?.to_string();
         let localpath = format!(
             "{}/{}",
             desktoppath,
-            filename.file_name().to_str().context("file name")?
+            filename.file_name().to_str().ok_or_else(|| NixDataError::Parse(String::from("Invalid file name")))?
         );
         if Path::new(&localpath).exists() {
             fs::remove_file(&localpath)?;